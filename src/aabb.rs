@@ -1,5 +1,6 @@
 use ray::*;
 use util::*;
+use stats::*;
 extern crate nalgebra as alg;
 
 pub enum Axis {
@@ -67,6 +68,7 @@ impl AABB {
 
     /// Check if the given ray hits the bounding box.
     pub fn hit(&self, r: &Ray, time_min: f32,  time_max: f32) -> bool {
+        count_box_test(r);
         let mut t0 = mymin((self.min.x - r.origin().x) / r.direction().x,
                         (self.max.x - r.origin().x) / r.direction().x);
         let mut t1 = mymax((self.min.x - r.origin().x) / r.direction().x,
@@ -99,6 +101,25 @@ impl AABB {
 
         return true;
     }
+
+    /// Find where the given ray enters and leaves the bounding box, clipped to the time range.
+    pub fn hit_range(&self, r: &Ray, time_min: f32, time_max: f32) -> Option<(f32, f32)> {
+        count_box_test(r);
+        let mut t_enter = time_min;
+        let mut t_exit = time_max;
+        for axis in 0..3 {
+            let inverse = 1.0 / r.direction()[axis];
+            let t0 = (self.min[axis] - r.origin()[axis]) * inverse;
+            let t1 = (self.max[axis] - r.origin()[axis]) * inverse;
+            t_enter = mymax(mymin(t0, t1), t_enter);
+            t_exit = mymin(mymax(t0, t1), t_exit);
+            if t_exit < t_enter {
+                return None;
+            }
+        }
+
+        Some((t_enter, t_exit))
+    }
 }
 
 pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
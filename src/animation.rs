@@ -0,0 +1,357 @@
+extern crate nalgebra as alg;
+
+use std::ops::{Add, Mul, Sub};
+
+use camera::*;
+use scene_file::*;
+
+//Anything that can be blended between keyframes, which is f32 and the vectors
+pub trait Animatable : Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f32, Output = Self> {}
+
+impl<T : Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>> Animatable for T {}
+
+//How a keyframe gets to the next one. Bézier handles are the two control points between the keys, in the same units as the
+//values. Catmull-Rom passes smoothly through every key using the keys either side for its tangents
+#[derive(Copy, Clone, Debug)]
+pub enum Interpolation<T : Animatable> {
+	Linear,
+	Bezier(T, T),
+	CatmullRom,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe<T : Animatable> {
+	pub frame : f32,
+	pub value : T,
+	pub interpolation : Interpolation<T>,
+}
+
+impl<T : Animatable> Keyframe<T> {
+	pub fn new(frame : f32, value : T, interpolation : Interpolation<T>) -> Keyframe<T> {
+		Keyframe {
+			frame,
+			value,
+			interpolation,
+		}
+	}
+}
+
+//Keyframes in frame order, holding the first and last values before and after them. Making one with no keyframes or a
+//frame that isn't a number is an error
+#[derive(Clone, Debug)]
+pub struct Track<T : Animatable> {
+	pub keys : Vec<Keyframe<T>>,
+}
+
+impl<T : Animatable> Track<T> {
+	pub fn new(mut keys : Vec<Keyframe<T>>) -> Result<Track<T>, String> {
+		if keys.is_empty() {
+			return Err("A track needs at least one keyframe".to_string());
+		}
+		if keys.iter().any(|key| key.frame.is_nan()) {
+			return Err("A keyframe's frame isn't a number".to_string());
+		}
+		keys.sort_by(|a, b| a.frame.partial_cmp(&b.frame).unwrap());
+		Ok(Track {
+			keys,
+		})
+	}
+
+	//The change per frame at key i, from the keys either side or just the one there is at the ends
+	fn tangent(&self, i : usize) -> T {
+		let previous = &self.keys[if i > 0 { i - 1 } else { i }];
+		let next = &self.keys[(i + 1).min(self.keys.len() - 1)];
+		if next.frame <= previous.frame {
+			return self.keys[i].value * 0.0;
+		}
+		(next.value - previous.value) * (1.0 / (next.frame - previous.frame))
+	}
+
+	pub fn sample(&self, frame : f32) -> T {
+		let first = &self.keys[0];
+		let last = &self.keys[self.keys.len() - 1];
+		if frame <= first.frame {
+			return first.value;
+		}
+		if frame >= last.frame {
+			return last.value;
+		}
+
+		let i = self.keys.iter().rposition(|key| key.frame <= frame).unwrap();
+		let key = &self.keys[i];
+		let next = &self.keys[i + 1];
+		let length = next.frame - key.frame;
+		let t = (frame - key.frame) / length;
+
+		match key.interpolation {
+			Interpolation::Linear => key.value + (next.value - key.value) * t,
+			Interpolation::Bezier(out_handle, in_handle) => {
+				let s = 1.0 - t;
+				key.value * (s * s * s) + out_handle * (3.0 * s * s * t) + in_handle * (3.0 * s * t * t) + next.value * (t * t * t)
+			},
+			//Cubic Hermite with the tangents scaled to the length of this segment
+			Interpolation::CatmullRom => {
+				let t2 = t * t;
+				let t3 = t2 * t;
+				key.value * (2.0 * t3 - 3.0 * t2 + 1.0) + self.tangent(i) * ((t3 - 2.0 * t2 + t) * length)
+					+ next.value * (3.0 * t2 - 2.0 * t3) + self.tangent(i + 1) * ((t3 - t2) * length)
+			},
+		}
+	}
+}
+
+//Camera parameters that change over the animation, anything left as None stays as the base camera has it. With a target the
+//camera is turned to look at it from its origin, keeping the base camera's image plane
+#[derive(Clone)]
+pub struct CameraAnimation {
+	pub origin : Option<Track<alg::Vector3<f32>>>,
+	pub target : Option<Track<alg::Vector3<f32>>>,
+	//Multiplies the size of the image plane, under 1 zooms in
+	pub zoom : Option<Track<f32>>,
+}
+
+//An object's transform and material over the animation, on top of how it's described in the scene. Shapes move, turn about
+//the vertical axis through their center and scale about it as instances, so they blur while the shutter's open. Meshes
+//turn and scale about their offset, which is wherever the model's origin ends up. Lights can only be moved, scaled and
+//dimmed, and only from frame to frame
+#[derive(Clone)]
+pub struct ObjectAnimation {
+	pub translation : Option<Track<alg::Vector3<f32>>>,
+	//Degrees
+	pub rotation : Option<Track<f32>>,
+	pub scale : Option<Track<f32>>,
+	pub colour : Option<Track<alg::Vector3<f32>>>,
+	//The metal's fuzz or dielectric's refractive index, diffuse has nothing to animate
+	pub material : Option<Track<f32>>,
+	//Only for lights
+	pub intensity : Option<Track<f32>>,
+}
+
+//Objects are picked out by their index in the scene description
+#[derive(Clone)]
+pub struct Animation {
+	pub camera : CameraAnimation,
+	pub objects : Vec<(usize, ObjectAnimation)>,
+}
+
+impl ObjectAnimation {
+	pub fn new() -> ObjectAnimation {
+		ObjectAnimation {
+			translation : None,
+			rotation : None,
+			scale : None,
+			colour : None,
+			material : None,
+			intensity : None,
+		}
+	}
+
+	fn apply(&self, object : &ObjectDescription, frame : f32) -> ObjectDescription {
+		let mut object = object.clone();
+		let colour = self.colour.as_ref().map(|track| track.sample(frame));
+		let parameter = self.material.as_ref().map(|track| track.sample(frame));
+		let animate_material = |material : &mut MaterialDescription| {
+			match (material, parameter) {
+				(&mut MaterialDescription::Metal(ref mut fuzz), Some(parameter)) => *fuzz = parameter,
+				(&mut MaterialDescription::Dielectric(ref mut refractive_index), Some(parameter)) => *refractive_index = parameter,
+				_ => {},
+			}
+		};
+
+		let pivot = match object {
+			ObjectDescription::Sphere { center, colour : ref mut object_colour, ref mut material, .. } |
+			ObjectDescription::Cube { center, colour : ref mut object_colour, ref mut material, .. } |
+			ObjectDescription::MovingSphere { center0 : center, colour : ref mut object_colour, ref mut material, .. } |
+			ObjectDescription::Mesh { offset : center, colour : ref mut object_colour, ref mut material, .. } => {
+				*object_colour = colour.unwrap_or(*object_colour);
+				animate_material(material);
+				center
+			},
+			ObjectDescription::Instance { pivot, .. } => pivot,
+			ObjectDescription::Light { ref mut center, ref mut intensity, colour : ref mut light_colour } => {
+				*center += self.translation.as_ref().map_or(alg::Vector3::new(0.0, 0.0, 0.0), |track| track.sample(frame));
+				*intensity = self.intensity.as_ref().map_or(*intensity, |track| track.sample(frame));
+				*light_colour = colour.unwrap_or(*light_colour);
+				return object;
+			},
+			ObjectDescription::SphereLight { ref mut center, ref mut radius, ref mut intensity, colour : ref mut light_colour } => {
+				*center += self.translation.as_ref().map_or(alg::Vector3::new(0.0, 0.0, 0.0), |track| track.sample(frame));
+				*radius *= self.scale.as_ref().map_or(1.0, |track| track.sample(frame));
+				*intensity = self.intensity.as_ref().map_or(*intensity, |track| track.sample(frame));
+				*light_colour = colour.unwrap_or(*light_colour);
+				return object;
+			},
+			ObjectDescription::SpotLight { ref mut center, ref mut intensity, colour : ref mut light_colour, .. } => {
+				*center += self.translation.as_ref().map_or(alg::Vector3::new(0.0, 0.0, 0.0), |track| track.sample(frame));
+				*intensity = self.intensity.as_ref().map_or(*intensity, |track| track.sample(frame));
+				*light_colour = colour.unwrap_or(*light_colour);
+				return object;
+			},
+			//Nowhere to move it to
+			ObjectDescription::DirectionalLight { ref mut intensity, colour : ref mut light_colour, .. } => {
+				*intensity = self.intensity.as_ref().map_or(*intensity, |track| track.sample(frame));
+				*light_colour = colour.unwrap_or(*light_colour);
+				return object;
+			},
+			//Scaled about its middle
+			ObjectDescription::RectLight { ref mut corner, ref mut edge_u, ref mut edge_v, ref mut intensity, colour : ref mut light_colour } => {
+				let scale = self.scale.as_ref().map_or(1.0, |track| track.sample(frame));
+				let middle = *corner + (*edge_u + *edge_v) * 0.5;
+				*edge_u *= scale;
+				*edge_v *= scale;
+				*corner = middle - (*edge_u + *edge_v) * 0.5 + self.translation.as_ref().map_or(alg::Vector3::new(0.0, 0.0, 0.0), |track| track.sample(frame));
+				*intensity = self.intensity.as_ref().map_or(*intensity, |track| track.sample(frame));
+				*light_colour = colour.unwrap_or(*light_colour);
+				return object;
+			},
+		};
+
+		if self.translation.is_none() && self.rotation.is_none() && self.scale.is_none() {
+			return object;
+		}
+		ObjectDescription::Instance {
+			object : Box::new(object),
+			pivot,
+			translation : self.translation.clone(),
+			rotation : self.rotation.clone(),
+			scale : self.scale.clone(),
+			frame,
+		}
+	}
+}
+
+impl Animation {
+	pub fn new() -> Animation {
+		Animation {
+			camera : CameraAnimation {
+				origin : None,
+				target : None,
+				zoom : None,
+			},
+			objects : Vec::new(),
+		}
+	}
+
+	//False when only the camera moves, so the same scene can be rendered for every frame
+	pub fn moves_objects(&self) -> bool {
+		!self.objects.is_empty()
+	}
+
+	pub fn camera_at(&self, base : &Camera, frame : f32) -> Camera {
+		let mut camera = base.clone();
+		if let Some(ref origin) = self.camera.origin {
+			camera.origin = origin.sample(frame);
+		}
+		if let Some(ref target) = self.camera.target {
+			camera = camera.look_at(target.sample(frame), alg::Vector3::new(0.0, 1.0, 0.0));
+		}
+		if let Some(ref zoom) = self.camera.zoom {
+			camera = camera.zoomed(zoom.sample(frame));
+		}
+		camera
+	}
+
+	pub fn scene_at(&self, description : &SceneDescription, frame : f32) -> SceneDescription {
+		let mut scene = description.clone();
+		for &(index, ref animation) in self.objects.iter() {
+			assert!(index < scene.objects.len(), "Animating object {} but the scene only has {}", index, scene.objects.len());
+			scene.objects[index] = animation.apply(&description.objects[index], frame);
+		}
+		scene
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn track(keys : &[(f32, f32)], interpolation : Interpolation<f32>) -> Track<f32> {
+		Track::new(keys.iter().map(|&(frame, value)| Keyframe::new(frame, value, interpolation)).collect()).unwrap()
+	}
+
+	#[test]
+	fn keys_are_hit_exactly_and_held_either_side() {
+		for &interpolation in [Interpolation::Linear, Interpolation::Bezier(3.0, -2.0), Interpolation::CatmullRom].iter() {
+			let track = track(&[(2.0, 1.0), (5.0, 4.0), (9.0, -3.0)], interpolation);
+			assert_eq!(track.sample(2.0), 1.0);
+			assert_eq!(track.sample(5.0), 4.0);
+			assert_eq!(track.sample(9.0), -3.0);
+			assert_eq!(track.sample(-10.0), 1.0);
+			assert_eq!(track.sample(1.9), 1.0);
+			assert_eq!(track.sample(9.1), -3.0);
+			assert_eq!(track.sample(1000.0), -3.0);
+		}
+	}
+
+	#[test]
+	fn keys_are_sorted_by_frame() {
+		let track = track(&[(5.0, 4.0), (2.0, 1.0)], Interpolation::Linear);
+		assert_eq!(track.sample(0.0), 1.0);
+		assert_eq!(track.sample(3.5), 2.5);
+	}
+
+	#[test]
+	fn interpolations_between_keys() {
+		assert_eq!(track(&[(0.0, 0.0), (4.0, 2.0)], Interpolation::Linear).sample(1.0), 0.5);
+		//0.2 * 3/8 + 0.6 * 3/8 + 1 / 8 halfway along
+		let bezier = track(&[(0.0, 0.0), (1.0, 1.0)], Interpolation::Bezier(0.2, 0.6));
+		assert!((bezier.sample(0.5) - 0.425).abs() < 1e-6);
+		//Keys on x squared give tangents of 2 and 4 at x = 1 and 2, and the Hermite curve between them
+		//1 * 1/2 + 2 * 1/8 + 4 * 1/2 - 4 * 1/8 = 2.25 at 1.5, which is x squared again
+		let catmull_rom = track(&[(0.0, 0.0), (1.0, 1.0), (2.0, 4.0), (3.0, 9.0)], Interpolation::CatmullRom);
+		assert!((catmull_rom.sample(1.5) - 2.25).abs() < 1e-6);
+	}
+
+	#[test]
+	fn tracks_without_keys_or_with_nan_frames_are_errors() {
+		assert!(Track::<f32>::new(Vec::new()).is_err());
+		assert!(Track::new(vec![Keyframe::new(1.0, 0.0, Interpolation::Linear), Keyframe::new(f32::NAN, 1.0, Interpolation::Linear)]).is_err());
+	}
+
+	#[test]
+	fn scene_at_animates_only_the_chosen_objects() {
+		let mut description = SceneDescription::new();
+		let center = alg::Vector3::new(1.0, 2.0, 3.0);
+		description.objects.push(ObjectDescription::Sphere { center, radius : 1.0, colour : alg::Vector3::new(1.0, 1.0, 1.0), material : MaterialDescription::Metal(0.0) });
+		description.objects.push(ObjectDescription::Light { center, intensity : 1.0, colour : alg::Vector3::new(1.0, 1.0, 1.0) });
+		description.objects.push(ObjectDescription::Cube { center, extents : 1.0, colour : alg::Vector3::new(1.0, 1.0, 1.0), material : MaterialDescription::Diffuse });
+
+		let mut sphere = ObjectAnimation::new();
+		sphere.rotation = Some(track(&[(1.0, 0.0), (11.0, 90.0)], Interpolation::Linear));
+		sphere.material = Some(track(&[(1.0, 0.0), (11.0, 0.5)], Interpolation::Linear));
+		let mut light = ObjectAnimation::new();
+		light.translation = Some(Track::new(vec![
+			Keyframe::new(1.0, alg::Vector3::new(0.0, 0.0, 0.0), Interpolation::Linear),
+			Keyframe::new(11.0, alg::Vector3::new(10.0, 0.0, 0.0), Interpolation::Linear),
+		]).unwrap());
+		light.intensity = Some(track(&[(1.0, 1.0), (11.0, 3.0)], Interpolation::Linear));
+		let mut animation = Animation::new();
+		animation.objects.push((0, sphere));
+		animation.objects.push((1, light));
+
+		let scene = animation.scene_at(&description, 6.0);
+		match scene.objects[0] {
+			ObjectDescription::Instance { ref object, pivot, ref rotation, frame, .. } => {
+				assert_eq!(pivot, center);
+				assert_eq!(frame, 6.0);
+				assert_eq!(rotation.as_ref().unwrap().sample(frame), 45.0);
+				match **object {
+					ObjectDescription::Sphere { material : MaterialDescription::Metal(fuzz), .. } => assert_eq!(fuzz, 0.25),
+					_ => panic!("The instance should hold the metal sphere"),
+				}
+			},
+			_ => panic!("A turning sphere should be an instance"),
+		}
+		match scene.objects[1] {
+			ObjectDescription::Light { center : moved, intensity, .. } => {
+				assert_eq!(moved, center + alg::Vector3::new(5.0, 0.0, 0.0));
+				assert_eq!(intensity, 2.0);
+			},
+			_ => panic!("Lights are moved in place"),
+		}
+		match scene.objects[2] {
+			ObjectDescription::Cube { center : unmoved, .. } => assert_eq!(unmoved, center),
+			_ => panic!("Objects without an animation are left alone"),
+		}
+	}
+}
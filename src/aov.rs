@@ -0,0 +1,464 @@
+extern crate nalgebra as alg;
+extern crate image;
+
+use std::f32;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use ray::*;
+use hits::*;
+use scene::*;
+use integrator::*;
+use exr::*;
+use stats::*;
+use checkpoint::*;
+
+//Arbitrary output variables, the extra passes a render can write next to the beauty image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Aov {
+	Beauty,
+	Albedo,
+	Normal,
+	Depth,
+	Position,
+	ObjectId,
+	MaterialId,
+	//Only the path tracer splits its light up, the other integrators leave these black
+	Direct,
+	Indirect,
+	Emission,
+	//How many samples adaptive sampling gave each pixel, as a heatmap
+	SampleCount,
+}
+
+impl Aov {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Aov::Beauty => "beauty",
+			Aov::Albedo => "albedo",
+			Aov::Normal => "normal",
+			Aov::Depth => "depth",
+			Aov::Position => "position",
+			Aov::ObjectId => "object_id",
+			Aov::MaterialId => "material_id",
+			Aov::Direct => "direct",
+			Aov::Indirect => "indirect",
+			Aov::Emission => "emission",
+			Aov::SampleCount => "sample_count",
+		}
+	}
+
+	fn channels(&self) -> &'static [&'static str] {
+		match *self {
+			Aov::Depth => &["Z"],
+			Aov::ObjectId | Aov::MaterialId => &["id"],
+			Aov::SampleCount => &["count"],
+			_ => &["R", "G", "B"],
+		}
+	}
+}
+
+//What the camera ray hit first, shared by every integrator
+pub struct SurfaceSample {
+	pub albedo : alg::Vector3<f32>,
+	pub normal : alg::Vector3<f32>,
+	//distance along the ray, not along the camera's view direction
+	pub depth : f32,
+	pub position : alg::Vector3<f32>,
+	pub object_id : u32,
+	pub material_id : u32,
+}
+
+//Everything one camera sample found
+pub struct AovSample {
+	pub beauty : alg::Vector3<f32>,
+	pub light : LightSplit,
+	//None when the camera ray missed everything
+	pub surface : Option<SurfaceSample>,
+}
+
+impl AovSample {
+	pub fn new(ray : &Ray, scene : &Scene) -> AovSample {
+		let surface = scene.hit(ray, 0.00001, 999.9).map(|hit| {
+			SurfaceSample {
+				albedo : hit.colour,
+				normal : hit.normal,
+				depth : hit.time * ray.direction().norm(),
+				position : hit.pos,
+				object_id : hit.object_id,
+				material_id : hit.material.id(),
+			}
+		});
+
+		AovSample {
+			beauty : alg::Vector3::new(0.0, 0.0, 0.0),
+			light : LightSplit::new(),
+			surface,
+		}
+	}
+}
+
+//Sums of every sample in a pixel. Geometric passes are averaged over the samples that hit something,
+//ids can't be averaged so the first sample to hit something decides them
+#[derive(Copy, Clone)]
+pub struct AovPixel {
+	samples : u32,
+	hits : u32,
+	beauty : alg::Vector3<f32>,
+	albedo : alg::Vector3<f32>,
+	normal : alg::Vector3<f32>,
+	depth : f32,
+	position : alg::Vector3<f32>,
+	object_id : u32,
+	material_id : u32,
+	light : LightSplit,
+	//running mean and sum of squared differences of the beauty luminance, for the variance
+	luminance_mean : f32,
+	luminance_m2 : f32,
+}
+
+impl AovPixel {
+	pub fn new() -> AovPixel {
+		AovPixel {
+			samples : 0,
+			hits : 0,
+			beauty : alg::Vector3::new(0.0, 0.0, 0.0),
+			albedo : alg::Vector3::new(0.0, 0.0, 0.0),
+			normal : alg::Vector3::new(0.0, 0.0, 0.0),
+			depth : 0.0,
+			position : alg::Vector3::new(0.0, 0.0, 0.0),
+			object_id : 0,
+			material_id : 0,
+			light : LightSplit::new(),
+			luminance_mean : 0.0,
+			luminance_m2 : 0.0,
+		}
+	}
+
+	pub fn add(&mut self, sample : &AovSample) {
+		self.samples += 1;
+		self.beauty += sample.beauty;
+		self.light.emission += sample.light.emission;
+		self.light.direct += sample.light.direct;
+		self.light.indirect += sample.light.indirect;
+
+		//Welford's online variance
+		let luminance = luminance(sample.beauty);
+		let delta = luminance - self.luminance_mean;
+		self.luminance_mean += delta / self.samples as f32;
+		self.luminance_m2 += delta * (luminance - self.luminance_mean);
+
+		if let Some(ref surface) = sample.surface {
+			if self.hits == 0 {
+				self.object_id = surface.object_id;
+				self.material_id = surface.material_id;
+			}
+			self.hits += 1;
+			self.albedo += surface.albedo;
+			self.normal += surface.normal;
+			self.depth += surface.depth;
+			self.position += surface.position;
+		}
+	}
+
+	pub fn beauty(&self) -> alg::Vector3<f32> {
+		self.beauty / (self.samples.max(1) as f32)
+	}
+
+	pub fn samples(&self) -> u32 {
+		self.samples
+	}
+
+	//Standard error of the mean luminance carried through the gamma the images are saved with, roughly how visible the remaining noise is
+	pub fn noise(&self) -> f32 {
+		if self.samples < 2 {
+			return f32::INFINITY;
+		}
+
+		let variance = self.luminance_m2 / (self.samples - 1) as f32;
+		(variance / self.samples as f32).sqrt() / (2.0 * self.luminance_mean.max(0.0001).sqrt())
+	}
+
+	//Replace the beauty with an already averaged colour, like the output of the denoiser
+	pub fn set_beauty(&mut self, beauty : alg::Vector3<f32>) {
+		self.beauty = beauty * (self.samples.max(1) as f32);
+	}
+
+	//Replace the beauty and light passes with already averaged ones, like the film's filtered values
+	pub fn set_light(&mut self, beauty : alg::Vector3<f32>, light : LightSplit) {
+		let samples = self.samples.max(1) as f32;
+		self.beauty = beauty * samples;
+		self.light.emission = light.emission * samples;
+		self.light.direct = light.direct * samples;
+		self.light.indirect = light.indirect * samples;
+	}
+
+	pub fn albedo(&self) -> alg::Vector3<f32> {
+		self.albedo / (self.samples.max(1) as f32)
+	}
+
+	//Zero where nothing was hit
+	pub fn normal(&self) -> alg::Vector3<f32> {
+		if self.hits == 0 {
+			return self.normal;
+		}
+		self.normal.normalize()
+	}
+
+	//Infinite where nothing was hit
+	pub fn depth(&self) -> f32 {
+		if self.hits == 0 {
+			return f32::INFINITY;
+		}
+		self.depth / self.hits as f32
+	}
+
+	pub fn position(&self) -> alg::Vector3<f32> {
+		self.position / (self.hits.max(1) as f32)
+	}
+
+	pub fn write_to<W : Write>(&self, writer : &mut W) -> io::Result<()> {
+		write_u32(writer, self.samples)?;
+		write_u32(writer, self.hits)?;
+		write_vector(writer, &self.beauty)?;
+		write_vector(writer, &self.albedo)?;
+		write_vector(writer, &self.normal)?;
+		write_f32(writer, self.depth)?;
+		write_vector(writer, &self.position)?;
+		write_u32(writer, self.object_id)?;
+		write_u32(writer, self.material_id)?;
+		write_vector(writer, &self.light.emission)?;
+		write_vector(writer, &self.light.direct)?;
+		write_vector(writer, &self.light.indirect)?;
+		write_f32(writer, self.luminance_mean)?;
+		write_f32(writer, self.luminance_m2)
+	}
+
+	pub fn read_from<R : Read>(reader : &mut R) -> io::Result<AovPixel> {
+		let mut pixel = AovPixel::new();
+		pixel.samples = read_u32(reader)?;
+		pixel.hits = read_u32(reader)?;
+		pixel.beauty = read_vector(reader)?;
+		pixel.albedo = read_vector(reader)?;
+		pixel.normal = read_vector(reader)?;
+		pixel.depth = read_f32(reader)?;
+		pixel.position = read_vector(reader)?;
+		pixel.object_id = read_u32(reader)?;
+		pixel.material_id = read_u32(reader)?;
+		pixel.light.emission = read_vector(reader)?;
+		pixel.light.direct = read_vector(reader)?;
+		pixel.light.indirect = read_vector(reader)?;
+		pixel.luminance_mean = read_f32(reader)?;
+		pixel.luminance_m2 = read_f32(reader)?;
+		Ok(pixel)
+	}
+
+	//The value of a pass as floats, one per channel
+	fn values(&self, aov : Aov) -> Vec<f32> {
+		let samples = self.samples.max(1) as f32;
+		let colour = match aov {
+			Aov::Beauty => self.beauty(),
+			Aov::Albedo => self.albedo(),
+			Aov::Normal => self.normal(),
+			Aov::Position => self.position(),
+			Aov::Direct => self.light.direct / samples,
+			Aov::Indirect => self.light.indirect / samples,
+			Aov::Emission => self.light.emission / samples,
+			Aov::Depth => return vec![self.depth()],
+			Aov::ObjectId => return vec![self.object_id as f32],
+			Aov::MaterialId => return vec![self.material_id as f32],
+			Aov::SampleCount => return vec![self.samples as f32],
+		};
+		vec![colour.x, colour.y, colour.z]
+	}
+}
+
+pub fn luminance(colour : alg::Vector3<f32>) -> f32 {
+	0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z
+}
+
+//A distinct, stable colour for every id so neighbouring objects are easy to tell apart, black for the background
+fn id_colour(id : u32) -> alg::Vector3<f32> {
+	if id == 0 {
+		return alg::Vector3::new(0.0, 0.0, 0.0);
+	}
+
+	let mut hash = id.wrapping_mul(0x9E3779B1);
+	hash ^= hash >> 16;
+	hash = hash.wrapping_mul(0x85EBCA6B);
+	hash ^= hash >> 13;
+	alg::Vector3::new((hash & 0xFF) as f32 / 255.0, ((hash >> 8) & 0xFF) as f32 / 255.0, ((hash >> 16) & 0xFF) as f32 / 255.0)
+}
+
+pub fn write_png(filename : &str, width : usize, height : usize, pixels : &[u8]) {
+	let path = Path::new(filename);
+	let display = path.display();
+
+	let file = match File::create(path) {
+		Err(why) => panic!("Couldn't create {}: {}", display, why),
+		Ok(file) => file,
+	};
+
+	let writer = BufWriter::new(&file);
+	if let Err(why) = encode_png(writer, width, height, pixels) {
+		panic!("hmm? {}", why);
+	}
+}
+
+//8 bit RGB pixels, top row first, as a PNG written to anything, like a buffer to send somewhere
+pub fn encode_png<W : Write>(writer : W, width : usize, height : usize, pixels : &[u8]) -> io::Result<()> {
+	let out_image = image::png::PNGEncoder::new(writer);
+	out_image.encode(pixels, width as u32, height as u32, image::ColorType::RGB(8))
+}
+
+fn to_byte(value : f32) -> u8 {
+	(255.99 * value.clamp(0.0, 1.0)) as u8
+}
+
+//Every pass of a frame, top row first
+#[derive(Clone)]
+pub struct AovImage {
+	pub width : usize,
+	pub height : usize,
+	pub pixels : Vec<AovPixel>,
+}
+
+impl AovImage {
+	//Every pixel black with no samples, until tiles are placed in to it
+	pub fn new(width : usize, height : usize) -> AovImage {
+		AovImage {
+			width,
+			height,
+			pixels : vec![AovPixel::new(); width * height],
+		}
+	}
+
+	//Counted from the bottom like the camera
+	pub fn pixel(&self, x : usize, y : usize) -> AovPixel {
+		self.pixels[(self.height - 1 - y) * self.width + x]
+	}
+
+	//Pixels x0..x1 and y0..y1 counted from the bottom like the camera, given bottom row first
+	pub fn place_tile(&mut self, x0 : usize, y0 : usize, x1 : usize, y1 : usize, pixels : &[AovPixel]) {
+		let tile_width = x1 - x0;
+		for y in y0..y1 {
+			let row = (self.height - 1 - y) * self.width;
+			let tile_row = (y - y0) * tile_width;
+			self.pixels[row + x0..row + x1].copy_from_slice(&pixels[tile_row..tile_row + tile_width]);
+		}
+	}
+
+	pub fn set_beauty(&mut self, beauty : Vec<alg::Vector3<f32>>) {
+		for (pixel, colour) in self.pixels.iter_mut().zip(beauty) {
+			pixel.set_beauty(colour);
+		}
+	}
+
+	//A pass as 8 bit RGB. Light passes get the same gamma as the beauty image, the rest are scaled to fit in to view
+	pub fn pass_pixels(&self, aov : Aov) -> Vec<u8> {
+		let max_samples = self.pixels.iter().map(|pixel| pixel.samples).max().unwrap_or(1);
+		let mut max_depth : f32 = 0.0;
+		let mut min_position = alg::Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+		let mut max_position = alg::Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+		for pixel in self.pixels.iter().filter(|pixel| pixel.hits > 0) {
+			max_depth = max_depth.max(pixel.depth());
+			for axis in 0..3 {
+				min_position[axis] = min_position[axis].min(pixel.position()[axis]);
+				max_position[axis] = max_position[axis].max(pixel.position()[axis]);
+			}
+		}
+
+		let mut bytes : Vec<u8> = Vec::with_capacity(self.width * self.height * 3);
+		for pixel in self.pixels.iter() {
+			let colour = match aov {
+				Aov::Normal => {
+					if pixel.hits == 0 {
+						alg::Vector3::new(0.0, 0.0, 0.0)
+					}
+					else {
+						0.5 * (pixel.normal() + alg::Vector3::new(1.0, 1.0, 1.0))
+					}
+				},
+				Aov::Depth => {
+					let depth = if pixel.hits == 0 { 0.0 } else { 1.0 - pixel.depth() / max_depth.max(0.0001) };
+					alg::Vector3::new(depth, depth, depth)
+				},
+				Aov::Position => {
+					let mut position = alg::Vector3::new(0.0, 0.0, 0.0);
+					if pixel.hits > 0 {
+						for axis in 0..3 {
+							position[axis] = (pixel.position()[axis] - min_position[axis]) / (max_position[axis] - min_position[axis]).max(0.0001);
+						}
+					}
+					position
+				},
+				Aov::ObjectId => id_colour(pixel.object_id),
+				Aov::MaterialId => id_colour(pixel.material_id),
+				Aov::SampleCount => heatmap_colour(pixel.samples, max_samples),
+				_ => {
+					let values = pixel.values(aov);
+					alg::Vector3::new(values[0].max(0.0).sqrt(), values[1].max(0.0).sqrt(), values[2].max(0.0).sqrt())
+				},
+			};
+
+			bytes.push(to_byte(colour.x));
+			bytes.push(to_byte(colour.y));
+			bytes.push(to_byte(colour.z));
+		}
+
+		bytes
+	}
+
+	//The beauty pass goes to name.png and every other pass to name_pass.png, or all of them in to layers of name.exr
+	pub fn save(&self, name : &str, aovs : &[Aov], exr : bool) {
+		if exr {
+			self.save_exr(name, aovs);
+			return;
+		}
+
+		for aov in aovs.iter() {
+			let filename = match *aov {
+				Aov::Beauty => format!("{}.png", name),
+				_ => format!("{}_{}.png", name, aov.name()),
+			};
+			println!("Saving {}", filename);
+			write_png(&filename, self.width, self.height, &self.pass_pixels(*aov));
+		}
+	}
+
+	fn save_exr(&self, name : &str, aovs : &[Aov]) {
+		let mut values : Vec<(String, Vec<f32>)> = Vec::new();
+		for aov in aovs.iter() {
+			let channels = aov.channels();
+			let mut passes : Vec<Vec<f32>> = vec![Vec::with_capacity(self.pixels.len()); channels.len()];
+			for pixel in self.pixels.iter() {
+				for (channel, value) in pixel.values(*aov).into_iter().enumerate() {
+					passes[channel].push(value);
+				}
+			}
+
+			for (channel, pass) in channels.iter().zip(passes) {
+				let channel_name = match *aov {
+					Aov::Beauty => channel.to_string(),
+					_ => format!("{}.{}", aov.name(), channel),
+				};
+				values.push((channel_name, pass));
+			}
+		}
+
+		let mut channels : Vec<ExrChannel> = values.iter().map(|(channel_name, pass)| {
+			ExrChannel {
+				name : channel_name.clone(),
+				values : &pass[..],
+			}
+		}).collect();
+
+		let filename = format!("{}.exr", name);
+		println!("Saving {}", filename);
+		if let Err(why) = write_exr(Path::new(&filename), self.width, self.height, &mut channels) {
+			panic!("Couldn't write {}: {}", filename, why);
+		}
+	}
+}
@@ -0,0 +1,444 @@
+extern crate nalgebra as alg;
+
+use ray::*;
+use hits::*;
+use scene::*;
+use util::*;
+use sampler::*;
+use integrator::*;
+
+//Longest path, counted in bounces between the camera and the light
+pub static MAX_BDPT_DEPTH : usize = 8;
+
+#[derive(Copy, Clone, PartialEq)]
+enum VertexKind {
+	Camera,
+	Light,
+	Surface,
+}
+
+//One point along a camera or light subpath. pdf_fwd is the density (per unit area) of reaching this vertex from
+//the one before it in the subpath, pdf_rev the density of reaching it if the path was traced the other way
+struct Vertex {
+	kind : VertexKind,
+	pos : alg::Vector3<f32>,
+	//zero for the camera, point and spot lights
+	normal : alg::Vector3<f32>,
+	//direction of the ray that arrived here, for evaluating the material
+	incoming : alg::Vector3<f32>,
+	hit : Option<HitInfo>,
+	light : usize,
+	beta : alg::Vector3<f32>,
+	pdf_fwd : f32,
+	pdf_rev : f32,
+	//a specular bounce, delta lights are checked with is_delta instead
+	delta : bool,
+}
+
+impl Vertex {
+	fn new(kind : VertexKind, pos : alg::Vector3<f32>, normal : alg::Vector3<f32>, beta : alg::Vector3<f32>, pdf_fwd : f32) -> Vertex {
+		Vertex {
+			kind,
+			pos,
+			normal,
+			incoming : alg::Vector3::new(0.0, 0.0, 0.0),
+			hit : None,
+			light : 0,
+			beta,
+			pdf_fwd,
+			pdf_rev : 0.0,
+			delta : false,
+		}
+	}
+
+	fn is_on_surface(&self) -> bool {
+		self.normal != alg::Vector3::new(0.0, 0.0, 0.0)
+	}
+
+	//Turn a solid angle density of leaving this vertex towards next in to a density per unit area at next
+	fn convert_density(&self, pdf : f32, next : &Vertex) -> f32 {
+		let w = next.pos - self.pos;
+		let distance_squared = squared_length(w);
+		if distance_squared == 0.0 {
+			return 0.0;
+		}
+
+		let mut pdf = pdf / distance_squared;
+		if next.is_on_surface() {
+			pdf *= next.normal.dot(&(w / distance_squared.sqrt())).abs();
+		}
+		pdf
+	}
+
+	//BSDF times the cosine at this vertex for light leaving towards next
+	fn evaluate(&self, next : &Vertex) -> alg::Vector3<f32> {
+		let hit = match self.hit {
+			Some(ref hit) => hit,
+			None => return alg::Vector3::new(0.0, 0.0, 0.0),
+		};
+
+		let direction = next.pos - self.pos;
+		if squared_length(direction) == 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+
+		let ray = Ray::new(self.pos - self.incoming, self.incoming);
+		hit.material.evaluate(&ray, hit, direction.normalize())
+	}
+
+	//Density per unit area of a path arriving from prev carrying on to next
+	fn pdf(&self, scene : &Scene, prev : Option<&Vertex>, next : &Vertex) -> f32 {
+		if self.kind == VertexKind::Light {
+			return self.pdf_light(scene, next);
+		}
+
+		let hit = match self.hit {
+			Some(ref hit) => hit,
+			None => return 0.0,
+		};
+
+		let prev = match prev {
+			Some(prev) => prev,
+			None => return 0.0,
+		};
+
+		let direction = next.pos - self.pos;
+		let incoming = self.pos - prev.pos;
+		if squared_length(direction) == 0.0 || squared_length(incoming) == 0.0 {
+			return 0.0;
+		}
+
+		let ray = Ray::new(prev.pos, incoming.normalize());
+		self.convert_density(hit.material.pdf(&ray, hit, direction.normalize()), next)
+	}
+
+	//Density per unit area at next of this point on a light sending light towards it
+	fn pdf_light(&self, scene : &Scene, next : &Vertex) -> f32 {
+		let light = &scene.lights[self.light];
+		let w = next.pos - self.pos;
+		let distance_squared = squared_length(w);
+		if distance_squared == 0.0 {
+			return 0.0;
+		}
+
+		let direction = w / distance_squared.sqrt();
+		//Light from infinitely far away arrives spread evenly over the disc it's sent from instead
+		if light.is_infinite() {
+			let mut pdf = light.pdf_position();
+			if next.is_on_surface() {
+				pdf *= next.normal.dot(&self.normal).abs();
+			}
+			return pdf;
+		}
+
+		let mut pdf = light.pdf_direction(self.normal, direction) / distance_squared;
+		if next.is_on_surface() {
+			pdf *= next.normal.dot(&direction).abs();
+		}
+		pdf
+	}
+
+	//Density of picking this light and this point on it
+	fn pdf_light_origin(&self, scene : &Scene) -> f32 {
+		scene.lights[self.light].pdf_position() / scene.lights.len() as f32
+	}
+}
+
+//Bounce a subpath around the scene, adding a vertex at every hit. Camera subpaths that hit a light end on a light vertex,
+//ones that escape return the background they see
+fn random_walk(scene : &Scene, mut ray : Ray, mut beta : alg::Vector3<f32>, pdf : f32, path : &mut Vec<Vertex>, camera_path : bool) -> alg::Vector3<f32> {
+	let mut pdf_fwd = pdf;
+
+	while path.len() <= MAX_BDPT_DEPTH {
+		let hit = scene.hit(&ray, 0.00001, 999.9);
+		let time_max = match hit {
+			Some(ref hit) => hit.time,
+			None => 999.9,
+		};
+
+		if let Some((index, time)) = scene.hit_light(&ray, 0.00001, time_max) {
+			if camera_path {
+				let pos = ray.point_at_parameter(time);
+				let normal = scene.lights[index].normal(pos);
+				let mut vertex = Vertex::new(VertexKind::Light, pos, normal, beta, 0.0);
+				vertex.light = index;
+				vertex.incoming = ray.direction().normalize();
+				vertex.pdf_fwd = path[path.len() - 1].convert_density(pdf_fwd, &vertex);
+				path.push(vertex);
+			}
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+
+		let hit = match hit {
+			Some(hit) => hit,
+			None => {
+				if camera_path {
+					//Infinitely far lights can't be found this way when connecting to them could have, so only through
+					//the camera or a mirror
+					let mut radiance = background(&ray, scene);
+					let previous = &path[path.len() - 1];
+					if previous.kind == VertexKind::Camera || previous.delta {
+						radiance += infinite_light_radiance(&ray, scene);
+					}
+					return multiply_colour(beta, radiance);
+				}
+				return alg::Vector3::new(0.0, 0.0, 0.0);
+			}
+		};
+
+		let mut vertex = Vertex::new(VertexKind::Surface, hit.pos, hit.normal, beta, 0.0);
+		vertex.incoming = ray.direction().normalize();
+		vertex.pdf_fwd = path[path.len() - 1].convert_density(pdf_fwd, &vertex);
+
+		let sample = hit.material.sample(&ray, &hit, next_sample_2d());
+		let sample = match sample {
+			Some(sample) => sample,
+			None => {
+				vertex.hit = Some(hit);
+				path.push(vertex);
+				break;
+			}
+		};
+
+		//Density of the path going the other way, out along the incoming ray
+		let mut pdf_rev = 0.0;
+		if sample.specular {
+			vertex.delta = true;
+			pdf_fwd = 0.0;
+		}
+		else {
+			let reverse = Ray::new(hit.pos + sample.direction, -sample.direction);
+			pdf_rev = hit.material.pdf(&reverse, &hit, -vertex.incoming);
+			pdf_fwd = sample.pdf;
+		}
+
+		beta = multiply_colour(beta, sample.weight);
+		ray = spawn_ray(&hit, sample.direction);
+		vertex.hit = Some(hit);
+
+		let previous = path.len() - 1;
+		path[previous].pdf_rev = vertex.convert_density(pdf_rev, &path[previous]);
+		path.push(vertex);
+	}
+
+	alg::Vector3::new(0.0, 0.0, 0.0)
+}
+
+fn light_subpath(scene : &Scene, path : &mut Vec<Vertex>) {
+	let index = ((next_sample_1d() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+	let light = &scene.lights[index];
+	let light_pdf = 1.0 / scene.lights.len() as f32;
+
+	let (pos, normal, pdf_position) = light.sample_position(next_sample_2d());
+	let (direction, pdf_direction) = light.sample_direction(normal, next_sample_2d());
+	if pdf_direction == 0.0 {
+		return;
+	}
+
+	let emitted = light.emitted_towards(normal, direction);
+	let mut vertex = Vertex::new(VertexKind::Light, pos, normal, emitted, pdf_position * light_pdf);
+	vertex.light = index;
+	path.push(vertex);
+
+	let mut cos_theta = 1.0;
+	if !light.is_delta() {
+		cos_theta = normal.dot(&direction).abs();
+	}
+	let beta = emitted * (cos_theta / (light_pdf * pdf_position * pdf_direction));
+	random_walk(scene, Ray::new(pos + direction * 0.0001, direction), beta, pdf_direction, path, false);
+
+	//Parallel light doesn't spread out with distance, so the first hit's density is just where on the disc it started
+	if light.is_infinite() && path.len() > 1 {
+		let mut pdf = pdf_position * light_pdf;
+		if path[1].is_on_surface() {
+			pdf *= path[1].normal.dot(&direction).abs();
+		}
+		path[1].pdf_fwd = pdf;
+	}
+}
+
+fn visible(scene : &Scene, a : &Vertex, b : &Vertex) -> bool {
+	let w = b.pos - a.pos;
+	let distance = squared_length(w).sqrt();
+	let direction = w / distance;
+	let mut origin = a.pos;
+	if a.is_on_surface() {
+		origin += a.normal * (0.0001 * a.normal.dot(&direction).signum());
+	}
+	let mut end = distance - 0.0001;
+	if b.is_on_surface() {
+		end -= 0.0001;
+	}
+
+	scene.hit(&Ray::new(origin, direction), 0.00001, end).is_none()
+}
+
+fn remap0(f : f32) -> f32 {
+	if f != 0.0 { f } else { 1.0 }
+}
+
+//Weight of this connection strategy against every other way the same path could have been built, with the balance heuristic.
+//Light tracing straight to the camera (t = 1) isn't one of our strategies so it's left out of the sum
+fn mis_weight(scene : &Scene, camera : &[Vertex], light : &[Vertex], sampled : Option<&Vertex>, s : usize, t : usize) -> f32 {
+	if s + t == 2 {
+		return 1.0;
+	}
+
+	let light_vertex = |i : usize| -> &Vertex {
+		if s == 1 {
+			return sampled.unwrap();
+		}
+		&light[i]
+	};
+
+	let camera_fwd : Vec<f32> = camera.iter().take(t).map(|v| v.pdf_fwd).collect();
+	let mut camera_rev : Vec<f32> = camera.iter().take(t).map(|v| v.pdf_rev).collect();
+	let mut camera_delta : Vec<bool> = camera.iter().take(t).map(|v| v.delta).collect();
+	let light_fwd : Vec<f32> = (0..s).map(|i| light_vertex(i).pdf_fwd).collect();
+	let mut light_rev : Vec<f32> = (0..s).map(|i| light_vertex(i).pdf_rev).collect();
+	let mut light_delta : Vec<bool> = (0..s).map(|i| light_vertex(i).delta).collect();
+
+	let pt = &camera[t - 1];
+	let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+	let qs = if s > 0 { Some(light_vertex(s - 1)) } else { None };
+	let qs_minus = if s > 1 { Some(light_vertex(s - 2)) } else { None };
+
+	//The densities at the two vertices being joined (and the ones before them) change now that they're connected
+	camera_rev[t - 1] = match qs {
+		Some(qs) => qs.pdf(scene, qs_minus, pt),
+		None => pt.pdf_light_origin(scene),
+	};
+	if let Some(pt_minus) = pt_minus {
+		camera_rev[t - 2] = match qs {
+			Some(qs) => pt.pdf(scene, Some(qs), pt_minus),
+			None => pt.pdf_light(scene, pt_minus),
+		};
+	}
+	if let Some(qs) = qs {
+		light_rev[s - 1] = pt.pdf(scene, pt_minus, qs);
+		if let Some(qs_minus) = qs_minus {
+			light_rev[s - 2] = qs.pdf(scene, Some(pt), qs_minus);
+		}
+	}
+	camera_delta[t - 1] = false;
+	if s > 0 {
+		light_delta[s - 1] = false;
+	}
+
+	let mut sum = 0.0;
+	let mut ratio = 1.0;
+	let mut i = t - 1;
+	while i > 1 {
+		ratio *= remap0(camera_rev[i]) / remap0(camera_fwd[i]);
+		if !camera_delta[i] && !camera_delta[i - 1] {
+			sum += ratio;
+		}
+		i -= 1;
+	}
+
+	ratio = 1.0;
+	for i in (0..s).rev() {
+		ratio *= remap0(light_rev[i]) / remap0(light_fwd[i]);
+		//Camera paths never end on infinitely far lights, so they count as delta here
+		let delta_light = if i > 0 { light_delta[i - 1] } else {
+			let light = &scene.lights[light_vertex(0).light];
+			light.is_delta() || light.is_infinite()
+		};
+		if !light_delta[i] && !delta_light {
+			sum += ratio;
+		}
+	}
+
+	1.0 / (1.0 + sum)
+}
+
+//Joins the first s vertices of the light subpath to the first t of the camera subpath
+fn connect(scene : &Scene, camera : &[Vertex], light : &[Vertex], s : usize, t : usize) -> alg::Vector3<f32> {
+	let pt = &camera[t - 1];
+	let zero = alg::Vector3::new(0.0, 0.0, 0.0);
+
+	if s == 0 {
+		if pt.kind != VertexKind::Light {
+			return zero;
+		}
+
+		let radiance = multiply_colour(pt.beta, scene.lights[pt.light].emitted_towards(pt.normal, -pt.incoming));
+		if radiance == zero {
+			return zero;
+		}
+		return radiance * mis_weight(scene, camera, light, None, s, t);
+	}
+
+	if pt.kind != VertexKind::Surface || pt.delta {
+		return zero;
+	}
+
+	if s == 1 {
+		//Sample a fresh direction to a light like direct lighting does, rather than using the start of the light subpath
+		let index = ((next_sample_1d() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+		let light_source = &scene.lights[index];
+		let light_pdf = 1.0 / scene.lights.len() as f32;
+		let sample = match light_source.sample(pt.pos, next_sample_2d()) {
+			Some(sample) => sample,
+			None => return zero,
+		};
+		if sample.pdf == 0.0 {
+			return zero;
+		}
+
+		let pos = pt.pos + sample.direction * sample.distance;
+		let mut sampled = Vertex::new(VertexKind::Light, pos, light_source.normal(pos), sample.radiance / (light_pdf * sample.pdf), 0.0);
+		sampled.light = index;
+		sampled.pdf_fwd = sampled.pdf_light_origin(scene);
+
+		let radiance = multiply_colour(multiply_colour(pt.beta, pt.evaluate(&sampled)), sampled.beta);
+		if radiance == zero || !visible(scene, pt, &sampled) {
+			return zero;
+		}
+		return radiance * mis_weight(scene, camera, light, Some(&sampled), s, t);
+	}
+
+	let qs = &light[s - 1];
+	if qs.kind != VertexKind::Surface || qs.delta {
+		return zero;
+	}
+
+	let distance_squared = squared_length(qs.pos - pt.pos);
+	if distance_squared == 0.0 {
+		return zero;
+	}
+
+	let radiance = multiply_colour(multiply_colour(qs.beta, qs.evaluate(pt)), multiply_colour(pt.evaluate(qs), pt.beta)) / distance_squared;
+	if radiance == zero || !visible(scene, qs, pt) {
+		return zero;
+	}
+	radiance * mis_weight(scene, camera, light, None, s, t)
+}
+
+//Traces a subpath from the camera and another from a light, then joins every pair of their vertices
+pub fn bidirectional_colour(ray : Ray, scene : &Scene) -> alg::Vector3<f32> {
+	let mut camera : Vec<Vertex> = Vec::with_capacity(MAX_BDPT_DEPTH + 2);
+	camera.push(Vertex::new(VertexKind::Camera, ray.origin(), alg::Vector3::new(0.0, 0.0, 0.0), alg::Vector3::new(1.0, 1.0, 1.0), 1.0));
+	let direction = ray.direction().normalize();
+	let mut radiance = random_walk(scene, Ray::new(ray.origin(), direction), alg::Vector3::new(1.0, 1.0, 1.0), 1.0, &mut camera, true);
+
+	let mut light : Vec<Vertex> = Vec::with_capacity(MAX_BDPT_DEPTH + 1);
+	if !scene.lights.is_empty() {
+		light_subpath(scene, &mut light);
+	}
+
+	for t in 2..camera.len() + 1 {
+		for s in 0..light.len() + 1 {
+			if s + t - 2 > MAX_BDPT_DEPTH {
+				continue;
+			}
+			if s > 0 && scene.lights.is_empty() {
+				continue;
+			}
+
+			radiance += connect(scene, &camera, &light, s, t);
+		}
+	}
+
+	radiance
+}
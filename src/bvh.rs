@@ -1,17 +1,19 @@
 use hits::*;
 use aabb::*;
 use ray::*;
+use stats::*;
 extern crate nalgebra as alg;
 use std::f32;
 use std::cmp::Ordering;
+use std::mem;
 
 pub struct BVHNode {
-    left: Box<Hitable + Sync>,
-    right: Box<Hitable + Sync>,
+    left: Box<dyn Hitable + Sync>,
+    right: Box<dyn Hitable + Sync>,
     bbox: AABB,
 }
 
-fn compare_x(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
+fn compare_x(a: &Box<dyn Hitable + Sync>, b: &Box<dyn Hitable + Sync>) -> Ordering {
     let l = a.bounding_box(0.0, 0.0).unwrap();
     let r = b.bounding_box(0.0, 0.0).unwrap();
     if l.min().x < r.min().x {
@@ -21,7 +23,7 @@ fn compare_x(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
     }
 }
 
-fn compare_y(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
+fn compare_y(a: &Box<dyn Hitable + Sync>, b: &Box<dyn Hitable + Sync>) -> Ordering {
     let l = a.bounding_box(0.0, 0.0).unwrap();
     let r = b.bounding_box(0.0, 0.0).unwrap();
     if l.min().y < r.min().y {
@@ -31,7 +33,7 @@ fn compare_y(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
     }
 }
 
-fn compare_z(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
+fn compare_z(a: &Box<dyn Hitable + Sync>, b: &Box<dyn Hitable + Sync>) -> Ordering {
     let l = a.bounding_box(0.0, 0.0).unwrap();
     let r = b.bounding_box(0.0, 0.0).unwrap();
     if l.min().z < r.min().z {
@@ -42,7 +44,7 @@ fn compare_z(a: &Box<Hitable + Sync>, b: &Box<Hitable + Sync>) -> Ordering {
 }
 
 impl BVHNode {
-    pub fn new(mut list: Vec<Box<Hitable + Sync>>, time_min: f32, time_max: f32) -> BVHNode
+    pub fn new(mut list: Vec<Box<dyn Hitable + Sync>>, time_min: f32, time_max: f32) -> BVHNode
     {
         let length = list.len();
         assert_ne!(length, 0);
@@ -153,4 +155,10 @@ impl Hitable for BVHNode {
     fn bounding_box(&self, _time_min: f32, _time_max: f32) -> Option<AABB> {
         Some(self.bbox.clone())
     }
-}
\ No newline at end of file
+
+    fn accumulate_stats(&self, stats: &mut BVHStats, depth: usize) {
+        stats.add_node(&self.bbox, depth, mem::size_of::<BVHNode>());
+        self.left.accumulate_stats(stats, depth + 1);
+        self.right.accumulate_stats(stats, depth + 1);
+    }
+}
@@ -0,0 +1,351 @@
+extern crate nalgebra as alg;
+extern crate memmap;
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use memmap::Mmap;
+
+use aabb::*;
+use mesh::*;
+
+//Bump this whenever the layout of the cache or the way the BVH is built changes
+const CACHE_VERSION : u32 = 1;
+const CACHE_MAGIC : &[u8; 4] = b"RTMC";
+
+//magic, version, source hash, node count, triangle count, payload checksum
+const HEADER_SIZE : usize = 4 + 4 + 8 + 4 + 4 + 8;
+//min, max, offset, count
+const NODE_SIZE : usize = 6 * 4 + 4 + 4;
+//three vertices and a normal
+const TRIANGLE_SIZE : usize = 12 * 4;
+
+#[derive(Debug)]
+pub enum CacheError {
+	Io(io::Error),
+	Stale,
+	Corrupt(&'static str),
+}
+
+impl fmt::Display for CacheError {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CacheError::Io(ref why) => write!(f, "{}", why),
+			CacheError::Stale => write!(f, "source file has changed"),
+			CacheError::Corrupt(why) => write!(f, "corrupted ({})", why),
+		}
+	}
+}
+
+impl From<io::Error> for CacheError {
+	fn from(why : io::Error) -> CacheError {
+		CacheError::Io(why)
+	}
+}
+
+pub fn cache_path_for(source : &Path) -> PathBuf {
+	let mut name = source.as_os_str().to_owned();
+	name.push(".bvhcache");
+	PathBuf::from(name)
+}
+
+fn hash_bytes(bytes : &[u8]) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	hasher.write(bytes);
+	hasher.finish()
+}
+
+//The std hasher isn't guaranteed to stay the same between compiler versions, which at worst just forces a rebuild
+pub fn hash_source_file(source : &Path) -> io::Result<u64> {
+	let mut contents = vec![];
+	File::open(source)?.read_to_end(&mut contents)?;
+	Ok(hash_bytes(&contents))
+}
+
+fn push_u32(bytes : &mut Vec<u8>, value : u32) {
+	bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_f32(bytes : &mut Vec<u8>, value : f32) {
+	bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+}
+
+fn push_vector(bytes : &mut Vec<u8>, value : alg::Vector3<f32>) {
+	push_f32(bytes, value.x);
+	push_f32(bytes, value.y);
+	push_f32(bytes, value.z);
+}
+
+fn read_u32(bytes : &[u8], at : usize) -> u32 {
+	let mut word = [0; 4];
+	word.copy_from_slice(&bytes[at..at + 4]);
+	u32::from_le_bytes(word)
+}
+
+fn read_u64(bytes : &[u8], at : usize) -> u64 {
+	let mut word = [0; 8];
+	word.copy_from_slice(&bytes[at..at + 8]);
+	u64::from_le_bytes(word)
+}
+
+fn read_f32(bytes : &[u8], at : usize) -> f32 {
+	f32::from_bits(read_u32(bytes, at))
+}
+
+fn read_vector(bytes : &[u8], at : usize) -> alg::Vector3<f32> {
+	alg::Vector3::new(read_f32(bytes, at), read_f32(bytes, at + 4), read_f32(bytes, at + 8))
+}
+
+pub fn save_mesh_cache(cache : &Path, source : &Path, nodes : &Vec<MeshNode>, triangles : &Vec<PackedTriangle>) -> Result<(), CacheError> {
+	let source_hash = hash_source_file(source)?;
+
+	let mut payload : Vec<u8> = Vec::with_capacity(nodes.len() * NODE_SIZE + triangles.len() * TRIANGLE_SIZE);
+	for node in nodes {
+		push_vector(&mut payload, node.bbox.min());
+		push_vector(&mut payload, node.bbox.max());
+		push_u32(&mut payload, node.offset);
+		push_u32(&mut payload, node.count);
+	}
+
+	for triangle in triangles {
+		for vertex in triangle.vertices.iter() {
+			for value in vertex.iter() {
+				push_f32(&mut payload, *value);
+			}
+		}
+
+		for value in triangle.normal.iter() {
+			push_f32(&mut payload, *value);
+		}
+	}
+
+	let mut header : Vec<u8> = Vec::with_capacity(HEADER_SIZE);
+	header.extend_from_slice(CACHE_MAGIC);
+	push_u32(&mut header, CACHE_VERSION);
+	header.extend_from_slice(&source_hash.to_le_bytes());
+	push_u32(&mut header, nodes.len() as u32);
+	push_u32(&mut header, triangles.len() as u32);
+	header.extend_from_slice(&hash_bytes(&payload).to_le_bytes());
+
+	//Write to a temporary file first so an interrupted save can't leave a half written cache behind
+	let mut temp_name = cache.as_os_str().to_owned();
+	temp_name.push(".tmp");
+	let temp_path = PathBuf::from(temp_name);
+	{
+		let mut writer = BufWriter::new(File::create(&temp_path)?);
+		writer.write_all(&header)?;
+		writer.write_all(&payload)?;
+		writer.flush()?;
+	}
+
+	::std::fs::rename(&temp_path, cache)?;
+	Ok(())
+}
+
+pub fn load_mesh_cache(cache : &Path, source : &Path) -> Result<(Vec<MeshNode>, Vec<PackedTriangle>), CacheError> {
+	let file = File::open(cache)?;
+	let map = unsafe { Mmap::map(&file)? };
+	let bytes = &map[..];
+
+	if bytes.len() < HEADER_SIZE {
+		return Err(CacheError::Corrupt("truncated header"));
+	}
+
+	if &bytes[0..4] != CACHE_MAGIC {
+		return Err(CacheError::Corrupt("bad magic"));
+	}
+
+	if read_u32(bytes, 4) != CACHE_VERSION {
+		return Err(CacheError::Stale);
+	}
+
+	if read_u64(bytes, 8) != hash_source_file(source)? {
+		return Err(CacheError::Stale);
+	}
+
+	let node_count = read_u32(bytes, 16) as usize;
+	let triangle_count = read_u32(bytes, 20) as usize;
+	let checksum = read_u64(bytes, 24);
+
+	if node_count == 0 || bytes.len() != HEADER_SIZE + node_count * NODE_SIZE + triangle_count * TRIANGLE_SIZE {
+		return Err(CacheError::Corrupt("wrong size"));
+	}
+
+	let payload = &bytes[HEADER_SIZE..];
+	if hash_bytes(payload) != checksum {
+		return Err(CacheError::Corrupt("checksum mismatch"));
+	}
+
+	let mut nodes : Vec<MeshNode> = Vec::with_capacity(node_count);
+	for i in 0..node_count {
+		let at = i * NODE_SIZE;
+		let node = MeshNode {
+			bbox : AABB::new(read_vector(payload, at), read_vector(payload, at + 12)),
+			offset : read_u32(payload, at + 24),
+			count : read_u32(payload, at + 28),
+		};
+
+		//Make sure a bad cache can't send traversal off the end of the lists
+		let in_range = if node.count == 0 {
+			(node.offset as usize) > i && (node.offset as usize) < node_count
+		} else {
+			node.offset as usize + node.count as usize <= triangle_count
+		};
+
+		if !in_range {
+			return Err(CacheError::Corrupt("node out of range"));
+		}
+
+		nodes.push(node);
+	}
+
+	let mut triangles : Vec<PackedTriangle> = Vec::with_capacity(triangle_count);
+	let triangles_start = node_count * NODE_SIZE;
+	for i in 0..triangle_count {
+		let at = triangles_start + i * TRIANGLE_SIZE;
+		let value = |n : usize| read_f32(payload, at + n * 4);
+		triangles.push(PackedTriangle {
+			vertices : [[value(0), value(1), value(2)], [value(3), value(4), value(5)], [value(6), value(7), value(8)]],
+			normal : [value(9), value(10), value(11)],
+		});
+	}
+
+	Ok((nodes, triangles))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+
+	use hits::*;
+	use materials::*;
+	use ray::*;
+	use sampler::*;
+
+	//A bumpy grid of triangles, big enough for the BVH to have a few levels
+	fn write_grid_obj(name : &str) -> PathBuf {
+		let size = 12;
+		let mut contents = String::new();
+		for z in 0..size + 1 {
+			for x in 0..size + 1 {
+				let height = ((x * 7 + z * 13) % 5) as f32 * 0.1;
+				contents.push_str(&format!("v {} {} {}\n", x as f32 * 0.25 - 1.5, height, z as f32 * 0.25 - 1.5));
+			}
+		}
+		for z in 0..size {
+			for x in 0..size {
+				let corner = z * (size + 1) + x + 1;
+				contents.push_str(&format!("f {} {} {}\n", corner, corner + 1, corner + size + 1));
+				contents.push_str(&format!("f {} {} {}\n", corner + 1, corner + size + 2, corner + size + 1));
+			}
+		}
+
+		let path = env::temp_dir().join(name);
+		fs::write(&path, contents).unwrap();
+		let _ = fs::remove_file(cache_path_for(&path));
+		path
+	}
+
+	fn mesh(nodes : Vec<MeshNode>, triangles : Vec<PackedTriangle>) -> Mesh {
+		Mesh {
+			nodes,
+			triangles,
+			colour : alg::Vector3::new(1.0, 1.0, 1.0),
+			material : Box::new(Diffuse::new(1)),
+			id : 1
+		}
+	}
+
+	fn same_nodes(a : &[MeshNode], b : &[MeshNode]) -> bool {
+		a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| {
+			a.bbox.min() == b.bbox.min() && a.bbox.max() == b.bbox.max() && a.offset == b.offset && a.count == b.count
+		})
+	}
+
+	fn same_triangles(a : &[PackedTriangle], b : &[PackedTriangle]) -> bool {
+		a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.vertices == b.vertices && a.normal == b.normal)
+	}
+
+	#[test]
+	fn flattened_bvh_hits_the_same_triangles_as_a_linear_list() {
+		let path = write_grid_obj("rust_raytracer_cache_bvh.obj");
+		let mut triangles = load_obj_triangles(&path).unwrap();
+		let nodes = build_mesh_bvh(&mut triangles);
+		assert!(nodes.len() > 1);
+
+		//One leaf holding every triangle tests them all in turn
+		let everything = MeshNode {
+			bbox : nodes[0].bbox.clone(),
+			offset : 0,
+			count : triangles.len() as u32,
+		};
+		let linear = mesh(vec![everything], triangles.clone());
+		let bvh = mesh(nodes, triangles);
+
+		let mut hits = 0;
+		for i in 0..500 {
+			start_independent_sample(7, i, 0);
+			let origin = alg::Vector3::new(next_sample_1d() * 4.0 - 2.0, 2.0, next_sample_1d() * 4.0 - 2.0);
+			let target = alg::Vector3::new(next_sample_1d() * 3.0 - 1.5, next_sample_1d() * 0.5, next_sample_1d() * 3.0 - 1.5);
+			let ray = Ray::new(origin, target - origin);
+
+			match (bvh.hit(&ray, 0.0001, 999.9), linear.hit(&ray, 0.0001, 999.9)) {
+				(Some(a), Some(b)) => {
+					assert!((a.time - b.time).abs() < 0.00001, "ray {} hit at {} instead of {}", i, a.time, b.time);
+					hits += 1;
+				},
+				(None, None) => {},
+				_ => panic!("ray {} only hit one of the two", i),
+			}
+		}
+		assert!(hits > 100);
+	}
+
+	#[test]
+	fn cache_loads_back_what_was_saved() {
+		let path = write_grid_obj("rust_raytracer_cache_round_trip.obj");
+		let cache = cache_path_for(&path);
+		let mut triangles = load_obj_triangles(&path).unwrap();
+		let nodes = build_mesh_bvh(&mut triangles);
+		save_mesh_cache(&cache, &path, &nodes, &triangles).unwrap();
+
+		let loaded = load_mesh_cache(&cache, &path);
+		fs::remove_file(&cache).unwrap();
+		match loaded {
+			Ok((loaded_nodes, loaded_triangles)) => {
+				assert!(same_nodes(&loaded_nodes, &nodes), "The nodes came back different");
+				assert!(same_triangles(&loaded_triangles, &triangles), "The triangles came back different");
+			},
+			Err(why) => panic!("The cache didn't load: {}", why),
+		}
+	}
+
+	#[test]
+	fn cache_from_another_version_or_source_is_stale() {
+		let path = write_grid_obj("rust_raytracer_cache_stale.obj");
+		let cache = cache_path_for(&path);
+		let mut triangles = load_obj_triangles(&path).unwrap();
+		let nodes = build_mesh_bvh(&mut triangles);
+		save_mesh_cache(&cache, &path, &nodes, &triangles).unwrap();
+
+		let mut bytes = fs::read(&cache).unwrap();
+		bytes[4..8].copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+		fs::write(&cache, &bytes).unwrap();
+		let other_version = load_mesh_cache(&cache, &path);
+
+		save_mesh_cache(&cache, &path, &nodes, &triangles).unwrap();
+		fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+		let other_source = load_mesh_cache(&cache, &path);
+		fs::remove_file(&cache).unwrap();
+
+		assert!(matches!(other_version, Err(CacheError::Stale)), "A cache from another version was used");
+		assert!(matches!(other_source, Err(CacheError::Stale)), "A cache for a changed obj was used");
+	}
+}
@@ -0,0 +1,57 @@
+extern crate nalgebra as alg;
+
+use ray::*;
+
+#[derive(Clone)]
+pub struct Camera {
+	pub origin : alg::Vector3<f32>,
+	pub lower_left_corner : alg::Vector3<f32>,
+	pub horizontal : alg::Vector3<f32>,
+	pub vertical : alg::Vector3<f32>,
+}
+
+impl Camera {
+	pub fn new (p_origin : alg::Vector3<f32>, p_lower_left_corner : alg::Vector3<f32>, p_horizontal : alg::Vector3<f32>, p_vertical : alg::Vector3<f32>) -> Camera {
+		Camera {
+			origin : p_origin,
+			lower_left_corner : p_lower_left_corner,
+			horizontal : p_horizontal,
+			vertical : p_vertical,
+		}
+	}
+
+	//Turned to look from the origin at target, with the image plane sized and placed the same relative to where it looks
+	pub fn look_at(&self, target : alg::Vector3<f32>, up : alg::Vector3<f32>) -> Camera {
+		let base_u = self.horizontal.normalize();
+		let base_v = self.vertical.normalize();
+		let base_w = base_u.cross(&base_v);
+
+		let w = (self.origin - target).normalize();
+		let u = up.cross(&w).normalize();
+		let v = w.cross(&u);
+		let corner = self.lower_left_corner;
+
+		Camera {
+			origin : self.origin,
+			lower_left_corner : u * corner.dot(&base_u) + v * corner.dot(&base_v) + w * corner.dot(&base_w),
+			horizontal : u * self.horizontal.norm(),
+			vertical : v * self.vertical.norm(),
+		}
+	}
+
+	//The image plane scaled about its center, under 1 zooms in
+	pub fn zoomed(&self, zoom : f32) -> Camera {
+		let center = self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5;
+		Camera {
+			origin : self.origin,
+			lower_left_corner : center - (self.horizontal + self.vertical) * (zoom * 0.5),
+			horizontal : self.horizontal * zoom,
+			vertical : self.vertical * zoom,
+		}
+	}
+
+	//u and v go from 0 to 1 across the image, starting in the bottom left
+	pub fn get_ray(&self, u : f32, v : f32) -> Ray {
+		Ray::new(self.origin, self.lower_left_corner + u * self.horizontal + v * self.vertical)
+	}
+}
@@ -0,0 +1,261 @@
+extern crate nalgebra as alg;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use aov::*;
+use film::*;
+use render::*;
+
+//Everything a render has accumulated so far, so it can carry on after being stopped. There's no random number state to keep,
+//every sample's numbers come from the seed, its pixel and its index, so a pixel carries on from the number of samples it has.
+//Only finished tiles are ever in here, the rest of the pixels have no samples yet
+static CHECKPOINT_MAGIC : [u8; 4] = *b"RTCP";
+static CHECKPOINT_VERSION : u32 = 1;
+//Strings are only signatures and error messages, anything longer is a corrupted length
+static MAX_STRING_BYTES : usize = 1 << 16;
+
+pub struct Checkpoint {
+	//The sample count the sampler was set up for, kept when more samples are added so the sequences carry on instead of restarting
+	pub sampler_samples : u32,
+	//Sums, not yet resolved through the film
+	pub image : AovImage,
+	pub film : Film,
+}
+
+//The settings that have to match for samples to be added to a checkpoint, sample counts and noise thresholds can change
+pub fn settings_signature(settings : &RenderSettings) -> String {
+	format!("{}x{} {:?} {:?} seed {} {:?} shutter {} {} heatmap {}", settings.width, settings.height, settings.integrator, settings.sampler,
+		settings.seed, settings.filter, settings.shutter_open, settings.shutter_close, settings.debug_heatmap)
+}
+
+pub fn write_u32<W : Write>(writer : &mut W, value : u32) -> io::Result<()> {
+	writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32<W : Write>(writer : &mut W, value : f32) -> io::Result<()> {
+	writer.write_all(&value.to_bits().to_le_bytes())
+}
+
+pub fn write_vector<W : Write>(writer : &mut W, value : &alg::Vector3<f32>) -> io::Result<()> {
+	write_f32(writer, value.x)?;
+	write_f32(writer, value.y)?;
+	write_f32(writer, value.z)
+}
+
+pub fn read_u32<R : Read>(reader : &mut R) -> io::Result<u32> {
+	let mut bytes = [0; 4];
+	reader.read_exact(&mut bytes)?;
+	Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_f32<R : Read>(reader : &mut R) -> io::Result<f32> {
+	Ok(f32::from_bits(read_u32(reader)?))
+}
+
+pub fn read_vector<R : Read>(reader : &mut R) -> io::Result<alg::Vector3<f32>> {
+	let x = read_f32(reader)?;
+	let y = read_f32(reader)?;
+	let z = read_f32(reader)?;
+	Ok(alg::Vector3::new(x, y, z))
+}
+
+pub fn write_u64<W : Write>(writer : &mut W, value : u64) -> io::Result<()> {
+	writer.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u64<R : Read>(reader : &mut R) -> io::Result<u64> {
+	let mut bytes = [0; 8];
+	reader.read_exact(&mut bytes)?;
+	Ok(u64::from_le_bytes(bytes))
+}
+
+//Length first, then the bytes
+pub fn write_string<W : Write>(writer : &mut W, value : &str) -> io::Result<()> {
+	write_u32(writer, value.len() as u32)?;
+	writer.write_all(value.as_bytes())
+}
+
+pub fn read_string<R : Read>(reader : &mut R) -> io::Result<String> {
+	let length = read_u32(reader)? as usize;
+	if length > MAX_STRING_BYTES {
+		return Err(invalid_data(format!("a string {} bytes long is too long", length)));
+	}
+	let mut bytes = vec![0; length];
+	reader.read_exact(&mut bytes)?;
+	Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+pub fn invalid_data(message : String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+//Written next to the checkpoint and moved over it once it's complete, so stopping mid write leaves the last one intact
+pub fn write_checkpoint(path : &Path, settings : &RenderSettings, sampler_samples : u32, image : &AovImage, film : &Film) -> io::Result<()> {
+	let temporary = path.with_extension("checkpoint.tmp");
+	{
+		let mut writer = BufWriter::new(File::create(&temporary)?);
+		writer.write_all(&CHECKPOINT_MAGIC)?;
+		write_u32(&mut writer, CHECKPOINT_VERSION)?;
+		write_string(&mut writer, &settings_signature(settings))?;
+		write_u32(&mut writer, sampler_samples)?;
+
+		for pixel in image.pixels.iter() {
+			pixel.write_to(&mut writer)?;
+		}
+		film.write_to(&mut writer)?;
+		writer.flush()?;
+	}
+	fs::rename(&temporary, path)
+}
+
+pub fn read_checkpoint(path : &Path, settings : &RenderSettings) -> io::Result<Checkpoint> {
+	let mut reader = BufReader::new(File::open(path)?);
+	let mut magic = [0; 4];
+	reader.read_exact(&mut magic)?;
+	if magic != CHECKPOINT_MAGIC {
+		return Err(invalid_data(format!("{} isn't a checkpoint", path.display())));
+	}
+	let version = read_u32(&mut reader)?;
+	if version != CHECKPOINT_VERSION {
+		return Err(invalid_data(format!("checkpoint version {} isn't supported", version)));
+	}
+
+	let signature = read_string(&mut reader)?;
+	let expected = settings_signature(settings);
+	if signature != expected {
+		return Err(invalid_data(format!("checkpoint was rendered with \"{}\", not \"{}\"", signature, expected)));
+	}
+	let sampler_samples = read_u32(&mut reader)?;
+
+	let mut image = AovImage::new(settings.width, settings.height);
+	for pixel in image.pixels.iter_mut() {
+		*pixel = AovPixel::read_from(&mut reader)?;
+	}
+	let film = Film::read_from(&mut reader, settings.width, settings.height)?;
+
+	Ok(Checkpoint {
+		sampler_samples,
+		image,
+		film,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+
+	use super::*;
+	use filter::*;
+	use integrator::*;
+	use sampler::*;
+
+	fn settings() -> RenderSettings {
+		RenderSettings {
+			width : 6,
+			height : 4,
+			integrator : Integrator::Path,
+			samples : 4,
+			adaptive_sampling : false,
+			adaptive_min_samples : 4,
+			adaptive_max_samples : 4,
+			adaptive_batch_samples : 4,
+			adaptive_noise_threshold : 0.0,
+			sampler : SamplerKind::Sobol,
+			seed : 1,
+			filter : Filter::new(FilterKind::Gaussian, 1.5),
+			tile_size : 8,
+			tile_order : TileOrder::Spiral,
+			flush_interval : 0.0,
+			progressive : false,
+			time_limit : 0.0,
+			progressive_noise_threshold : 0.0,
+			checkpoint_interval : 0.0,
+			shutter_open : 0.0,
+			shutter_close : 0.0,
+			debug_heatmap : false,
+			heatmap_max_tests : 200,
+		}
+	}
+
+	//An image and film with a few samples in them, some hitting something and some not
+	fn accumulated(settings : &RenderSettings) -> (AovImage, Film) {
+		let mut image = AovImage::new(settings.width, settings.height);
+		let mut tile = FilmTile::new(settings.width, settings.height, settings.filter, 0, 0, settings.width, settings.height);
+		for (i, pixel) in image.pixels.iter_mut().enumerate().filter(|&(i, _)| i % 3 != 0) {
+			let value = i as f32 * 0.1;
+			let mut light = LightSplit::new();
+			light.direct = alg::Vector3::new(value, 0.5, 0.25);
+			let sample = AovSample {
+				beauty : alg::Vector3::new(value, 0.5, 0.25),
+				light,
+				surface : if i % 2 == 0 { None } else {
+					Some(SurfaceSample {
+						albedo : alg::Vector3::new(0.5, value, 0.5),
+						normal : alg::Vector3::new(0.0, 1.0, 0.0),
+						depth : value + 1.0,
+						position : alg::Vector3::new(value, 0.0, -1.0),
+						object_id : i as u32,
+						material_id : 2 * i as u32,
+					})
+				},
+			};
+			pixel.add(&sample);
+			pixel.add(&sample);
+			tile.add_sample((i % settings.width) as f32 + 0.3, (i / settings.width) as f32 + 0.6, &sample);
+		}
+		let mut film = Film::new(settings.width, settings.height);
+		film.merge(&tile);
+		(image, film)
+	}
+
+	fn bytes(image : &AovImage, film : &Film) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		for pixel in image.pixels.iter() {
+			pixel.write_to(&mut bytes).unwrap();
+		}
+		film.write_to(&mut bytes).unwrap();
+		bytes
+	}
+
+	#[test]
+	fn checkpoint_reads_back_what_was_written() {
+		let settings = settings();
+		let (image, film) = accumulated(&settings);
+		let path = env::temp_dir().join("checkpoint_round_trip_test.checkpoint");
+		write_checkpoint(&path, &settings, 16, &image, &film).unwrap();
+		let checkpoint = read_checkpoint(&path, &settings).unwrap();
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!(checkpoint.sampler_samples, 16);
+		assert!(bytes(&checkpoint.image, &checkpoint.film) == bytes(&image, &film), "The checkpoint came back different");
+	}
+
+	#[test]
+	fn checkpoint_for_other_settings_is_rejected() {
+		let settings = settings();
+		let (image, film) = accumulated(&settings);
+		let path = env::temp_dir().join("checkpoint_mismatch_test.checkpoint");
+		write_checkpoint(&path, &settings, 4, &image, &film).unwrap();
+
+		let mut other = settings;
+		other.seed = 2;
+		let result = read_checkpoint(&path, &other);
+		fs::remove_file(&path).unwrap();
+		match result {
+			Err(why) => assert_eq!(why.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("A checkpoint with a different seed was read"),
+		}
+	}
+
+	#[test]
+	fn overlong_string_is_rejected_before_reading_it() {
+		let mut bytes = Vec::new();
+		write_u32(&mut bytes, u32::MAX).unwrap();
+		let error = read_string(&mut &bytes[..]).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,117 @@
+extern crate nalgebra as alg;
+
+use rayon::prelude::*;
+
+use aov::*;
+use util::*;
+
+//Edge avoiding a-trous wavelet filter (Dammertz et al. 2010) guided by the albedo, normal and position passes.
+//Each pass blurs with a 5x5 B3 spline kernel whose taps are spread twice as far apart as the last pass, and taps that
+//land on a different surface or a very different colour are weighted down so edges stay sharp
+pub static DENOISE_PASSES : usize = 5;
+//How quickly the weights fall off, larger values blur more across that kind of difference
+pub static DENOISE_COLOUR_SIGMA : f32 = 0.6;
+pub static DENOISE_POSITION_SIGMA : f32 = 0.2;
+pub static DENOISE_NORMAL_POWER : f32 = 64.0;
+
+static KERNEL : [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+//What the filter needs to know about a pixel to decide whether its neighbours are the same surface
+struct Guide {
+	hit : bool,
+	normal : alg::Vector3<f32>,
+	position : alg::Vector3<f32>,
+}
+
+//Compare colours after a simple tone map so fireflies don't make every neighbour look like an edge
+fn tone_map(colour : alg::Vector3<f32>) -> alg::Vector3<f32> {
+	alg::Vector3::new(colour.x / (1.0 + colour.x), colour.y / (1.0 + colour.y), colour.z / (1.0 + colour.z))
+}
+
+//Texture detail lives in the albedo, so only the lighting under it is filtered and the albedo is multiplied back after
+fn demodulate(colour : alg::Vector3<f32>, albedo : alg::Vector3<f32>) -> alg::Vector3<f32> {
+	let mut lighting = colour;
+	for i in 0..3 {
+		if albedo[i] > 0.001 {
+			lighting[i] = colour[i] / albedo[i];
+		}
+	}
+	lighting
+}
+
+fn remodulate(lighting : alg::Vector3<f32>, albedo : alg::Vector3<f32>) -> alg::Vector3<f32> {
+	let mut colour = lighting;
+	for i in 0..3 {
+		if albedo[i] > 0.001 {
+			colour[i] = lighting[i] * albedo[i];
+		}
+	}
+	colour
+}
+
+fn filter_pass(lighting : &[alg::Vector3<f32>], guides : &[Guide], width : usize, height : usize, step : usize, colour_sigma : f32) -> Vec<alg::Vector3<f32>> {
+	(0..width * height).into_par_iter().map(|index| {
+		let x = (index % width) as isize;
+		let y = (index / width) as isize;
+		let centre = &guides[index];
+		let centre_colour = tone_map(lighting[index]);
+
+		let mut sum = alg::Vector3::new(0.0, 0.0, 0.0);
+		let mut total_weight = 0.0;
+		for (ky, ky_weight) in KERNEL.iter().enumerate() {
+			for (kx, kx_weight) in KERNEL.iter().enumerate() {
+				let sx = x + (kx as isize - 2) * step as isize;
+				let sy = y + (ky as isize - 2) * step as isize;
+				if sx < 0 || sy < 0 || sx >= width as isize || sy >= height as isize {
+					continue;
+				}
+
+				let sample_index = sy as usize * width + sx as usize;
+				let guide = &guides[sample_index];
+				if guide.hit != centre.hit {
+					continue;
+				}
+
+				let mut weight = kx_weight * ky_weight;
+				let colour_difference = squared_length(tone_map(lighting[sample_index]) - centre_colour);
+				weight *= (-colour_difference / (colour_sigma * colour_sigma)).exp();
+
+				if centre.hit {
+					let position_difference = squared_length(guide.position - centre.position);
+					weight *= (-position_difference / (DENOISE_POSITION_SIGMA * DENOISE_POSITION_SIGMA)).exp();
+					weight *= guide.normal.dot(&centre.normal).max(0.0).powf(DENOISE_NORMAL_POWER);
+				}
+
+				sum += lighting[sample_index] * weight;
+				total_weight += weight;
+			}
+		}
+
+		if total_weight <= 0.0 {
+			return lighting[index];
+		}
+		sum / total_weight
+	}).collect()
+}
+
+//Filtered copy of the beauty pass, still linear
+pub fn denoise(image : &AovImage) -> Vec<alg::Vector3<f32>> {
+	let guides : Vec<Guide> = image.pixels.iter().map(|pixel| {
+		Guide {
+			hit : pixel.depth().is_finite(),
+			normal : pixel.normal(),
+			position : pixel.position(),
+		}
+	}).collect();
+
+	let mut lighting : Vec<alg::Vector3<f32>> = image.pixels.iter().map(|pixel| demodulate(pixel.beauty(), pixel.albedo())).collect();
+
+	//The colour weight gets stricter every pass, the wide passes should only smooth what's left of the noise
+	let mut colour_sigma = DENOISE_COLOUR_SIGMA;
+	for pass in 0..DENOISE_PASSES {
+		lighting = filter_pass(&lighting, &guides, image.width, image.height, 1 << pass, colour_sigma);
+		colour_sigma *= 0.5;
+	}
+
+	lighting.iter().zip(image.pixels.iter()).map(|(lighting, pixel)| remodulate(*lighting, pixel.albedo())).collect()
+}
@@ -0,0 +1,380 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use scene::*;
+use camera::*;
+use photon::*;
+use aov::*;
+use film::*;
+use checkpoint::*;
+use render::*;
+use scene_file::*;
+use animation::*;
+
+//A coordinator splits each frame in to tiles and hands them to workers over TCP as they ask for more, merging what comes
+//back in to one image. Workers are the same program with the same settings, they build the scene themselves for the frame
+//they're told and only need the camera and a tile. Each worker thread has its own connection, so a worker renders as many tiles at once as it has threads.
+//A tile whose worker drops its connection or takes longer than WORKER_TIMEOUT goes back in the queue for someone else
+static DISTRIBUTED_MAGIC : [u8; 4] = *b"RTDW";
+static DISTRIBUTED_VERSION : u32 = 2;
+static WORKER_TIMEOUT : u64 = 300;
+//Seconds a worker keeps trying to reach the coordinator before it gives up
+static WORKER_CONNECT_SECONDS : u64 = 30;
+
+//Coordinator to worker messages
+static MESSAGE_TILE : u32 = 1;
+//Reconnect for the next frame
+static MESSAGE_FRAME_DONE : u32 = 2;
+static MESSAGE_SHUTDOWN : u32 = 3;
+
+//Everything the coordinator and worker need to agree on for their tiles to fit together
+fn distributed_signature(settings : &RenderSettings) -> String {
+	format!("{} samples {} adaptive {} {} {} {} {}", settings_signature(settings), settings.samples, settings.adaptive_sampling,
+		settings.adaptive_min_samples, settings.adaptive_max_samples, settings.adaptive_batch_samples, settings.adaptive_noise_threshold)
+}
+
+fn write_camera<W : Write>(writer : &mut W, camera : &Camera) -> io::Result<()> {
+	write_vector(writer, &camera.origin)?;
+	write_vector(writer, &camera.lower_left_corner)?;
+	write_vector(writer, &camera.horizontal)?;
+	write_vector(writer, &camera.vertical)
+}
+
+fn read_camera<R : Read>(reader : &mut R) -> io::Result<Camera> {
+	let origin = read_vector(reader)?;
+	let lower_left_corner = read_vector(reader)?;
+	let horizontal = read_vector(reader)?;
+	let vertical = read_vector(reader)?;
+	Ok(Camera::new(origin, lower_left_corner, horizontal, vertical))
+}
+
+fn write_tile<W : Write>(writer : &mut W, tile : &Tile) -> io::Result<()> {
+	write_u32(writer, tile.x0 as u32)?;
+	write_u32(writer, tile.y0 as u32)?;
+	write_u32(writer, tile.x1 as u32)?;
+	write_u32(writer, tile.y1 as u32)
+}
+
+fn read_tile<R : Read>(reader : &mut R) -> io::Result<Tile> {
+	Ok(Tile {
+		x0 : read_u32(reader)? as usize,
+		y0 : read_u32(reader)? as usize,
+		x1 : read_u32(reader)? as usize,
+		y1 : read_u32(reader)? as usize,
+	})
+}
+
+fn write_rendered_tile<W : Write>(writer : &mut W, rendered : &RenderedTile) -> io::Result<()> {
+	write_tile(writer, &rendered.tile)?;
+	write_u64(writer, rendered.samples)?;
+	for pixel in rendered.pixels.iter() {
+		pixel.write_to(writer)?;
+	}
+	rendered.film_tile.write_to(writer)
+}
+
+fn read_rendered_tile<R : Read>(reader : &mut R, settings : &RenderSettings) -> io::Result<RenderedTile> {
+	let tile = read_tile(reader)?;
+	if tile.x0 > tile.x1 || tile.y0 > tile.y1 || tile.x1 > settings.width || tile.y1 > settings.height {
+		return Err(invalid_data(format!("tile {:?} isn't in the image", tile)));
+	}
+	let samples = read_u64(reader)?;
+	let mut pixels = Vec::with_capacity((tile.x1 - tile.x0) * (tile.y1 - tile.y0));
+	for _pixel in 0..(tile.x1 - tile.x0) * (tile.y1 - tile.y0) {
+		pixels.push(AovPixel::read_from(reader)?);
+	}
+	let film_tile = FilmTile::read_from(reader, settings.filter)?;
+
+	Ok(RenderedTile {
+		tile,
+		pixels,
+		film_tile,
+		samples,
+	})
+}
+
+//The frame being coordinated, shared by every worker connection
+struct CoordinatedFrame<'a> {
+	camera : &'a Camera,
+	settings : &'a RenderSettings,
+	sampler_samples : u32,
+	frame : u32,
+	last_frame : bool,
+	//Tiles no worker has taken yet, along with any a lost worker had
+	queue : Mutex<VecDeque<Tile>>,
+	done : AtomicBool,
+}
+
+//Talks to one worker connection until the frame is done or the worker fails, when the tile it had goes back in the queue
+fn serve_worker(stream : TcpStream, address : SocketAddr, coordinated : &CoordinatedFrame, sender : mpsc::Sender<RenderedTile>) -> io::Result<()> {
+	let settings = coordinated.settings;
+	stream.set_nonblocking(false)?;
+	stream.set_nodelay(true)?;
+	stream.set_read_timeout(Some(Duration::from_secs(WORKER_TIMEOUT)))?;
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut writer = BufWriter::new(stream);
+
+	let mut magic = [0; 4];
+	reader.read_exact(&mut magic)?;
+	let version = read_u32(&mut reader)?;
+	let signature = read_string(&mut reader)?;
+	let expected = distributed_signature(settings);
+	if magic != DISTRIBUTED_MAGIC || version != DISTRIBUTED_VERSION || signature != expected {
+		write_string(&mut writer, &format!("it renders \"{}\" with version {}, not \"{}\" with version {}", expected, DISTRIBUTED_VERSION, signature, version))?;
+		writer.flush()?;
+		return Err(invalid_data(format!("settings \"{}\" don't match", signature)));
+	}
+	write_string(&mut writer, "")?;
+	write_u32(&mut writer, coordinated.frame)?;
+	writer.flush()?;
+	//Workers without this frame's scene hang up to build it and come back
+	if read_u32(&mut reader)? == 0 {
+		return Ok(());
+	}
+
+	loop {
+		let tile = loop {
+			if coordinated.done.load(Ordering::Relaxed) {
+				write_u32(&mut writer, if coordinated.last_frame { MESSAGE_SHUTDOWN } else { MESSAGE_FRAME_DONE })?;
+				return writer.flush();
+			}
+			if let Some(tile) = coordinated.queue.lock().unwrap().pop_front() {
+				break tile;
+			}
+			thread::sleep(Duration::from_millis(100));
+		};
+
+		let result = (|| {
+			write_u32(&mut writer, MESSAGE_TILE)?;
+			write_camera(&mut writer, coordinated.camera)?;
+			write_u32(&mut writer, coordinated.sampler_samples)?;
+			write_tile(&mut writer, &tile)?;
+			writer.flush()?;
+			read_rendered_tile(&mut reader, settings)
+		})();
+
+		match result {
+			Ok(rendered) => {
+				if sender.send(rendered).is_err() {
+					return Ok(());
+				}
+			},
+			Err(why) => {
+				println!("Worker {} failed on tile {:?}, giving it to another worker", address, tile);
+				coordinated.queue.lock().unwrap().push_front(tile);
+				return Err(why);
+			},
+		}
+	}
+}
+
+//Renders a frame on whichever workers connect to the listener, writing the image out along the way like a local render.
+//Workers are told to reconnect for the next frame, or to stop after the last one
+pub fn coordinate(listener : &TcpListener, camera : &Camera, settings : &RenderSettings, name : &str, frame : u32, last_frame : bool) -> AovImage {
+	let tiles = tiles(settings.width, settings.height, settings.tile_size, settings.tile_order);
+	let mut state = RenderState::new(settings, name, None, None);
+	let coordinated = CoordinatedFrame {
+		camera,
+		settings,
+		sampler_samples : state.sampler_samples,
+		frame,
+		last_frame,
+		queue : Mutex::new(tiles.iter().cloned().collect()),
+		done : AtomicBool::new(false),
+	};
+	let (sender, receiver) = mpsc::channel::<RenderedTile>();
+
+	if let Ok(address) = listener.local_addr() {
+		println!("Waiting for workers on {}", address);
+	}
+	listener.set_nonblocking(true).expect("Couldn't stop the listener blocking");
+
+	thread::scope(|scope| {
+		let coordinated = &coordinated;
+		scope.spawn(move || {
+			while !coordinated.done.load(Ordering::Relaxed) {
+				match listener.accept() {
+					Ok((stream, address)) => {
+						println!("Worker {} connected", address);
+						let sender = sender.clone();
+						scope.spawn(move || {
+							if let Err(why) = serve_worker(stream, address, coordinated, sender) {
+								println!("Lost worker {}: {}", address, why);
+							}
+						});
+					},
+					Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+					Err(why) => println!("Couldn't accept a worker: {}", why),
+				}
+			}
+		});
+
+		let mut finished = 0;
+		let mut last_percent = -1;
+		while finished < tiles.len() {
+			if let Ok(rendered) = receiver.recv_timeout(Duration::from_millis(100)) {
+				let index = tiles.iter().position(|tile| *tile == rendered.tile).expect("A worker sent back a tile that isn't in the frame");
+				state.add_tile(index, rendered);
+				finished += 1;
+
+				let percent = (finished * 100 / tiles.len()) as i32;
+				if percent != last_percent {
+					println!("{}%", percent);
+					last_percent = percent;
+				}
+			}
+			if finished < tiles.len() {
+				state.save_if_due();
+			}
+		}
+		coordinated.done.store(true, Ordering::Relaxed);
+	});
+
+	state.end_pass();
+	state.finish()
+}
+
+//How a worker connection ended
+enum Finished {
+	Shutdown,
+	//The coordinator wants a frame whose scene hasn't been built
+	NeedsFrame(u32),
+}
+
+//The scene a worker has built and the frame it was built for
+struct WorkerScene {
+	frame : u32,
+	scene : Scene<'static>,
+	photons : Option<PhotonMapper>,
+}
+
+//Keeps one worker connection going, reconnecting after every frame. Returns once the coordinator says it's finished,
+//refuses the settings, can't be reached for WORKER_CONNECT_SECONDS or moves on to a frame the scene isn't built for
+fn work_connection(address : &str, built : Option<&WorkerScene>, moves_objects : bool, settings : &RenderSettings) -> Finished {
+	loop {
+		let connect_start = Instant::now();
+		let stream = loop {
+			match TcpStream::connect(address) {
+				Ok(stream) => break stream,
+				Err(why) => {
+					if connect_start.elapsed() >= Duration::from_secs(WORKER_CONNECT_SECONDS) {
+						println!("Couldn't reach the coordinator at {}: {}", address, why);
+						return Finished::Shutdown;
+					}
+					thread::sleep(Duration::from_millis(500));
+				},
+			}
+		};
+
+		match serve_coordinator(stream, built, moves_objects, settings) {
+			Ok(None) => {},
+			Ok(Some(finished)) => return finished,
+			Err(why) => println!("Lost the coordinator: {}", why),
+		}
+	}
+}
+
+//Renders tiles for the coordinator until it's done with this connection, None if it wants the worker back for another frame
+fn serve_coordinator(stream : TcpStream, built : Option<&WorkerScene>, moves_objects : bool, settings : &RenderSettings) -> io::Result<Option<Finished>> {
+	stream.set_nodelay(true)?;
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut writer = BufWriter::new(stream);
+
+	writer.write_all(&DISTRIBUTED_MAGIC)?;
+	write_u32(&mut writer, DISTRIBUTED_VERSION)?;
+	write_string(&mut writer, &distributed_signature(settings))?;
+	writer.flush()?;
+	let refusal = read_string(&mut reader)?;
+	if !refusal.is_empty() {
+		println!("The coordinator refused this worker, {}", refusal);
+		return Ok(Some(Finished::Shutdown));
+	}
+
+	//Any frame will do when only the camera moves
+	let frame = read_u32(&mut reader)?;
+	let built = match built {
+		Some(built) if built.frame == frame || !moves_objects => built,
+		_ => {
+			write_u32(&mut writer, 0)?;
+			writer.flush()?;
+			return Ok(Some(Finished::NeedsFrame(frame)));
+		},
+	};
+	write_u32(&mut writer, 1)?;
+	writer.flush()?;
+
+	loop {
+		let message = read_u32(&mut reader)?;
+		if message == MESSAGE_FRAME_DONE {
+			return Ok(None);
+		}
+		if message == MESSAGE_SHUTDOWN {
+			return Ok(Some(Finished::Shutdown));
+		}
+		if message != MESSAGE_TILE {
+			return Err(invalid_data(format!("unknown message {}", message)));
+		}
+
+		let camera = read_camera(&mut reader)?;
+		let sampler_samples = read_u32(&mut reader)?;
+		let tile = read_tile(&mut reader)?;
+		if tile.x0 > tile.x1 || tile.y0 > tile.y1 || tile.x1 > settings.width || tile.y1 > settings.height {
+			return Err(invalid_data(format!("tile {:?} isn't in the image", tile)));
+		}
+
+		let pass = TilePass {
+			start : None,
+			sampler_samples,
+			sample_limit : settings.max_samples(),
+			stop : &AtomicBool::new(false),
+		};
+		let rendered = render_tile(tile, &built.scene, &camera, settings, &built.photons, &pass);
+		write_rendered_tile(&mut writer, &rendered)?;
+		writer.flush()?;
+	}
+}
+
+//Renders tiles for the coordinator at address on as many connections as rayon has threads, until it's finished. The scene is
+//built from the description for whichever frame the coordinator is on, and rebuilt for each frame if the animation moves objects
+pub fn work(address : &str, description : &SceneDescription, animation : &Animation, settings : &RenderSettings, accelerate : fn(Scene<'static>) -> Scene<'static>) {
+	let threads = rayon::current_num_threads().max(1);
+	println!("Working for {} on {} threads", address, threads);
+	let mut built : Option<WorkerScene> = None;
+	loop {
+		let finished : Vec<Finished> = thread::scope(|scope| {
+			let built = built.as_ref();
+			let handles : Vec<_> = (0..threads).map(|_thread| {
+				scope.spawn(move || work_connection(address, built, animation.moves_objects(), settings))
+			}).collect();
+			handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+		});
+
+		let frame = match finished.iter().filter_map(|finished| match *finished { Finished::NeedsFrame(frame) => Some(frame), _ => None }).next() {
+			Some(frame) => frame,
+			None => return,
+		};
+		println!("Building the scene for frame {}", frame);
+		let scene = match animation.scene_at(description, frame as f32).build() {
+			Ok(scene) => accelerate(scene),
+			Err(why) => {
+				println!("Couldn't build the scene for frame {}: {}", frame, why);
+				return;
+			}
+		};
+		let mut photons : Option<PhotonMapper> = None;
+		if settings.integrator == Integrator::PhotonMapping {
+			photons = Some(PhotonMapper::new(&scene, settings.seed, settings.shutter_open, settings.shutter_close, settings.max_samples() as usize));
+		}
+		built = Some(WorkerScene {
+			frame,
+			scene,
+			photons,
+		});
+	}
+}
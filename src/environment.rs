@@ -0,0 +1,246 @@
+extern crate nalgebra as alg;
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::hdr::HDRDecoder;
+
+use exr::*;
+use aov::*;
+
+//Light from infinitely far away in every direction a ray can escape to
+pub trait Environment {
+	fn radiance(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32>;
+
+	//Environments that aren't sampled are only ever found by BSDF samples, the rest are sampled for direct lighting too
+	fn is_importance_sampled(&self) -> bool {
+		false
+	}
+
+	fn sample(&self, _u : alg::Vector2<f32>) -> Option<EnvironmentSample> {
+		None
+	}
+
+	//Solid angle pdf of sample picking direction
+	fn pdf(&self, _direction : alg::Vector3<f32>) -> f32 {
+		0.0
+	}
+}
+
+pub struct EnvironmentSample {
+	pub direction : alg::Vector3<f32>,
+	pub radiance : alg::Vector3<f32>,
+	pub pdf : f32,
+}
+
+pub struct ConstantEnvironment {
+	pub colour : alg::Vector3<f32>,
+}
+
+//Blends from one colour to the other across the sky along an axis, white to red from left to right by default
+pub struct GradientEnvironment {
+	pub start : alg::Vector3<f32>,
+	pub end : alg::Vector3<f32>,
+	pub axis : alg::Vector3<f32>,
+}
+
+impl ConstantEnvironment {
+	pub fn new(colour : alg::Vector3<f32>) -> ConstantEnvironment {
+		ConstantEnvironment {
+			colour,
+		}
+	}
+}
+
+impl GradientEnvironment {
+	pub fn new(start : alg::Vector3<f32>, end : alg::Vector3<f32>, axis : alg::Vector3<f32>) -> GradientEnvironment {
+		GradientEnvironment {
+			start,
+			end,
+			axis : axis.normalize(),
+		}
+	}
+
+	//The sky there's always been
+	pub fn white_to_red() -> GradientEnvironment {
+		GradientEnvironment::new(alg::Vector3::new(1.0, 1.0, 1.0), alg::Vector3::new(1.0, 0.0, 0.0), alg::Vector3::new(1.0, 0.0, 0.0))
+	}
+}
+
+impl Environment for ConstantEnvironment {
+	fn radiance(&self, _direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.colour
+	}
+}
+
+impl Environment for GradientEnvironment {
+	fn radiance(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		let t = 0.5 * (direction.normalize().dot(&self.axis) + 1.0);
+		(1.0 - t) * self.start + t * self.end
+	}
+}
+
+//Piecewise constant over n equal steps from 0 to 1, for picking steps in proportion to their value
+struct Distribution1D {
+	function : Vec<f32>,
+	cdf : Vec<f32>,
+	integral : f32,
+}
+
+impl Distribution1D {
+	fn new(function : Vec<f32>) -> Distribution1D {
+		let n = function.len();
+		let mut cdf = vec![0.0; n + 1];
+		for i in 0..n {
+			cdf[i + 1] = cdf[i] + function[i] / n as f32;
+		}
+
+		let integral = cdf[n];
+		for (i, value) in cdf.iter_mut().enumerate().skip(1) {
+			//All zero is sampled evenly instead
+			*value = if integral > 0.0 { *value / integral } else { i as f32 / n as f32 };
+		}
+
+		Distribution1D {
+			function,
+			cdf,
+			integral,
+		}
+	}
+
+	//The step u lands in, as (position from 0 to 1, pdf, index)
+	fn sample(&self, u : f32) -> (f32, f32, usize) {
+		let n = self.function.len();
+		let index = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+			Ok(i) => i,
+			Err(i) => i - 1,
+		}.min(n - 1);
+
+		let width = self.cdf[index + 1] - self.cdf[index];
+		let offset = if width > 0.0 { (u - self.cdf[index]) / width } else { 0.0 };
+		let pdf = if self.integral > 0.0 { self.function[index] / self.integral } else { 1.0 };
+		((index as f32 + offset) / n as f32, pdf, index)
+	}
+}
+
+//An HDR photo of everything around a point, longitude across and latitude down, turned about the vertical axis by rotation.
+//Directions are sampled in proportion to how bright the pixels are, so small bright suns don't need many samples to find
+pub struct EquirectEnvironment {
+	pub width : usize,
+	pub height : usize,
+	//Top row first
+	pub pixels : Vec<alg::Vector3<f32>>,
+	//Radians
+	pub rotation : f32,
+	pub intensity : f32,
+	//One distribution across each row, weighted by how much of the sphere the row covers
+	rows : Vec<Distribution1D>,
+	//Picks the row
+	marginal : Distribution1D,
+}
+
+impl EquirectEnvironment {
+	//rotation is in degrees
+	pub fn new(width : usize, height : usize, pixels : Vec<alg::Vector3<f32>>, rotation : f32, intensity : f32) -> EquirectEnvironment {
+		let mut rows : Vec<Distribution1D> = Vec::with_capacity(height);
+		for y in 0..height {
+			let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+			let row = pixels[y * width..(y + 1) * width].iter().map(|pixel| luminance(*pixel) * sin_theta).collect();
+			rows.push(Distribution1D::new(row));
+		}
+		let marginal = Distribution1D::new(rows.iter().map(|row| row.integral).collect());
+
+		EquirectEnvironment {
+			width,
+			height,
+			pixels,
+			rotation : rotation.to_radians(),
+			intensity,
+			rows,
+			marginal,
+		}
+	}
+
+	//Radiance .hdr or OpenEXR .exr
+	pub fn load(path : &Path, rotation : f32, intensity : f32) -> io::Result<EquirectEnvironment> {
+		let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+		let (width, height, pixels) = match extension.as_str() {
+			"hdr" => {
+				let decoder = HDRDecoder::new(BufReader::new(File::open(path)?)).map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why.to_string()))?;
+				let metadata = decoder.metadata();
+				let pixels = decoder.read_image_hdr().map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why.to_string()))?;
+				(metadata.width as usize, metadata.height as usize, pixels.iter().map(|pixel| alg::Vector3::new(pixel[0], pixel[1], pixel[2])).collect())
+			},
+			"exr" => read_exr_rgb(path)?,
+			_ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't an .hdr or .exr", path.display()))),
+		};
+
+		if width == 0 || height == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is empty", path.display())));
+		}
+		Ok(EquirectEnvironment::new(width, height, pixels, rotation, intensity))
+	}
+
+	//Where the direction is in the image, both from 0 to 1
+	fn direction_to_uv(&self, direction : alg::Vector3<f32>) -> (f32, f32) {
+		let direction = direction.normalize();
+		let phi = direction.x.atan2(-direction.z) - self.rotation;
+		let u = phi / (2.0 * PI) + 0.5;
+		let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+		(u - u.floor(), v)
+	}
+
+	fn pixel_index(&self, u : f32, v : f32) -> (usize, usize) {
+		(((u * self.width as f32) as usize).min(self.width - 1), ((v * self.height as f32) as usize).min(self.height - 1))
+	}
+}
+
+impl Environment for EquirectEnvironment {
+	fn radiance(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		let (u, v) = self.direction_to_uv(direction);
+		let (x, y) = self.pixel_index(u, v);
+		self.pixels[y * self.width + x] * self.intensity
+	}
+
+	fn is_importance_sampled(&self) -> bool {
+		self.marginal.integral > 0.0
+	}
+
+	fn sample(&self, u : alg::Vector2<f32>) -> Option<EnvironmentSample> {
+		let (v, row_pdf, y) = self.marginal.sample(u.y);
+		let (u, column_pdf, x) = self.rows[y].sample(u.x);
+
+		let theta = v * PI;
+		let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+		let sin_theta = theta.sin();
+		if sin_theta <= 0.0 {
+			return None;
+		}
+
+		//The image covers 2 pi by pi radians, and each bit of it is squashed by sin theta on the sphere
+		let pdf = row_pdf * column_pdf / (2.0 * PI * PI * sin_theta);
+		if pdf <= 0.0 {
+			return None;
+		}
+
+		Some(EnvironmentSample {
+			direction : alg::Vector3::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos()),
+			radiance : self.pixels[y * self.width + x] * self.intensity,
+			pdf,
+		})
+	}
+
+	fn pdf(&self, direction : alg::Vector3<f32>) -> f32 {
+		let (u, v) = self.direction_to_uv(direction);
+		let sin_theta = (v * PI).sin();
+		if sin_theta <= 0.0 || self.marginal.integral <= 0.0 {
+			return 0.0;
+		}
+
+		let (x, y) = self.pixel_index(u, v);
+		self.rows[y].function[x] / self.marginal.integral / (2.0 * PI * PI * sin_theta)
+	}
+}
@@ -0,0 +1,390 @@
+extern crate nalgebra as alg;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use inflate::inflate_bytes_zlib;
+
+//Just enough of OpenEXR to write a single part, uncompressed, 32 bit float scanline image with any number of channels.
+//Layers are channels named "layer.R" and so on, which is how compositors expect multilayer files. Reading only goes as far
+//as the RGB of single part scanline images that are uncompressed or zip compressed, which covers most environment maps
+//https://www.openexr.com/documentation/openexrfilelayout.pdf
+
+static EXR_MAGIC : [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+static EXR_VERSION : u32 = 2;
+static PIXEL_TYPE_UINT : i32 = 0;
+static PIXEL_TYPE_HALF : i32 = 1;
+static PIXEL_TYPE_FLOAT : i32 = 2;
+static COMPRESSION_NONE : u8 = 0;
+static COMPRESSION_ZIPS : u8 = 2;
+static COMPRESSION_ZIP : u8 = 3;
+
+pub struct ExrChannel<'a> {
+	pub name : String,
+	//width * height values, top row first
+	pub values : &'a [f32],
+}
+
+fn write_attribute<W : Write>(writer : &mut W, name : &str, kind : &str, data : &[u8]) -> io::Result<()> {
+	writer.write_all(name.as_bytes())?;
+	writer.write_all(&[0])?;
+	writer.write_all(kind.as_bytes())?;
+	writer.write_all(&[0])?;
+	writer.write_all(&(data.len() as i32).to_le_bytes())?;
+	writer.write_all(data)
+}
+
+fn box2i(width : usize, height : usize) -> Vec<u8> {
+	let mut data = Vec::with_capacity(16);
+	for value in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+		data.extend_from_slice(&value.to_le_bytes());
+	}
+	data
+}
+
+pub fn write_exr(path : &Path, width : usize, height : usize, channels : &mut Vec<ExrChannel>) -> io::Result<()> {
+	//Readers expect the channels sorted by name, both in the header and in every scanline
+	channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+	let mut header : Vec<u8> = Vec::new();
+	header.extend_from_slice(&EXR_MAGIC);
+	let mut version = EXR_VERSION;
+	if channels.iter().any(|channel| channel.name.len() > 31) {
+		//long names flag
+		version |= 0x400;
+	}
+	header.extend_from_slice(&version.to_le_bytes());
+
+	let mut channel_list : Vec<u8> = Vec::new();
+	for channel in channels.iter() {
+		channel_list.extend_from_slice(channel.name.as_bytes());
+		channel_list.push(0);
+		channel_list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+		//linear flag and three reserved bytes
+		channel_list.extend_from_slice(&[0, 0, 0, 0]);
+		channel_list.extend_from_slice(&1i32.to_le_bytes());
+		channel_list.extend_from_slice(&1i32.to_le_bytes());
+	}
+	channel_list.push(0);
+
+	write_attribute(&mut header, "channels", "chlist", &channel_list)?;
+	write_attribute(&mut header, "compression", "compression", &[0])?;
+	write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height))?;
+	write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height))?;
+	write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+	write_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes())?;
+	write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+	write_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes())?;
+	header.push(0);
+
+	//Every scanline is its y, its size, then each channel's row in turn
+	let line_size = width * channels.len() * 4;
+	let chunk_size = 8 + line_size;
+	let table_size = height * 8;
+
+	let mut writer = BufWriter::new(File::create(path)?);
+	writer.write_all(&header)?;
+	for y in 0..height {
+		let offset = (header.len() + table_size + y * chunk_size) as u64;
+		writer.write_all(&offset.to_le_bytes())?;
+	}
+
+	for y in 0..height {
+		writer.write_all(&(y as i32).to_le_bytes())?;
+		writer.write_all(&(line_size as i32).to_le_bytes())?;
+		for channel in channels.iter() {
+			for value in channel.values[y * width..(y + 1) * width].iter() {
+				writer.write_all(&value.to_le_bytes())?;
+			}
+		}
+	}
+
+	writer.flush()
+}
+
+fn invalid(message : String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+//Reads through a file already in memory, failing instead of running off the end
+struct ExrReader<'a> {
+	bytes : &'a [u8],
+	position : usize,
+}
+
+impl<'a> ExrReader<'a> {
+	fn take(&mut self, count : usize) -> io::Result<&'a [u8]> {
+		let end = match self.position.checked_add(count) {
+			Some(end) if end <= self.bytes.len() => end,
+			_ => return Err(invalid("the file ends too early".to_string())),
+		};
+		let taken = &self.bytes[self.position..end];
+		self.position = end;
+		Ok(taken)
+	}
+
+	fn i32(&mut self) -> io::Result<i32> {
+		let bytes = self.take(4)?;
+		Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+
+	fn u64(&mut self) -> io::Result<u64> {
+		let bytes = self.take(8)?;
+		let mut value = [0; 8];
+		value.copy_from_slice(bytes);
+		Ok(u64::from_le_bytes(value))
+	}
+
+	fn string(&mut self) -> io::Result<String> {
+		let rest = self.bytes.get(self.position..).ok_or_else(|| invalid("the file ends too early".to_string()))?;
+		let length = rest.iter().position(|byte| *byte == 0).ok_or_else(|| invalid("a name isn't terminated".to_string()))?;
+		let string = String::from_utf8_lossy(self.take(length)?).into_owned();
+		self.take(1)?;
+		Ok(string)
+	}
+}
+
+fn half_to_f32(half : u16) -> f32 {
+	let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+	let exponent = ((half >> 10) & 0x1f) as i32;
+	let mantissa = (half & 0x3ff) as f32;
+	match exponent {
+		0 => sign * mantissa * 2.0f32.powi(-24),
+		31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+		_ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent - 15),
+	}
+}
+
+//Zip compressed data has every other byte split in to two halves and is stored as differences, to compress better
+fn unzip_chunk(compressed : &[u8], expected : usize) -> io::Result<Vec<u8>> {
+	if compressed.len() == expected {
+		return Ok(compressed.to_vec());
+	}
+	let mut data = inflate_bytes_zlib(compressed).map_err(invalid)?;
+	if data.len() != expected {
+		return Err(invalid(format!("a chunk unzipped to {} bytes instead of {}", data.len(), expected)));
+	}
+
+	for i in 1..data.len() {
+		data[i] = data[i - 1].wrapping_add(data[i]).wrapping_sub(128);
+	}
+	let half = data.len().div_ceil(2);
+	let mut interleaved = Vec::with_capacity(data.len());
+	for i in 0..half {
+		interleaved.push(data[i]);
+		if half + i < data.len() {
+			interleaved.push(data[half + i]);
+		}
+	}
+	Ok(interleaved)
+}
+
+//The R, G and B channels of an image, top row first
+pub fn read_exr_rgb(path : &Path) -> io::Result<(usize, usize, Vec<alg::Vector3<f32>>)> {
+	let bytes = fs::read(path)?;
+	let mut reader = ExrReader { bytes : &bytes, position : 0 };
+	if reader.take(4)? != &EXR_MAGIC[..] {
+		return Err(invalid(format!("{} isn't an EXR", path.display())));
+	}
+	let version = reader.i32()?;
+	//Tiled, deep or multipart
+	if version & 0x1a00 != 0 {
+		return Err(invalid(format!("{} isn't a single part scanline image", path.display())));
+	}
+
+	let mut channels : Vec<(String, i32)> = Vec::new();
+	let mut compression = COMPRESSION_NONE;
+	let mut window : Option<(i32, i32, i32, i32)> = None;
+	loop {
+		let name = reader.string()?;
+		if name.is_empty() {
+			break;
+		}
+		let _kind = reader.string()?;
+		let size = reader.i32()? as usize;
+		let mut attribute = ExrReader { bytes : reader.take(size)?, position : 0 };
+		match name.as_str() {
+			"channels" => {
+				loop {
+					let channel = attribute.string()?;
+					if channel.is_empty() {
+						break;
+					}
+					let pixel_type = attribute.i32()?;
+					attribute.take(4)?;
+					if attribute.i32()? != 1 || attribute.i32()? != 1 {
+						return Err(invalid(format!("channel {} is subsampled", channel)));
+					}
+					channels.push((channel, pixel_type));
+				}
+			},
+			"compression" => compression = attribute.take(1)?[0],
+			"dataWindow" => window = Some((attribute.i32()?, attribute.i32()?, attribute.i32()?, attribute.i32()?)),
+			_ => {},
+		}
+	}
+
+	let (x_min, y_min, x_max, y_max) = window.ok_or_else(|| invalid("there's no data window".to_string()))?;
+	let size = |min : i32, max : i32| max.checked_sub(min).and_then(|size| size.checked_add(1)).map(|size| size.max(0) as usize);
+	let (width, height) = match (size(x_min, x_max), size(y_min, y_max)) {
+		(Some(width), Some(height)) => (width, height),
+		_ => return Err(invalid(format!("the data window ({}, {}) to ({}, {}) is too big", x_min, y_min, x_max, y_max))),
+	};
+	let pixel_count = width.checked_mul(height).ok_or_else(|| invalid(format!("{} by {} pixels is too many", width, height)))?;
+	let lines_per_chunk = match compression {
+		c if c == COMPRESSION_NONE || c == COMPRESSION_ZIPS => 1,
+		c if c == COMPRESSION_ZIP => 16,
+		c => return Err(invalid(format!("compression {} isn't supported, only none and zip", c))),
+	};
+
+	//Channels are stored sorted by name, and each one's row is all together in every scanline
+	channels.sort_by(|a, b| a.0.cmp(&b.0));
+	let mut line_size : usize = 0;
+	let mut offsets : Vec<usize> = Vec::with_capacity(channels.len());
+	for &(_, pixel_type) in channels.iter() {
+		offsets.push(line_size);
+		line_size = width.checked_mul(if pixel_type == PIXEL_TYPE_HALF { 2 } else { 4 }).and_then(|size| line_size.checked_add(size))
+			.ok_or_else(|| invalid(format!("scanlines {} pixels wide are too big", width)))?;
+	}
+	//Zip can't squeeze data smaller than about a thousandth, so a window needing more than that is broken and not worth
+	//making room for
+	let most_data = bytes.len().saturating_mul(if compression == COMPRESSION_NONE { 1 } else { 1032 });
+	if line_size.checked_mul(height).is_none_or(|size| size > most_data) {
+		return Err(invalid(format!("{} by {} pixels is more than the file could hold", width, height)));
+	}
+	let mut rgb : [Option<usize>; 3] = [None; 3];
+	let mut luminance : Option<usize> = None;
+	for (i, (name, _)) in channels.iter().enumerate() {
+		match name.as_str() {
+			"R" => rgb[0] = Some(i),
+			"G" => rgb[1] = Some(i),
+			"B" => rgb[2] = Some(i),
+			"Y" => luminance = Some(i),
+			_ => {},
+		}
+	}
+	//Greyscale images with just Y are grey in every component
+	if rgb.iter().all(|channel| channel.is_none()) {
+		match luminance {
+			Some(channel) => rgb = [Some(channel); 3],
+			None => return Err(invalid(format!("{} has no R, G, B or Y channels", path.display()))),
+		}
+	}
+
+	let chunks = height.div_ceil(lines_per_chunk);
+	//Checked before making room for it, a broken window could ask for far more than there is
+	if chunks > (bytes.len() - reader.position) / 8 {
+		return Err(invalid(format!("the offsets of {} chunks don't fit in the file", chunks)));
+	}
+	let mut table : Vec<u64> = Vec::with_capacity(chunks);
+	for _chunk in 0..chunks {
+		table.push(reader.u64()?);
+	}
+
+	let mut pixels = vec![alg::Vector3::new(0.0, 0.0, 0.0); pixel_count];
+	for offset in table {
+		if offset > bytes.len() as u64 {
+			return Err(invalid(format!("a chunk starts at {}, past the end of the file", offset)));
+		}
+		reader.position = offset as usize;
+		let y = (reader.i32()? - y_min).max(0) as usize;
+		let size = reader.i32()? as usize;
+		let lines = lines_per_chunk.min(height.saturating_sub(y));
+		let data = if compression == COMPRESSION_NONE { reader.take(size)?.to_vec() } else { unzip_chunk(reader.take(size)?, lines * line_size)? };
+		if data.len() < lines * line_size {
+			return Err(invalid(format!("the chunk at line {} is too small", y)));
+		}
+
+		for line in 0..lines {
+			for (component, channel) in rgb.iter().enumerate() {
+				//A missing channel stays black
+				let channel = match *channel {
+					Some(channel) => channel,
+					None => continue,
+				};
+				let pixel_type = channels[channel].1;
+				for x in 0..width {
+					let start = line * line_size + offsets[channel];
+					let value = if pixel_type == PIXEL_TYPE_HALF {
+						half_to_f32(u16::from_le_bytes([data[start + x * 2], data[start + x * 2 + 1]]))
+					}
+					else {
+						let b = &data[start + x * 4..start + x * 4 + 4];
+						let bits = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+						if pixel_type == PIXEL_TYPE_UINT { bits as f32 } else { f32::from_bits(bits) }
+					};
+					pixels[(y + line) * width + x][component] = value;
+				}
+			}
+		}
+	}
+
+	Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+
+	use super::*;
+	use aov::*;
+	use environment::*;
+	use integrator::*;
+
+	#[test]
+	fn saved_exr_loads_back_as_an_environment() {
+		let (width, height) = (5, 3);
+		let mut image = AovImage::new(width, height);
+		let mut expected = Vec::new();
+		for (i, pixel) in image.pixels.iter_mut().enumerate() {
+			let beauty = alg::Vector3::new(i as f32 * 0.25, 1.0 / (i as f32 + 1.0), 1000.0 - i as f32);
+			pixel.add(&AovSample { beauty, light : LightSplit::new(), surface : None });
+			expected.push(beauty);
+		}
+
+		//The other layers are in the file too, only R, G and B are read back
+		let name = env::temp_dir().join("exr_round_trip_test");
+		let name = name.to_str().unwrap();
+		image.save(name, &[Aov::Beauty, Aov::Albedo, Aov::Depth], true);
+		let path = format!("{}.exr", name);
+		let environment = EquirectEnvironment::load(Path::new(&path), 0.0, 1.0).unwrap();
+		fs::remove_file(&path).unwrap();
+
+		assert_eq!((environment.width, environment.height), (width, height));
+		assert!(environment.pixels == expected, "Read back {:?} instead of {:?}", environment.pixels, expected);
+	}
+
+	//A data window with these corners, written over one that was saved properly
+	fn with_window(name : &str, window : [i32; 4]) -> io::Result<(usize, usize, Vec<alg::Vector3<f32>>)> {
+		let path = env::temp_dir().join(name);
+		let values = [0.5; 4];
+		let mut channels : Vec<ExrChannel> = ["R", "G", "B"].iter().map(|name| ExrChannel { name : name.to_string(), values : &values[..] }).collect();
+		write_exr(&path, 2, 2, &mut channels).unwrap();
+
+		let mut bytes = fs::read(&path).unwrap();
+		let attribute = b"dataWindow\0box2i\0";
+		let start = bytes.windows(attribute.len()).position(|window| window == &attribute[..]).unwrap() + attribute.len() + 4;
+		for (i, corner) in window.iter().enumerate() {
+			bytes[start + i * 4..start + i * 4 + 4].copy_from_slice(&corner.to_le_bytes());
+		}
+		fs::write(&path, &bytes).unwrap();
+
+		let result = read_exr_rgb(&path);
+		fs::remove_file(&path).unwrap();
+		result
+	}
+
+	#[test]
+	fn oversized_data_window_is_invalid_data() {
+		assert_eq!(with_window("exr_window_test.exr", [0, 0, 1, 1]).unwrap().2.len(), 4);
+		for window in [[i32::MIN, 0, i32::MAX, 1], [0, i32::MIN, 1, i32::MAX], [0, 0, i32::MAX - 1, 1], [0, 0, 1, 1 << 30]].iter() {
+			match with_window("exr_window_test.exr", *window) {
+				Err(why) => assert_eq!(why.kind(), io::ErrorKind::InvalidData),
+				Ok(_) => panic!("The data window {:?} was read", window),
+			}
+		}
+	}
+}
@@ -0,0 +1,208 @@
+extern crate nalgebra as alg;
+
+use std::io;
+use std::io::{Read, Write};
+
+use aov::*;
+use filter::*;
+use integrator::*;
+use checkpoint::*;
+
+//Filter weighted sums of the light passes, divided by the summed weight when the image is saved
+#[derive(Copy, Clone)]
+pub struct FilmPixel {
+	weight : f32,
+	beauty : alg::Vector3<f32>,
+	light : LightSplit,
+}
+
+impl FilmPixel {
+	pub fn new() -> FilmPixel {
+		FilmPixel {
+			weight : 0.0,
+			beauty : alg::Vector3::new(0.0, 0.0, 0.0),
+			light : LightSplit::new(),
+		}
+	}
+
+	fn add(&mut self, other : &FilmPixel) {
+		self.weight += other.weight;
+		self.beauty += other.beauty;
+		self.light.emission += other.light.emission;
+		self.light.direct += other.light.direct;
+		self.light.indirect += other.light.indirect;
+	}
+
+	fn write_to<W : Write>(&self, writer : &mut W) -> io::Result<()> {
+		write_f32(writer, self.weight)?;
+		write_vector(writer, &self.beauty)?;
+		write_vector(writer, &self.light.emission)?;
+		write_vector(writer, &self.light.direct)?;
+		write_vector(writer, &self.light.indirect)
+	}
+
+	fn read_from<R : Read>(reader : &mut R) -> io::Result<FilmPixel> {
+		let mut pixel = FilmPixel::new();
+		pixel.weight = read_f32(reader)?;
+		pixel.beauty = read_vector(reader)?;
+		pixel.light.emission = read_vector(reader)?;
+		pixel.light.direct = read_vector(reader)?;
+		pixel.light.indirect = read_vector(reader)?;
+		Ok(pixel)
+	}
+
+	fn resolve(&self) -> (alg::Vector3<f32>, LightSplit) {
+		//Filters with negative lobes can leave next to no weight on a pixel
+		if self.weight.abs() < 0.000001 {
+			return (alg::Vector3::new(0.0, 0.0, 0.0), LightSplit::new());
+		}
+
+		let mut light = self.light;
+		light.emission /= self.weight;
+		light.direct /= self.weight;
+		light.indirect /= self.weight;
+		(self.beauty / self.weight, light)
+	}
+}
+
+//Part of the film one thread renders in to. Samples near its edge spread in to neighbouring tiles, so it's padded by the
+//filter radius on every side and merged in to the film when it's done instead of every sample touching shared pixels
+pub struct FilmTile {
+	//pixel bounds covered including the padding, y going up like the camera
+	x0 : isize,
+	y0 : isize,
+	width : usize,
+	height : usize,
+	filter : Filter,
+	pixels : Vec<FilmPixel>,
+}
+
+impl FilmTile {
+	//A tile for rendering pixels x0..x1, y0..y1, padded out to every pixel its samples can reach.
+	//Doesn't need the film itself, so threads can start tiles while the film is still taking in finished ones
+	pub fn new(film_width : usize, film_height : usize, filter : Filter, x0 : usize, y0 : usize, x1 : usize, y1 : usize) -> FilmTile {
+		let padding = filter.radius.ceil() as isize;
+		let padded_x0 = (x0 as isize - padding).max(0);
+		let padded_y0 = (y0 as isize - padding).max(0);
+		let padded_x1 = (x1 as isize + padding).min(film_width as isize);
+		let padded_y1 = (y1 as isize + padding).min(film_height as isize);
+		let width = (padded_x1 - padded_x0) as usize;
+		let height = (padded_y1 - padded_y0) as usize;
+
+		FilmTile {
+			x0 : padded_x0,
+			y0 : padded_y0,
+			width,
+			height,
+			filter,
+			pixels : vec![FilmPixel::new(); width * height],
+		}
+	}
+
+	pub fn write_to<W : Write>(&self, writer : &mut W) -> io::Result<()> {
+		write_u32(writer, self.x0 as u32)?;
+		write_u32(writer, self.y0 as u32)?;
+		write_u32(writer, self.width as u32)?;
+		write_u32(writer, self.height as u32)?;
+		for pixel in self.pixels.iter() {
+			pixel.write_to(writer)?;
+		}
+		Ok(())
+	}
+
+	pub fn read_from<R : Read>(reader : &mut R, filter : Filter) -> io::Result<FilmTile> {
+		let x0 = read_u32(reader)? as isize;
+		let y0 = read_u32(reader)? as isize;
+		let width = read_u32(reader)? as usize;
+		let height = read_u32(reader)? as usize;
+		let mut pixels = Vec::with_capacity(width * height);
+		for _pixel in 0..width * height {
+			pixels.push(FilmPixel::read_from(reader)?);
+		}
+
+		Ok(FilmTile {
+			x0,
+			y0,
+			width,
+			height,
+			filter,
+			pixels,
+		})
+	}
+
+	//Sample position in pixels, (0, 0) is the bottom left corner of the image
+	pub fn add_sample(&mut self, film_x : f32, film_y : f32, sample : &AovSample) {
+		let radius = self.filter.radius;
+		let first_x = ((film_x - 0.5 - radius).ceil() as isize).max(self.x0);
+		let last_x = ((film_x - 0.5 + radius).floor() as isize).min(self.x0 + self.width as isize - 1);
+		let first_y = ((film_y - 0.5 - radius).ceil() as isize).max(self.y0);
+		let last_y = ((film_y - 0.5 + radius).floor() as isize).min(self.y0 + self.height as isize - 1);
+
+		for y in first_y..last_y + 1 {
+			for x in first_x..last_x + 1 {
+				let weight = self.filter.evaluate(film_x - (x as f32 + 0.5), film_y - (y as f32 + 0.5));
+				if weight == 0.0 {
+					continue;
+				}
+
+				let pixel = &mut self.pixels[(y - self.y0) as usize * self.width + (x - self.x0) as usize];
+				pixel.weight += weight;
+				pixel.beauty += sample.beauty * weight;
+				pixel.light.emission += sample.light.emission * weight;
+				pixel.light.direct += sample.light.direct * weight;
+				pixel.light.indirect += sample.light.indirect * weight;
+			}
+		}
+	}
+}
+
+//The whole image's filtered light passes. Geometric passes and ids can't be blended across pixels, they stay in the AovPixels
+pub struct Film {
+	pub width : usize,
+	pub height : usize,
+	pixels : Vec<FilmPixel>,
+}
+
+impl Film {
+	pub fn new(width : usize, height : usize) -> Film {
+		Film {
+			width,
+			height,
+			pixels : vec![FilmPixel::new(); width * height],
+		}
+	}
+
+	pub fn merge(&mut self, tile : &FilmTile) {
+		for y in 0..tile.height {
+			let row = (tile.y0 as usize + y) * self.width + tile.x0 as usize;
+			for x in 0..tile.width {
+				self.pixels[row + x].add(&tile.pixels[y * tile.width + x]);
+			}
+		}
+	}
+
+	pub fn write_to<W : Write>(&self, writer : &mut W) -> io::Result<()> {
+		for pixel in self.pixels.iter() {
+			pixel.write_to(writer)?;
+		}
+		Ok(())
+	}
+
+	pub fn read_from<R : Read>(reader : &mut R, width : usize, height : usize) -> io::Result<Film> {
+		let mut film = Film::new(width, height);
+		for pixel in film.pixels.iter_mut() {
+			*pixel = FilmPixel::read_from(reader)?;
+		}
+		Ok(film)
+	}
+
+	//Write the filtered light passes in to the image, which is stored top row first
+	pub fn resolve(&self, image : &mut AovImage) {
+		for y in 0..self.height {
+			for x in 0..self.width {
+				let (beauty, light) = self.pixels[y * self.width + x].resolve();
+				image.pixels[(self.height - 1 - y) * self.width + x].set_light(beauty, light);
+			}
+		}
+	}
+}
@@ -0,0 +1,79 @@
+use std::f32::consts::PI;
+
+//Reconstruction filters, how much a sample counts towards a pixel given how far it landed from the pixel's centre
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterKind {
+	//Every sample inside the radius counts the same, with a radius of 0.5 it's the plain average of the samples in the pixel
+	Box,
+	Tent,
+	Gaussian,
+	//Mitchell-Netravali with B = C = 1/3, sharper than a gaussian with small negative lobes
+	Mitchell,
+	//Windowed sinc, the sharpest but rings around high contrast edges
+	Lanczos,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+	pub kind : FilterKind,
+	//In pixels, samples further than this from a pixel's centre on either axis don't count towards it
+	pub radius : f32,
+}
+
+fn gaussian(x : f32, alpha : f32) -> f32 {
+	(-alpha * x * x).exp()
+}
+
+fn mitchell(x : f32) -> f32 {
+	let b = 1.0 / 3.0;
+	let c = 1.0 / 3.0;
+	let x = x.abs();
+	if x > 2.0 {
+		return 0.0;
+	}
+
+	if x > 1.0 {
+		return ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0;
+	}
+	((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+}
+
+fn sinc(x : f32) -> f32 {
+	if x.abs() < 0.00001 {
+		return 1.0;
+	}
+	(PI * x).sin() / (PI * x)
+}
+
+impl Filter {
+	pub fn new(kind : FilterKind, radius : f32) -> Filter {
+		Filter {
+			kind,
+			radius,
+		}
+	}
+
+	//All of them are separable, the weight is the product of the weight along each axis
+	pub fn evaluate(&self, x : f32, y : f32) -> f32 {
+		self.evaluate_1d(x) * self.evaluate_1d(y)
+	}
+
+	fn evaluate_1d(&self, x : f32) -> f32 {
+		if x.abs() > self.radius {
+			return 0.0;
+		}
+
+		match self.kind {
+			FilterKind::Box => 1.0,
+			FilterKind::Tent => self.radius - x.abs(),
+			//Standard deviation of a third of the radius, shifted down so it reaches zero at the radius instead of being cut off
+			FilterKind::Gaussian => {
+				let alpha = 4.5 / (self.radius * self.radius);
+				(gaussian(x, alpha) - gaussian(self.radius, alpha)).max(0.0)
+			},
+			//Defined over [-2, 2], stretched to fit the radius
+			FilterKind::Mitchell => mitchell(2.0 * x / self.radius),
+			FilterKind::Lanczos => sinc(x) * sinc(x / self.radius),
+		}
+	}
+}
@@ -0,0 +1,268 @@
+extern crate nalgebra as alg;
+
+use std::f32;
+use std::mem;
+
+use aabb::*;
+use hits::*;
+use ray::*;
+use stats::*;
+
+//Cells per object, higher means smaller cells with fewer objects in each
+const GRID_DENSITY : f32 = 3.0;
+const MAX_RESOLUTION : usize = 128;
+//Cells holding more objects than this get their own grid inside them
+const NESTED_GRID_THRESHOLD : usize = 16;
+
+enum GridCell {
+	Objects(Vec<usize>),
+	Nested(GridLevel),
+}
+
+struct GridLevel {
+	bbox : AABB,
+	resolution : [usize; 3],
+	cell_size : alg::Vector3<f32>,
+	cells : Vec<GridCell>,
+}
+
+//A two level uniform grid, densely populated cells are subdivided again with a grid of their own
+pub struct Grid {
+	objects : Vec<Box<dyn Hitable + Sync>>,
+	root : GridLevel,
+}
+
+impl GridLevel {
+	fn new(bbox : AABB, indices : Vec<usize>, boxes : &Vec<AABB>, allow_nesting : bool) -> GridLevel {
+		let extent = bbox.max() - bbox.min();
+		let max_extent = extent.x.max(extent.y.max(extent.z)).max(0.000001);
+
+		//Aim for GRID_DENSITY cells per object, sized from the volume of the box (or its longest side if it's flat)
+		let volume = extent.x * extent.y * extent.z;
+		let cells_per_unit = if volume > 0.0 {
+			(GRID_DENSITY * indices.len() as f32 / volume).cbrt()
+		} else {
+			(GRID_DENSITY * indices.len() as f32).cbrt() / max_extent
+		};
+
+		let mut resolution = [1; 3];
+		let mut cell_size = alg::Vector3::new(max_extent, max_extent, max_extent);
+		for axis in 0..3 {
+			if extent[axis] > 0.0 {
+				resolution[axis] = ((extent[axis] * cells_per_unit).round() as usize).clamp(1, MAX_RESOLUTION);
+				cell_size[axis] = extent[axis] / resolution[axis] as f32;
+			}
+		}
+
+		let mut contents : Vec<Vec<usize>> = vec![vec![]; resolution[0] * resolution[1] * resolution[2]];
+		for &i in indices.iter() {
+			let first = cell_of(&bbox, &resolution, &cell_size, boxes[i].min());
+			let last = cell_of(&bbox, &resolution, &cell_size, boxes[i].max());
+			for z in first[2]..last[2] + 1 {
+				for y in first[1]..last[1] + 1 {
+					for x in first[0]..last[0] + 1 {
+						contents[x + resolution[0] * (y + resolution[1] * z)].push(i);
+					}
+				}
+			}
+		}
+
+		let total = indices.len();
+		let mut cells : Vec<GridCell> = Vec::with_capacity(contents.len());
+		for (index, objects) in contents.into_iter().enumerate() {
+			if allow_nesting && objects.len() > NESTED_GRID_THRESHOLD && objects.len() < total {
+				let x = index % resolution[0];
+				let y = (index / resolution[0]) % resolution[1];
+				let z = index / (resolution[0] * resolution[1]);
+				let min = bbox.min() + alg::Vector3::new(x as f32 * cell_size.x, y as f32 * cell_size.y, z as f32 * cell_size.z);
+				let cell_box = AABB::new(min, min + cell_size);
+				cells.push(GridCell::Nested(GridLevel::new(cell_box, objects, boxes, false)));
+			}
+			else {
+				cells.push(GridCell::Objects(objects));
+			}
+		}
+
+		GridLevel {
+			bbox,
+			resolution,
+			cell_size,
+			cells,
+		}
+	}
+
+	//3D DDA through the cells the ray passes through, stopping once the closest hit is inside the current cell
+	fn hit(&self, objects : &Vec<Box<dyn Hitable + Sync>>, ray : &Ray, time_min : f32, time_max : f32) -> Option<HitInfo> {
+		let (t_enter, t_exit) = self.bbox.hit_range(ray, time_min, time_max)?;
+
+		let cell = cell_of(&self.bbox, &self.resolution, &self.cell_size, ray.point_at_parameter(t_enter));
+		let mut cell = [cell[0] as isize, cell[1] as isize, cell[2] as isize];
+		let mut step = [0isize; 3];
+		let mut next_crossing = [f32::MAX; 3];
+		let mut delta = [f32::MAX; 3];
+		for axis in 0..3 {
+			let direction = ray.direction()[axis];
+			let cell_min = self.bbox.min()[axis] + cell[axis] as f32 * self.cell_size[axis];
+			if direction > 0.0 {
+				step[axis] = 1;
+				next_crossing[axis] = (cell_min + self.cell_size[axis] - ray.origin()[axis]) / direction;
+				delta[axis] = self.cell_size[axis] / direction;
+			}
+			else if direction < 0.0 {
+				step[axis] = -1;
+				next_crossing[axis] = (cell_min - ray.origin()[axis]) / direction;
+				delta[axis] = -self.cell_size[axis] / direction;
+			}
+		}
+
+		let mut closest = time_max;
+		let mut closest_hit : Option<HitInfo> = None;
+		loop {
+			count_box_test(ray);
+			let index = cell[0] as usize + self.resolution[0] * (cell[1] as usize + self.resolution[1] * cell[2] as usize);
+			match self.cells[index] {
+				GridCell::Objects(ref list) => {
+					for &i in list.iter() {
+						if let Some(hit) = objects[i].hit(ray, time_min, closest) {
+							closest = hit.time;
+							closest_hit = Some(hit);
+						}
+					}
+				},
+				GridCell::Nested(ref level) => {
+					if let Some(hit) = level.hit(objects, ray, time_min, closest) {
+						closest = hit.time;
+						closest_hit = Some(hit);
+					}
+				},
+			}
+
+			let mut axis = 0;
+			if next_crossing[1] < next_crossing[axis] {
+				axis = 1;
+			}
+			if next_crossing[2] < next_crossing[axis] {
+				axis = 2;
+			}
+
+			//Anything hit so far is closer than every cell that's left
+			if closest <= next_crossing[axis] || next_crossing[axis] > t_exit {
+				break;
+			}
+
+			cell[axis] += step[axis];
+			if cell[axis] < 0 || cell[axis] >= self.resolution[axis] as isize {
+				break;
+			}
+			next_crossing[axis] += delta[axis];
+		}
+
+		closest_hit
+	}
+
+	fn accumulate_stats(&self, stats : &mut BVHStats, depth : usize) {
+		stats.add_node(&self.bbox, depth, mem::size_of::<GridLevel>() + self.cells.len() * mem::size_of::<GridCell>());
+		for cell in self.cells.iter() {
+			match *cell {
+				GridCell::Objects(ref list) => {
+					if !list.is_empty() {
+						stats.add_leaf(&self.bbox, depth + 1, list.len(), list.len() * mem::size_of::<usize>());
+					}
+				},
+				GridCell::Nested(ref level) => level.accumulate_stats(stats, depth + 1),
+			}
+		}
+	}
+}
+
+fn cell_of(bbox : &AABB, resolution : &[usize; 3], cell_size : &alg::Vector3<f32>, pos : alg::Vector3<f32>) -> [usize; 3] {
+	let mut cell = [0; 3];
+	for axis in 0..3 {
+		let index = ((pos[axis] - bbox.min()[axis]) / cell_size[axis]) as isize;
+		cell[axis] = index.max(0).min(resolution[axis] as isize - 1) as usize;
+	}
+	cell
+}
+
+impl Grid {
+	pub fn new(list : Vec<Box<dyn Hitable + Sync>>, time_min : f32, time_max : f32) -> Grid {
+		assert_ne!(list.len(), 0);
+
+		let boxes : Vec<AABB> = list.iter().map(|h| h.bounding_box(time_min, time_max).unwrap()).collect();
+		let bbox = boxes.iter().skip(1).fold(boxes[0].clone(), |a, b| surrounding_box(&a, b));
+		let root = GridLevel::new(bbox, (0..list.len()).collect(), &boxes, true);
+
+		Grid {
+			objects : list,
+			root,
+		}
+	}
+}
+
+impl Hitable for Grid {
+	fn hit(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<HitInfo> {
+		self.root.hit(&self.objects, ray, time_min, time_max)
+	}
+
+	fn bounding_box(&self, _time_min : f32, _time_max : f32) -> Option<AABB> {
+		Some(self.root.bbox.clone())
+	}
+
+	fn accumulate_stats(&self, stats : &mut BVHStats, depth : usize) {
+		self.root.accumulate_stats(stats, depth);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use materials::*;
+	use sampler::*;
+	use shapes::*;
+
+	//Half spread out and half packed in to one corner, so some cells get a nested grid of their own
+	fn random_spheres() -> Vec<Box<dyn Hitable + Sync>> {
+		let mut list : Vec<Box<dyn Hitable + Sync>> = Vec::new();
+		for i in 0..200 {
+			start_independent_sample(3, i, 0);
+			let spread = if i % 2 == 0 { 10.0 } else { 1.0 };
+			let center = alg::Vector3::new(next_sample_1d() * spread, next_sample_1d() * spread, next_sample_1d() * spread);
+			let radius = 0.05 + next_sample_1d() * 0.3;
+			list.push(Box::new(Sphere::new(center, radius, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Diffuse::new(1)), i + 1)));
+		}
+		list
+	}
+
+	#[test]
+	fn grid_finds_the_same_nearest_hits_as_a_linear_list() {
+		let grid = Grid::new(random_spheres(), 0.0, 1.0);
+		let linear = random_spheres();
+
+		let mut hits = 0;
+		for i in 0..1000 {
+			start_independent_sample(4, i, 0);
+			let origin = alg::Vector3::new(next_sample_1d() * 14.0 - 2.0, next_sample_1d() * 14.0 - 2.0, next_sample_1d() * 14.0 - 2.0);
+			let target = alg::Vector3::new(next_sample_1d() * 10.0, next_sample_1d() * 10.0, next_sample_1d() * 10.0);
+			let ray = Ray::new(origin, target - origin);
+
+			let mut closest : Option<HitInfo> = None;
+			for object in linear.iter() {
+				let time_max = closest.as_ref().map_or(999.9, |hit| hit.time);
+				if let Some(hit) = object.hit(&ray, 0.0001, time_max) {
+					closest = Some(hit);
+				}
+			}
+
+			match (grid.hit(&ray, 0.0001, 999.9), closest) {
+				(Some(a), Some(b)) => {
+					assert_eq!(a.object_id, b.object_id, "ray {} hit a different sphere", i);
+					assert!((a.time - b.time).abs() < 0.00001);
+					hits += 1;
+				},
+				(None, None) => {},
+				_ => panic!("ray {} only hit in one of the two", i),
+			}
+		}
+		assert!(hits > 100);
+	}
+}
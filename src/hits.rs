@@ -5,61 +5,134 @@ use aabb::*;
 use ray::*;
 use shapes::*;
 use materials::*;
+use stats::*;
+use std::mem;
 
 pub struct HitInfo {
 	pub time : f32,
 	pub pos : alg::Vector3<f32>,
 	pub normal : alg::Vector3<f32>,
 	pub colour : alg::Vector3<f32>,
-	pub material: Box<Material + Sync>
+	pub material: Box<dyn Material + Sync>,
+	pub object_id: u32
 }
 
 pub trait Hitable {
 	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo>;
 	fn bounding_box(&self, time_min: f32, time_max: f32) -> Option<AABB>;
+
+	//Used to gather statistics about acceleration structures, anything that isn't one is a single primitive in a leaf
+	fn accumulate_stats(&self, stats : &mut BVHStats, depth : usize) {
+		let bbox = self.bounding_box(0.0, 0.0).unwrap_or(AABB::zero());
+		stats.add_leaf(&bbox, depth, 1, mem::size_of::<Box<dyn Hitable + Sync>>());
+	}
+}
+
+//Bounds of an instance come from its transform at this many steps across the shutter, which is exact for straight line
+//motion and close enough for curves
+static MOTION_BOUND_STEPS : usize = 16;
+
+//Distance, position and normal of the nearest hit in range, the shapes fill in the rest
+fn hit_sphere(center : alg::Vector3<f32>, radius : f32, ray: &Ray, time_min: f32, time_max: f32) -> Option<(f32, alg::Vector3<f32>, alg::Vector3<f32>)> {
+	count_primitive_test(ray);
+	let oc = ray.origin() - center;
+	let a = ray.direction().dot(&ray.direction());
+	let b = oc.dot(&ray.direction());
+	let c = oc.dot(&oc) - radius*radius;
+	let discriminant = b*b - a*c;
+
+	if discriminant > 0.0 {
+		let mut temp = (-b - discriminant.sqrt()) / a;
+		if temp < time_max && temp > time_min {
+			let pos = ray.point_at_parameter(temp);
+			return Some((temp, pos, (pos - center) / radius));
+		}
+
+		temp = (-b + discriminant.sqrt()) / a;
+		if temp < time_max && temp > time_min {
+			let pos = ray.point_at_parameter(temp);
+			return Some((temp, pos, (pos - center) / radius));
+		}
+	}
+
+	None
+}
+
+fn sphere_box(center : alg::Vector3<f32>, radius : f32) -> AABB {
+	AABB::new(center - alg::Vector3::new(radius, radius, radius), center + alg::Vector3::new(radius, radius, radius))
 }
 
 impl Hitable for Sphere {
 	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo> {
-		let oc = ray.origin() - self.center;
-		let a = ray.direction().dot(&ray.direction());
-		let b = oc.dot(&ray.direction());
-		let c = oc.dot(&oc) - self.radius*self.radius;
-		let discriminant = b*b - a*c;
-
-		if discriminant > 0.0 {
-			let mut hit_info = HitInfo {time: 0.0, pos: alg::Vector3::new(0.0, 0.0, 0.0), normal: alg::Vector3::new(0.0, 0.0, 0.0), colour : self.colour, material : self.material.clone()};
-
-			let mut temp = (-b - discriminant.sqrt()) / a;
-			if temp < time_max && temp > time_min {
-				hit_info.time = temp;
-				hit_info.pos = ray.point_at_parameter(temp);
-				hit_info.normal = (hit_info.pos - self.center) / self.radius;
-				return Some(hit_info);
-			}
+		hit_sphere(self.center, self.radius, ray, time_min, time_max).map(|(time, pos, normal)| {
+			HitInfo {time, pos, normal, colour : self.colour, material : self.material.clone(), object_id : self.id}
+		})
+	}
 
-			temp = (-b + discriminant.sqrt()) / a;
-			if temp < time_max && temp > time_min {
-				hit_info.time = temp;
-				hit_info.pos = ray.point_at_parameter(temp);
-				hit_info.normal = (hit_info.pos - self.center) / self.radius;
-				return Some(hit_info);
-			}
+	fn bounding_box(&self, _time_min: f32, _time_max: f32) -> Option<AABB> {
+		Some(sphere_box(self.center, self.radius))
+	}
+}
+
+impl Hitable for MovingSphere {
+	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo> {
+		hit_sphere(self.center(ray.time), self.radius, ray, time_min, time_max).map(|(time, pos, normal)| {
+			HitInfo {time, pos, normal, colour : self.colour, material : self.material.clone(), object_id : self.id}
+		})
+	}
+
+	//Everywhere it goes while the shutter's open
+	fn bounding_box(&self, time_min: f32, time_max: f32) -> Option<AABB> {
+		Some(surrounding_box(&sphere_box(self.center(time_min), self.radius), &sphere_box(self.center(time_max), self.radius)))
+	}
+}
+
+impl Hitable for Instance {
+	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo> {
+		let transform = self.transform(ray.time);
+		if transform.is_identity() {
+			return self.hitable.hit(ray, time_min, time_max);
 		}
 
-		None
+		let object_ray = Ray {
+			a: transform.point_to_object(ray.origin()),
+			b: transform.direction_to_object(ray.direction()),
+			time: ray.time,
+			count_tests: ray.count_tests,
+		};
+		let mut hit_info = self.hitable.hit(&object_ray, time_min, time_max)?;
+		hit_info.pos = ray.point_at_parameter(hit_info.time);
+		hit_info.normal = transform.normal_to_world(hit_info.normal);
+		Some(hit_info)
 	}
 
-	fn bounding_box(&self, _time_min: f32, _time_max: f32) -> Option<AABB> {
-		Some(AABB::new(self.center - alg::Vector3::new(self.radius, self.radius, self.radius),
-		self.center + alg::Vector3::new(self.radius, self.radius, self.radius)))
+	fn bounding_box(&self, time_min: f32, time_max: f32) -> Option<AABB> {
+		let object_box = self.hitable.bounding_box(time_min, time_max)?;
+		let mut bbox : Option<AABB> = None;
+		for step in 0..MOTION_BOUND_STEPS + 1 {
+			let transform = self.transform(time_min + (time_max - time_min) * step as f32 / MOTION_BOUND_STEPS as f32);
+			for corner in 0..8 {
+				let point = alg::Vector3::new(
+					if corner & 1 == 0 { object_box.min().x } else { object_box.max().x },
+					if corner & 2 == 0 { object_box.min().y } else { object_box.max().y },
+					if corner & 4 == 0 { object_box.min().z } else { object_box.max().z });
+				let world = transform.point_to_world(point);
+				let point_box = AABB::new(world, world);
+				bbox = Some(match bbox {
+					Some(bbox) => surrounding_box(&bbox, &point_box),
+					None => point_box,
+				});
+			}
+		}
+		bbox
 	}
 }
 
 impl Hitable for Cube {
 	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo> {
+		count_primitive_test(ray);
 		let oc = ray.origin() - self.center;
-		let mut hit_info = HitInfo {time: 0.0, pos: alg::Vector3::new(0.0, 0.0, 0.0), normal: alg::Vector3::new(1.0, 0.0, 0.0), colour : self.colour, material : self.material.clone()};
+		let mut hit_info = HitInfo {time: 0.0, pos: alg::Vector3::new(0.0, 0.0, 0.0), normal: alg::Vector3::new(1.0, 0.0, 0.0), colour : self.colour, material : self.material.clone(), object_id : self.id};
 
 		let mut min = alg::Vector3::new(
 		-self.extents,
@@ -241,7 +314,8 @@ impl Hitable for Cube {
 
 impl Hitable for Triangle {
 	fn hit(&self, ray: &Ray, time_min: f32, time_max: f32) -> Option<HitInfo> {
-		let mut hit_info = HitInfo {time: 0.0, pos: alg::Vector3::new(0.0, 0.0, 0.0), normal: alg::Vector3::new(1.0, 0.0, 0.0), colour : self.colour, material : self.material.clone()};
+		count_primitive_test(ray);
+		let mut hit_info = HitInfo {time: 0.0, pos: alg::Vector3::new(0.0, 0.0, 0.0), normal: alg::Vector3::new(1.0, 0.0, 0.0), colour : self.colour, material : self.material.clone(), object_id : self.id};
 		
 		let mut edge1 = [0.0; 3];
 		edge1[0] = self.vertices[1].x - self.vertices[0].x;
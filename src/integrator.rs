@@ -0,0 +1,257 @@
+extern crate nalgebra as alg;
+use ray::*;
+use hits::*;
+use scene::*;
+use materials::*;
+use util::*;
+use sampler::*;
+
+//Bounces before russian roulette can end a path
+pub static MIN_BOUNCES : i32 = 3;
+//How far ambient occlusion looks for something blocking the sky, and how many rays it uses to look
+pub static AO_RADIUS : f32 = 1.0;
+pub static AO_SAMPLES : usize = 16;
+pub static MAX_WHITTED_DEPTH : i32 = 8;
+//Surfaces smoother than this are treated as mirrors by the Whitted tracer
+pub static WHITTED_GLOSSY_ROUGHNESS : f32 = 0.5;
+
+//What a ray that escapes the scene sees
+pub fn background(ray : &Ray, scene : &Scene) -> alg::Vector3<f32> {
+	scene.environment.radiance(ray.direction())
+}
+
+//What a ray that escapes the scene sees of infinitely far lights with a size, like the sun's disc
+pub fn infinite_light_radiance(ray : &Ray, scene : &Scene) -> alg::Vector3<f32> {
+	let mut radiance = alg::Vector3::new(0.0, 0.0, 0.0);
+	for light in scene.lights.iter().filter(|light| light.is_infinite() && !light.is_delta()) {
+		radiance += light.radiance_at_infinity(ray.direction());
+	}
+	radiance
+}
+
+//Nudge the start of a new ray off the surface, on whichever side it's heading
+pub fn spawn_ray(hit_info : &HitInfo, direction : alg::Vector3<f32>) -> Ray {
+	let mut bias = hit_info.normal * 0.0001;
+	if direction.dot(&hit_info.normal) < 0.0 {
+		bias = -bias;
+	}
+	Ray::new(hit_info.pos + bias, direction)
+}
+
+//Light arriving directly from every light in the scene. With mis it's weighted against BSDF sampling with the power heuristic,
+//for when the BSDF samples can find the same lights
+pub fn sample_lights(ray : &Ray, hit_info : &HitInfo, scene : &Scene, mis : bool) -> alg::Vector3<f32> {
+	let mut direct = alg::Vector3::new(0.0, 0.0, 0.0);
+	let origin = spawn_ray(hit_info, facing_normal(ray, hit_info)).origin();
+
+	for light in scene.lights.iter() {
+		let sample = match light.sample(origin, next_sample_2d()) {
+			Some(sample) => sample,
+			None => continue,
+		};
+
+		let f = hit_info.material.evaluate(ray, hit_info, sample.direction);
+		if f == alg::Vector3::new(0.0, 0.0, 0.0) {
+			continue;
+		}
+
+		let shadow_ray = Ray::new(origin, sample.direction);
+		if scene.hit(&shadow_ray, 0.00001, sample.distance - 0.0001).is_some() {
+			continue;
+		}
+
+		let mut weight = 1.0;
+		if mis && !sample.delta {
+			weight = power_heuristic(1.0, sample.pdf, 1.0, hit_info.material.pdf(ray, hit_info, sample.direction));
+		}
+
+		direct += multiply_colour(f, sample.radiance) * (weight / sample.pdf);
+	}
+
+	//The environment is like one more light that's everywhere
+	if scene.environment.is_importance_sampled() {
+		if let Some(sample) = scene.environment.sample(next_sample_2d()) {
+			let f = hit_info.material.evaluate(ray, hit_info, sample.direction);
+			let shadow_ray = Ray::new(origin, sample.direction);
+			if f != alg::Vector3::new(0.0, 0.0, 0.0) && scene.hit(&shadow_ray, 0.00001, 999.9).is_none() && scene.hit_light(&shadow_ray, 0.00001, 999.9).is_none() {
+				let mut weight = 1.0;
+				if mis {
+					weight = power_heuristic(1.0, sample.pdf, 1.0, hit_info.material.pdf(ray, hit_info, sample.direction));
+				}
+				direct += multiply_colour(f, sample.radiance) * (weight / sample.pdf);
+			}
+		}
+	}
+
+	direct
+}
+
+//The light a path found, split by how many surfaces it bounced off on the way to the camera
+#[derive(Copy, Clone)]
+pub struct LightSplit {
+	//lights and sky seen straight from the camera
+	pub emission : alg::Vector3<f32>,
+	//light that reached the camera after one bounce
+	pub direct : alg::Vector3<f32>,
+	pub indirect : alg::Vector3<f32>,
+}
+
+impl LightSplit {
+	pub fn new() -> LightSplit {
+		LightSplit {
+			emission : alg::Vector3::new(0.0, 0.0, 0.0),
+			direct : alg::Vector3::new(0.0, 0.0, 0.0),
+			indirect : alg::Vector3::new(0.0, 0.0, 0.0),
+		}
+	}
+
+	fn add(&mut self, bounces : i32, light : alg::Vector3<f32>) {
+		match bounces {
+			0 => self.emission += light,
+			1 => self.direct += light,
+			_ => self.indirect += light,
+		}
+	}
+
+	pub fn total(&self) -> alg::Vector3<f32> {
+		self.emission + self.direct + self.indirect
+	}
+}
+
+pub fn colour(ray : Ray, scene : &Scene) -> alg::Vector3<f32> {
+	colour_split(ray, scene).total()
+}
+
+//Follows the path one bounce at a time, tracking how much of the light found along the way makes it back to the camera.
+//Lights are sampled directly at every bounce and also found by the BSDF samples, the two are combined with MIS
+pub fn colour_split(mut ray : Ray, scene : &Scene) -> LightSplit {
+	let mut radiance = LightSplit::new();
+	let mut throughput = alg::Vector3::new(1.0, 1.0, 1.0);
+	let mut depth = 0;
+
+	//What the previous bounce sampled, needed to weight any light this ray hits
+	let mut specular_bounce = true;
+	let mut last_pdf = 0.0;
+	let mut last_pos = ray.origin();
+
+	loop {
+		let hit = scene.hit(&ray, 0.00001, 999.9);
+		let time_max = match hit {
+			Some(ref hit) => hit.time,
+			None => 999.9,
+		};
+
+		if let Some((index, time)) = scene.hit_light(&ray, 0.00001, time_max) {
+			let light = &scene.lights[index];
+			let mut weight = 1.0;
+			if !specular_bounce {
+				weight = power_heuristic(1.0, last_pdf, 1.0, light.pdf(last_pos, ray.direction()));
+			}
+			radiance.add(depth, multiply_colour(throughput, light.emitted_at(&ray, time)) * weight);
+			return radiance;
+		}
+
+		let hit = match hit {
+			Some(hit) => hit,
+			None => {
+				let mut weight = 1.0;
+				if !specular_bounce && scene.environment.is_importance_sampled() {
+					weight = power_heuristic(1.0, last_pdf, 1.0, scene.environment.pdf(ray.direction()));
+				}
+				radiance.add(depth, multiply_colour(throughput, background(&ray, scene)) * weight);
+
+				//Infinitely far lights with a size, like the sun, are found here too
+				for light in scene.lights.iter().filter(|light| light.is_infinite() && !light.is_delta()) {
+					let mut weight = 1.0;
+					if !specular_bounce {
+						weight = power_heuristic(1.0, last_pdf, 1.0, light.pdf(last_pos, ray.direction()));
+					}
+					radiance.add(depth, multiply_colour(throughput, light.radiance_at_infinity(ray.direction())) * weight);
+				}
+				return radiance;
+			},
+		};
+
+		if !hit.material.is_specular() {
+			radiance.add(depth + 1, multiply_colour(throughput, sample_lights(&ray, &hit, scene, true)));
+		}
+
+		match hit.material.sample(&ray, &hit, next_sample_2d()) {
+			Some(sample) => {
+				throughput = multiply_colour(throughput, sample.weight);
+				specular_bounce = sample.specular;
+				last_pdf = sample.pdf;
+				last_pos = hit.pos;
+				ray = spawn_ray(&hit, sample.direction);
+			},
+			None => return radiance,
+		}
+
+		//Russian roulette, paths carrying less light are more likely to stop and the survivors are weighted up to keep it unbiased
+		depth += 1;
+		if depth >= MIN_BOUNCES {
+			let survival = throughput.x.max(throughput.y.max(throughput.z)).min(0.95);
+			if next_sample_1d() >= survival {
+				return radiance;
+			}
+
+			throughput /= survival;
+		}
+	}
+}
+
+//White where nothing is within AO_RADIUS of the hit, darkening as more of the hemisphere above it is blocked
+pub fn ambient_occlusion(ray : Ray, scene : &Scene) -> alg::Vector3<f32> {
+	let hit = match scene.hit(&ray, 0.00001, 999.9) {
+		Some(hit) => hit,
+		None => return alg::Vector3::new(1.0, 1.0, 1.0),
+	};
+
+	let normal = facing_normal(&ray, &hit);
+	let mut unoccluded = 0;
+	for _i in 0..AO_SAMPLES {
+		let direction = to_world(cosine_sample_hemisphere(next_sample_2d()), normal);
+		if scene.hit(&spawn_ray(&hit, direction), 0.00001, AO_RADIUS).is_none() {
+			unoccluded += 1;
+		}
+	}
+
+	let visibility = unoccluded as f32 / AO_SAMPLES as f32;
+	alg::Vector3::new(visibility, visibility, visibility)
+}
+
+//Classic Whitted ray tracing, direct light from the lights on rough surfaces and recursion down perfect reflections and refractions.
+//No indirect diffuse light, so it needs only a handful of samples per pixel
+pub fn whitted_colour(ray : Ray, scene : &Scene, depth : i32) -> alg::Vector3<f32> {
+	let hit = scene.hit(&ray, 0.00001, 999.9);
+	let time_max = match hit {
+		Some(ref hit) => hit.time,
+		None => 999.9,
+	};
+
+	if let Some((index, time)) = scene.hit_light(&ray, 0.00001, time_max) {
+		return scene.lights[index].emitted_at(&ray, time);
+	}
+
+	let hit = match hit {
+		Some(hit) => hit,
+		None => return background(&ray, scene) + infinite_light_radiance(&ray, scene),
+	};
+
+	if hit.material.roughness() >= WHITTED_GLOSSY_ROUGHNESS {
+		return sample_lights(&ray, &hit, scene, false);
+	}
+
+	let mut radiance = alg::Vector3::new(0.0, 0.0, 0.0);
+	if !hit.material.is_specular() {
+		radiance += sample_lights(&ray, &hit, scene, false);
+	}
+
+	if depth < MAX_WHITTED_DEPTH {
+		for lobe in hit.material.specular_lobes(&ray, &hit) {
+			radiance += multiply_colour(lobe.weight, whitted_colour(spawn_ray(&hit, lobe.direction), scene, depth + 1));
+		}
+	}
+
+	radiance
+}
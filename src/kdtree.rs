@@ -0,0 +1,289 @@
+extern crate nalgebra as alg;
+
+use std::f32;
+use std::mem;
+use std::cmp::Ordering;
+
+use aabb::*;
+use hits::*;
+use ray::*;
+use stats::*;
+
+//SAH costs, splits that leave one side empty are made cheaper so empty space gets cut away early
+const KD_TRAVERSAL_COST : f32 = 1.0;
+const KD_INTERSECTION_COST : f32 = 80.0;
+const KD_EMPTY_BONUS : f32 = 0.5;
+const KD_MAX_LEAF_OBJECTS : usize = 1;
+const KD_MAX_BAD_REFINES : usize = 3;
+
+enum KdNode {
+	//The child below the split is always the next node, above is the index of the other child
+	Interior { axis : usize, split : f32, above : usize },
+	Leaf { objects : Vec<usize> },
+}
+
+pub struct KdTree {
+	objects : Vec<Box<dyn Hitable + Sync>>,
+	nodes : Vec<KdNode>,
+	bbox : AABB,
+}
+
+struct Edge {
+	t : f32,
+	start : bool,
+}
+
+fn split_box(bbox : &AABB, axis : usize, split : f32) -> (AABB, AABB) {
+	let mut below_max = bbox.max();
+	below_max[axis] = split;
+	let mut above_min = bbox.min();
+	above_min[axis] = split;
+	(AABB::new(bbox.min(), below_max), AABB::new(above_min, bbox.max()))
+}
+
+//Returns the cheapest (cost, axis, split) found by sweeping over the edges of every object's box along each axis
+fn find_split(bbox : &AABB, indices : &[usize], boxes : &[AABB]) -> Option<(f32, usize, f32)> {
+	let extent = bbox.max() - bbox.min();
+	let inverse_area = 1.0 / bbox.area();
+	let mut best : Option<(f32, usize, f32)> = None;
+
+	for axis in 0..3 {
+		let mut edges : Vec<Edge> = Vec::with_capacity(indices.len() * 2);
+		for &i in indices.iter() {
+			edges.push(Edge { t : boxes[i].min()[axis], start : true });
+			edges.push(Edge { t : boxes[i].max()[axis], start : false });
+		}
+
+		edges.sort_by(|a, b| {
+			match a.t.partial_cmp(&b.t).unwrap_or(Ordering::Equal) {
+				Ordering::Equal => b.start.cmp(&a.start),
+				ordering => ordering,
+			}
+		});
+
+		let other0 = (axis + 1) % 3;
+		let other1 = (axis + 2) % 3;
+		let mut below = 0;
+		let mut above = indices.len();
+		for edge in edges.iter() {
+			if !edge.start {
+				above -= 1;
+			}
+
+			if edge.t > bbox.min()[axis] && edge.t < bbox.max()[axis] {
+				let below_area = 2.0 * (extent[other0] * extent[other1] + (edge.t - bbox.min()[axis]) * (extent[other0] + extent[other1]));
+				let above_area = 2.0 * (extent[other0] * extent[other1] + (bbox.max()[axis] - edge.t) * (extent[other0] + extent[other1]));
+				let bonus = if below == 0 || above == 0 { KD_EMPTY_BONUS } else { 0.0 };
+				let cost = KD_TRAVERSAL_COST + KD_INTERSECTION_COST * (1.0 - bonus) *
+					(below_area * inverse_area * below as f32 + above_area * inverse_area * above as f32);
+
+				let better = match best {
+					Some((best_cost, _, _)) => cost < best_cost,
+					None => true,
+				};
+
+				if better {
+					best = Some((cost, axis, edge.t));
+				}
+			}
+
+			if edge.start {
+				below += 1;
+			}
+		}
+	}
+
+	best
+}
+
+fn build_node(nodes : &mut Vec<KdNode>, bbox : &AABB, indices : Vec<usize>, boxes : &Vec<AABB>, depth : usize, mut bad_refines : usize) {
+	if indices.len() <= KD_MAX_LEAF_OBJECTS || depth == 0 {
+		nodes.push(KdNode::Leaf { objects : indices });
+		return;
+	}
+
+	let leaf_cost = KD_INTERSECTION_COST * indices.len() as f32;
+	let (cost, axis, split) = match find_split(bbox, &indices, boxes) {
+		Some(best) => best,
+		None => {
+			nodes.push(KdNode::Leaf { objects : indices });
+			return;
+		}
+	};
+
+	if cost > leaf_cost {
+		bad_refines += 1;
+	}
+
+	if (cost > 4.0 * leaf_cost && indices.len() < 16) || bad_refines == KD_MAX_BAD_REFINES {
+		nodes.push(KdNode::Leaf { objects : indices });
+		return;
+	}
+
+	//Objects touching the split plane go down both sides so nothing flat can fall between them
+	let below : Vec<usize> = indices.iter().cloned().filter(|&i| boxes[i].min()[axis] <= split).collect();
+	let above : Vec<usize> = indices.iter().cloned().filter(|&i| boxes[i].max()[axis] >= split).collect();
+	let (below_box, above_box) = split_box(bbox, axis, split);
+
+	let index = nodes.len();
+	nodes.push(KdNode::Interior { axis, split, above : 0 });
+	build_node(nodes, &below_box, below, boxes, depth - 1, bad_refines);
+	let above_index = nodes.len();
+	build_node(nodes, &above_box, above, boxes, depth - 1, bad_refines);
+
+	nodes[index] = KdNode::Interior { axis, split, above : above_index };
+}
+
+impl KdTree {
+	pub fn new(list : Vec<Box<dyn Hitable + Sync>>, time_min : f32, time_max : f32) -> KdTree {
+		assert_ne!(list.len(), 0);
+
+		let boxes : Vec<AABB> = list.iter().map(|h| h.bounding_box(time_min, time_max).unwrap()).collect();
+		let bbox = boxes.iter().skip(1).fold(boxes[0].clone(), |a, b| surrounding_box(&a, b));
+		let max_depth = (8.0 + 1.3 * (list.len() as f32).log2()).round() as usize;
+
+		let mut nodes : Vec<KdNode> = vec![];
+		build_node(&mut nodes, &bbox, (0..list.len()).collect(), &boxes, max_depth, 0);
+
+		KdTree {
+			objects : list,
+			nodes,
+			bbox,
+		}
+	}
+
+	fn accumulate_node_stats(&self, index : usize, bbox : &AABB, stats : &mut BVHStats, depth : usize) {
+		match self.nodes[index] {
+			KdNode::Interior { axis, split, above } => {
+				stats.add_node(bbox, depth, mem::size_of::<KdNode>());
+				let (below_box, above_box) = split_box(bbox, axis, split);
+				self.accumulate_node_stats(index + 1, &below_box, stats, depth + 1);
+				self.accumulate_node_stats(above, &above_box, stats, depth + 1);
+			},
+			KdNode::Leaf { ref objects } => {
+				stats.add_leaf(bbox, depth, objects.len(), mem::size_of::<KdNode>() + objects.len() * mem::size_of::<usize>());
+			},
+		}
+	}
+}
+
+impl Hitable for KdTree {
+	fn hit(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<HitInfo> {
+		let (mut t_min, mut t_max) = self.bbox.hit_range(ray, time_min, time_max)?;
+
+		let mut closest = time_max;
+		let mut closest_hit : Option<HitInfo> = None;
+		let mut stack : Vec<(usize, f32, f32)> = Vec::with_capacity(64);
+		let mut index = 0;
+		loop {
+			//A hit closer than this node means nothing further along can be closer
+			if closest < t_min {
+				break;
+			}
+
+			match self.nodes[index] {
+				KdNode::Interior { axis, split, above } => {
+					count_box_test(ray);
+					let origin = ray.origin()[axis];
+					let direction = ray.direction()[axis];
+					let t_plane = (split - origin) / direction;
+
+					let below_first = origin < split || (origin == split && direction <= 0.0);
+					let (first, second) = if below_first { (index + 1, above) } else { (above, index + 1) };
+
+					if t_plane > t_max || t_plane <= 0.0 {
+						index = first;
+					}
+					else if t_plane < t_min {
+						index = second;
+					}
+					else {
+						stack.push((second, t_plane, t_max));
+						index = first;
+						t_max = t_plane;
+					}
+				},
+				KdNode::Leaf { ref objects } => {
+					for &i in objects.iter() {
+						if let Some(hit) = self.objects[i].hit(ray, time_min, closest) {
+							closest = hit.time;
+							closest_hit = Some(hit);
+						}
+					}
+
+					match stack.pop() {
+						Some((next, next_min, next_max)) => {
+							index = next;
+							t_min = next_min;
+							t_max = next_max;
+						},
+						None => break,
+					}
+				},
+			}
+		}
+
+		closest_hit
+	}
+
+	fn bounding_box(&self, _time_min : f32, _time_max : f32) -> Option<AABB> {
+		Some(self.bbox.clone())
+	}
+
+	fn accumulate_stats(&self, stats : &mut BVHStats, depth : usize) {
+		self.accumulate_node_stats(0, &self.bbox, stats, depth);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use materials::*;
+	use sampler::*;
+	use shapes::*;
+
+	//Spheres of mixed sizes, the big ones overlap plenty of splits and end up in more than one leaf
+	fn random_spheres() -> Vec<Box<dyn Hitable + Sync>> {
+		let mut list : Vec<Box<dyn Hitable + Sync>> = Vec::new();
+		for i in 0..200 {
+			start_independent_sample(5, i, 0);
+			let center = alg::Vector3::new(next_sample_1d() * 10.0, next_sample_1d() * 10.0, next_sample_1d() * 10.0);
+			let radius = if i % 10 == 0 { 1.0 + next_sample_1d() } else { 0.05 + next_sample_1d() * 0.3 };
+			list.push(Box::new(Sphere::new(center, radius, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Diffuse::new(1)), i + 1)));
+		}
+		list
+	}
+
+	#[test]
+	fn kd_tree_finds_the_same_nearest_hits_as_a_linear_list() {
+		let tree = KdTree::new(random_spheres(), 0.0, 1.0);
+		let linear = random_spheres();
+
+		let mut hits = 0;
+		for i in 0..1000 {
+			start_independent_sample(6, i, 0);
+			let origin = alg::Vector3::new(next_sample_1d() * 14.0 - 2.0, next_sample_1d() * 14.0 - 2.0, next_sample_1d() * 14.0 - 2.0);
+			let target = alg::Vector3::new(next_sample_1d() * 10.0, next_sample_1d() * 10.0, next_sample_1d() * 10.0);
+			let ray = Ray::new(origin, target - origin);
+
+			let mut closest : Option<HitInfo> = None;
+			for object in linear.iter() {
+				let time_max = closest.as_ref().map_or(999.9, |hit| hit.time);
+				if let Some(hit) = object.hit(&ray, 0.0001, time_max) {
+					closest = Some(hit);
+				}
+			}
+
+			match (tree.hit(&ray, 0.0001, 999.9), closest) {
+				(Some(a), Some(b)) => {
+					assert_eq!(a.object_id, b.object_id, "ray {} hit a different sphere", i);
+					assert!((a.time - b.time).abs() < 0.00001);
+					hits += 1;
+				},
+				(None, None) => {},
+				_ => panic!("ray {} only hit in one of the two", i),
+			}
+		}
+		assert!(hits > 100);
+	}
+}
@@ -1,17 +1,593 @@
 extern crate nalgebra as alg;
 
-pub struct Light {
-	pub center: alg::Vector3<f32>,
-	pub intensity: f32,
-	pub colour: alg::Vector3<f32>,
+use std::f32::consts::PI;
+
+use ray::*;
+use util::*;
+
+//Anything that gives off light. Direct lighting samples a direction towards it from a point, photons and light subpaths
+//start from a point on it going in some direction, and lights with a surface can also be found by rays for MIS.
+//Delta lights (point, spot and directional) are only ever reached by sampling them
+pub trait Light {
+	fn is_delta(&self) -> bool;
+
+	//Infinitely far away, so there's no real position to start light paths from
+	fn is_infinite(&self) -> bool {
+		false
+	}
+
+	//Pick a direction from pos towards the light, with the radiance arriving along it (or the intensity over the squared
+	//distance for delta lights)
+	fn sample(&self, pos : alg::Vector3<f32>, u : alg::Vector2<f32>) -> Option<LightSample>;
+
+	//Solid angle pdf of sample() picking direction from pos
+	fn pdf(&self, _pos : alg::Vector3<f32>, _direction : alg::Vector3<f32>) -> f32 {
+		0.0
+	}
+
+	//Distance along the ray to the light's surface, delta lights can't be hit
+	fn hit(&self, _ray : &Ray, _time_min : f32, _time_max : f32) -> Option<f32> {
+		None
+	}
+
+	//Surface normal at a point on the light, zero for point and spot lights and the direction light travels for directional ones
+	fn normal(&self, _pos : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		alg::Vector3::new(0.0, 0.0, 0.0)
+	}
+
+	//Radiance leaving a point on the light with the normal in direction, or the intensity that way for point and spot lights
+	fn emitted_towards(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> alg::Vector3<f32>;
+
+	//Pick a point on the light to emit from, with its normal and the pdf with respect to area (1 for point and spot lights)
+	fn sample_position(&self, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32);
+
+	fn pdf_position(&self) -> f32;
+
+	//Direction for light to leave a point on the light in, with its solid angle pdf
+	fn sample_direction(&self, normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32);
+
+	fn pdf_direction(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32;
+
+	//Told about the sphere around everything once the scene's built, for lights that have to cover all of it
+	fn set_scene_bounds(&mut self, _center : alg::Vector3<f32>, _radius : f32) {}
+
+	//What a ray escaping the scene in direction sees of an infinitely far light, like a sun's disc
+	fn radiance_at_infinity(&self, _direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		alg::Vector3::new(0.0, 0.0, 0.0)
+	}
+
+	//Light coming back along a ray that hit the light time along it
+	fn emitted_at(&self, ray : &Ray, time : f32) -> alg::Vector3<f32> {
+		let direction = ray.direction().normalize();
+		self.emitted_towards(self.normal(ray.point_at_parameter(time)), -direction)
+	}
+}
+
+pub struct LightSample {
+	pub direction : alg::Vector3<f32>,
+	pub distance : f32,
+	pub radiance : alg::Vector3<f32>,
+	//solid angle pdf of direction, meaningless for delta lights which can only be sampled one way
+	pub pdf : f32,
+	pub delta : bool,
+}
+
+pub struct PointLight {
+	pub center : alg::Vector3<f32>,
+	pub intensity : f32,
+	pub colour : alg::Vector3<f32>,
+}
+
+//A point light that only shines within cone_angle of its direction, fading out over the last falloff of it
+pub struct SpotLight {
+	pub center : alg::Vector3<f32>,
+	pub direction : alg::Vector3<f32>,
+	pub intensity : f32,
+	pub colour : alg::Vector3<f32>,
+	cos_cone : f32,
+	cos_falloff_start : f32,
+}
+
+//Light from infinitely far away travelling along direction, like the sun. intensity is the irradiance it gives a surface
+//facing it. With an angular radius it's a disc in the sky that casts soft shadows, without one the light is parallel
+pub struct DirectionalLight {
+	pub direction : alg::Vector3<f32>,
+	pub intensity : f32,
+	pub colour : alg::Vector3<f32>,
+	//Radians
+	pub angular_radius : f32,
+	cos_angular_radius : f32,
+	//The sphere around the scene, light paths start from a disc across it
+	scene_center : alg::Vector3<f32>,
+	scene_radius : f32,
+}
+
+//A glowing ball that rays can hit
+pub struct SphereLight {
+	pub center : alg::Vector3<f32>,
+	pub radius : f32,
+	pub intensity : f32,
+	pub colour : alg::Vector3<f32>,
+}
+
+//A parallelogram from corner along edge_u and edge_v, lit on the side edge_u x edge_v points to
+pub struct RectLight {
+	pub corner : alg::Vector3<f32>,
+	pub edge_u : alg::Vector3<f32>,
+	pub edge_v : alg::Vector3<f32>,
+	pub intensity : f32,
+	pub colour : alg::Vector3<f32>,
+	normal : alg::Vector3<f32>,
+	area : f32,
+}
+
+impl PointLight {
+	pub fn new(center : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32>) -> PointLight {
+		PointLight {
+			center,
+			intensity,
+			colour,
+		}
+	}
+}
+
+impl SpotLight {
+	//Angles are in degrees, cone_angle from the direction to the edge of the light
+	pub fn new(center : alg::Vector3<f32>, direction : alg::Vector3<f32>, cone_angle : f32, falloff : f32, intensity : f32, colour : alg::Vector3<f32>) -> SpotLight {
+		let cone_angle = cone_angle.clamp(0.0, 180.0);
+		SpotLight {
+			center,
+			direction : direction.normalize(),
+			intensity,
+			colour,
+			cos_cone : cone_angle.to_radians().cos(),
+			cos_falloff_start : (cone_angle - falloff.max(0.0)).max(0.0).to_radians().cos(),
+		}
+	}
+
+	//How much of the intensity goes out in direction, smoothly from all of it inside the falloff to none at the edge
+	fn falloff(&self, direction : alg::Vector3<f32>) -> f32 {
+		let cos_theta = direction.dot(&self.direction);
+		if cos_theta < self.cos_cone {
+			return 0.0;
+		}
+		if cos_theta >= self.cos_falloff_start {
+			return 1.0;
+		}
+		let t = (cos_theta - self.cos_cone) / (self.cos_falloff_start - self.cos_cone);
+		t * t * (3.0 - 2.0 * t)
+	}
+}
+
+impl DirectionalLight {
+	pub fn new(direction : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32>) -> DirectionalLight {
+		DirectionalLight::with_radius(direction, 0.0, intensity, colour)
+	}
+
+	//angular_radius is in degrees
+	pub fn with_radius(direction : alg::Vector3<f32>, angular_radius : f32, intensity : f32, colour : alg::Vector3<f32>) -> DirectionalLight {
+		let angular_radius = angular_radius.clamp(0.0, 90.0).to_radians();
+		DirectionalLight {
+			direction : direction.normalize(),
+			intensity,
+			colour,
+			angular_radius,
+			cos_angular_radius : angular_radius.cos(),
+			scene_center : alg::Vector3::new(0.0, 0.0, 0.0),
+			scene_radius : 100.0,
+		}
+	}
+
+	fn solid_angle(&self) -> f32 {
+		2.0 * PI * (1.0 - self.cos_angular_radius)
+	}
+
+	//Radiance of the disc, spread so it gives the same irradiance whatever its size
+	fn radiance(&self) -> alg::Vector3<f32> {
+		self.colour * (self.intensity / self.solid_angle())
+	}
+
+	fn in_disc(&self, direction : alg::Vector3<f32>) -> bool {
+		-direction.normalize().dot(&self.direction) >= self.cos_angular_radius
+	}
+}
+
+impl SphereLight {
+	pub fn new(center : alg::Vector3<f32>, radius : f32, intensity : f32, colour : alg::Vector3<f32>) -> SphereLight {
+		SphereLight {
+			center,
+			radius,
+			intensity,
+			colour,
+		}
+	}
+
+	fn cos_theta_max(&self, pos : alg::Vector3<f32>) -> Option<f32> {
+		let distance_squared = squared_length(self.center - pos);
+		if distance_squared <= self.radius * self.radius {
+			return None;
+		}
+		Some((1.0 - self.radius * self.radius / distance_squared).max(0.0).sqrt())
+	}
+}
+
+impl RectLight {
+	pub fn new(corner : alg::Vector3<f32>, edge_u : alg::Vector3<f32>, edge_v : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32>) -> RectLight {
+		let cross = edge_u.cross(&edge_v);
+		let area = squared_length(cross).sqrt();
+		RectLight {
+			corner,
+			edge_u,
+			edge_v,
+			intensity,
+			colour,
+			normal : cross / area,
+			area,
+		}
+	}
+
+	//Solid angle pdf at pos of picking the point distance away along direction
+	fn solid_angle_pdf(&self, distance : f32, direction : alg::Vector3<f32>) -> f32 {
+		let cos_light = -self.normal.dot(&direction);
+		if cos_light <= 0.0 {
+			return 0.0;
+		}
+		distance * distance / (cos_light * self.area)
+	}
+}
+
+impl Light for PointLight {
+	fn is_delta(&self) -> bool {
+		true
+	}
+
+	fn sample(&self, pos : alg::Vector3<f32>, _u : alg::Vector2<f32>) -> Option<LightSample> {
+		let to_center = self.center - pos;
+		let distance = squared_length(to_center).sqrt();
+		Some(LightSample {
+			direction : to_center / distance,
+			distance,
+			radiance : self.colour * self.intensity / (distance * distance),
+			pdf : 1.0,
+			delta : true,
+		})
+	}
+
+	fn emitted_towards(&self, _normal : alg::Vector3<f32>, _direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.colour * self.intensity
+	}
+
+	fn sample_position(&self, _u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32) {
+		(self.center, alg::Vector3::new(0.0, 0.0, 0.0), 1.0)
+	}
+
+	fn pdf_position(&self) -> f32 {
+		1.0
+	}
+
+	//Evenly in every direction
+	fn sample_direction(&self, _normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32) {
+		(uniform_sample_sphere(u), 1.0 / (4.0 * PI))
+	}
+
+	fn pdf_direction(&self, _normal : alg::Vector3<f32>, _direction : alg::Vector3<f32>) -> f32 {
+		1.0 / (4.0 * PI)
+	}
+}
+
+impl Light for SpotLight {
+	fn is_delta(&self) -> bool {
+		true
+	}
+
+	fn sample(&self, pos : alg::Vector3<f32>, _u : alg::Vector2<f32>) -> Option<LightSample> {
+		let to_center = self.center - pos;
+		let distance = squared_length(to_center).sqrt();
+		let direction = to_center / distance;
+		let falloff = self.falloff(-direction);
+		if falloff <= 0.0 {
+			return None;
+		}
+
+		Some(LightSample {
+			direction,
+			distance,
+			radiance : self.colour * (self.intensity * falloff / (distance * distance)),
+			pdf : 1.0,
+			delta : true,
+		})
+	}
+
+	fn emitted_towards(&self, _normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.colour * (self.intensity * self.falloff(direction))
+	}
+
+	fn sample_position(&self, _u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32) {
+		(self.center, alg::Vector3::new(0.0, 0.0, 0.0), 1.0)
+	}
+
+	fn pdf_position(&self) -> f32 {
+		1.0
+	}
+
+	//Evenly over the whole cone, the falloff is left to the intensity
+	fn sample_direction(&self, _normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32) {
+		(to_world(uniform_sample_cone(u, self.cos_cone), self.direction), 1.0 / (2.0 * PI * (1.0 - self.cos_cone)))
+	}
+
+	fn pdf_direction(&self, _normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		if direction.dot(&self.direction) < self.cos_cone {
+			return 0.0;
+		}
+		1.0 / (2.0 * PI * (1.0 - self.cos_cone))
+	}
+}
+
+impl Light for DirectionalLight {
+	fn is_delta(&self) -> bool {
+		self.angular_radius <= 0.0
+	}
+
+	fn is_infinite(&self) -> bool {
+		true
+	}
+
+	//Straight back along the light or evenly over the disc
+	fn sample(&self, _pos : alg::Vector3<f32>, u : alg::Vector2<f32>) -> Option<LightSample> {
+		if self.is_delta() {
+			return Some(LightSample {
+				direction : -self.direction,
+				distance : 999.9,
+				radiance : self.colour * self.intensity,
+				pdf : 1.0,
+				delta : true,
+			});
+		}
+
+		Some(LightSample {
+			direction : to_world(uniform_sample_cone(u, self.cos_angular_radius), -self.direction),
+			distance : 999.9,
+			radiance : self.radiance(),
+			pdf : 1.0 / self.solid_angle(),
+			delta : false,
+		})
+	}
+
+	fn pdf(&self, _pos : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		if self.is_delta() || !self.in_disc(direction) {
+			return 0.0;
+		}
+		1.0 / self.solid_angle()
+	}
+
+	fn normal(&self, _pos : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.direction
+	}
+
+	fn emitted_towards(&self, _normal : alg::Vector3<f32>, _direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		if self.is_delta() {
+			return self.colour * self.intensity;
+		}
+		self.radiance()
+	}
+
+	//A point on the disc facing the light just outside the scene, so its light crosses all of it
+	fn sample_position(&self, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32) {
+		let disc = uniform_sample_disc(u) * self.scene_radius;
+		let pos = to_world(disc, self.direction) + self.scene_center - self.direction * self.scene_radius;
+		(pos, self.direction, self.pdf_position())
+	}
+
+	fn pdf_position(&self) -> f32 {
+		1.0 / (PI * self.scene_radius * self.scene_radius)
+	}
+
+	fn sample_direction(&self, _normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32) {
+		if self.is_delta() {
+			return (self.direction, 1.0);
+		}
+		(to_world(uniform_sample_cone(u, self.cos_angular_radius), self.direction), 1.0 / self.solid_angle())
+	}
+
+	fn pdf_direction(&self, _normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		if self.is_delta() || direction.normalize().dot(&self.direction) < self.cos_angular_radius {
+			return 0.0;
+		}
+		1.0 / self.solid_angle()
+	}
+
+	fn set_scene_bounds(&mut self, center : alg::Vector3<f32>, radius : f32) {
+		self.scene_center = center;
+		self.scene_radius = radius;
+	}
+
+	fn radiance_at_infinity(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		if self.is_delta() || !self.in_disc(direction) {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+		self.radiance()
+	}
+}
+
+impl Light for SphereLight {
+	fn is_delta(&self) -> bool {
+		false
+	}
+
+	//Sampled uniformly over the cone the sphere covers
+	fn sample(&self, pos : alg::Vector3<f32>, u : alg::Vector2<f32>) -> Option<LightSample> {
+		let to_center = self.center - pos;
+		let distance = squared_length(to_center).sqrt();
+		let cos_theta_max = self.cos_theta_max(pos)?;
+
+		let direction = to_world(uniform_sample_cone(u, cos_theta_max), to_center / distance);
+		let ray = Ray::new(pos, direction);
+		let hit_distance = match self.hit(&ray, 0.0, 999.9) {
+			Some(t) => t,
+			//grazing the edge of the sphere
+			None => distance - self.radius,
+		};
+
+		Some(LightSample {
+			direction,
+			distance : hit_distance,
+			radiance : self.colour * self.intensity,
+			pdf : 1.0 / (2.0 * PI * (1.0 - cos_theta_max)),
+			delta : false,
+		})
+	}
+
+	fn pdf(&self, pos : alg::Vector3<f32>, _direction : alg::Vector3<f32>) -> f32 {
+		match self.cos_theta_max(pos) {
+			Some(cos_theta_max) => 1.0 / (2.0 * PI * (1.0 - cos_theta_max)),
+			None => 0.0,
+		}
+	}
+
+	fn hit(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<f32> {
+		let oc = ray.origin() - self.center;
+		let a = ray.direction().dot(&ray.direction());
+		let b = oc.dot(&ray.direction());
+		let c = oc.dot(&oc) - self.radius * self.radius;
+		let discriminant = b * b - a * c;
+		if discriminant <= 0.0 {
+			return None;
+		}
+
+		let mut t = (-b - discriminant.sqrt()) / a;
+		if t < time_max && t > time_min {
+			return Some(t);
+		}
+
+		t = (-b + discriminant.sqrt()) / a;
+		if t < time_max && t > time_min {
+			return Some(t);
+		}
+
+		None
+	}
+
+	fn normal(&self, pos : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		(pos - self.center).normalize()
+	}
+
+	//Only out of its surface
+	fn emitted_towards(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		if normal.dot(&direction) <= 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+		self.colour * self.intensity
+	}
+
+	fn sample_position(&self, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32) {
+		let normal = uniform_sample_sphere(u);
+		(self.center + normal * self.radius, normal, self.pdf_position())
+	}
+
+	fn pdf_position(&self) -> f32 {
+		1.0 / (4.0 * PI * self.radius * self.radius)
+	}
+
+	//Cosine weighted around the normal
+	fn sample_direction(&self, normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32) {
+		let local = cosine_sample_hemisphere(u);
+		(to_world(local, normal), local.z / PI)
+	}
+
+	fn pdf_direction(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		normal.dot(&direction).max(0.0) / PI
+	}
 }
 
-impl Light {
-	pub fn new (p_center : alg::Vector3<f32>, p_intensity : f32, p_colour : alg::Vector3<f32>) -> Light {
-		Light {
-			center: p_center,
-			intensity: p_intensity,
-			colour: p_colour,
+impl Light for RectLight {
+	fn is_delta(&self) -> bool {
+		false
+	}
+
+	//Uniformly over the area, turned in to a solid angle pdf from pos
+	fn sample(&self, pos : alg::Vector3<f32>, u : alg::Vector2<f32>) -> Option<LightSample> {
+		let to_point = self.corner + self.edge_u * u.x + self.edge_v * u.y - pos;
+		let distance = squared_length(to_point).sqrt();
+		if distance == 0.0 {
+			return None;
 		}
+
+		let direction = to_point / distance;
+		let pdf = self.solid_angle_pdf(distance, direction);
+		if pdf == 0.0 {
+			return None;
+		}
+
+		Some(LightSample {
+			direction,
+			distance,
+			radiance : self.colour * self.intensity,
+			pdf,
+			delta : false,
+		})
 	}
-}
\ No newline at end of file
+
+	fn pdf(&self, pos : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		let direction = direction.normalize();
+		match self.hit(&Ray::new(pos, direction), 0.0, 999.9) {
+			Some(t) => self.solid_angle_pdf(t, direction),
+			None => 0.0,
+		}
+	}
+
+	//Both sides block rays, only the front glows
+	fn hit(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<f32> {
+		let denominator = self.normal.dot(&ray.direction());
+		if denominator == 0.0 {
+			return None;
+		}
+
+		let t = self.normal.dot(&(self.corner - ray.origin())) / denominator;
+		if t <= time_min || t >= time_max {
+			return None;
+		}
+
+		//Where the hit is along each edge, from 0 to 1 inside. The edges needn't be square to each other
+		let offset = ray.point_at_parameter(t) - self.corner;
+		let uu = squared_length(self.edge_u);
+		let vv = squared_length(self.edge_v);
+		let uv = self.edge_u.dot(&self.edge_v);
+		let ou = offset.dot(&self.edge_u);
+		let ov = offset.dot(&self.edge_v);
+		let determinant = uu * vv - uv * uv;
+		let u = (ou * vv - ov * uv) / determinant;
+		let v = (ov * uu - ou * uv) / determinant;
+		if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+			return None;
+		}
+		Some(t)
+	}
+
+	fn normal(&self, _pos : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.normal
+	}
+
+	fn emitted_towards(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		if normal.dot(&direction) <= 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+		self.colour * self.intensity
+	}
+
+	fn sample_position(&self, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>, f32) {
+		(self.corner + self.edge_u * u.x + self.edge_v * u.y, self.normal, self.pdf_position())
+	}
+
+	fn pdf_position(&self) -> f32 {
+		1.0 / self.area
+	}
+
+	fn sample_direction(&self, normal : alg::Vector3<f32>, u : alg::Vector2<f32>) -> (alg::Vector3<f32>, f32) {
+		let local = cosine_sample_hemisphere(u);
+		(to_world(local, normal), local.z / PI)
+	}
+
+	fn pdf_direction(&self, normal : alg::Vector3<f32>, direction : alg::Vector3<f32>) -> f32 {
+		normal.dot(&direction).max(0.0) / PI
+	}
+}
@@ -1,18 +1,17 @@
-use std::error::Error;
-use std::fs::File;
+use std::net::TcpListener;
 use std::path::Path;
-use std::io::{BufWriter, Write};
+use std::time::Instant;
 
 extern crate nalgebra as alg;
 extern crate rand;
 extern crate rayon;
 extern crate image;
 extern crate tobj;
+extern crate memmap;
+extern crate inflate;
 
-use rand::*;
 use rayon::prelude::*;
 use image::*;
-use tobj::*;
 
 mod ray;
 mod shapes;
@@ -23,70 +22,133 @@ mod util;
 mod aabb;
 mod bvh;
 mod light;
+mod mesh;
+mod cache;
+mod stats;
+mod grid;
+mod kdtree;
+mod camera;
+mod integrator;
+mod bdpt;
+mod photon;
+mod exr;
+mod aov;
+mod denoise;
+mod sampler;
+mod rng;
+mod filter;
+mod film;
+mod render;
+mod checkpoint;
+mod distributed;
+mod scene_file;
+mod server;
+mod animation;
+mod environment;
+mod sky;
 
-use ray::*;
-use shapes::*;
-use shapes::Triangle;
 use hits::*;
 use scene::*;
-use materials::*;
-use util::*;
 use bvh::*;
-use light::*;
-
-static DEBUG_NORMALS : bool = false;
-static USE_BVH : bool = false;
+use stats::*;
+use grid::*;
+use kdtree::*;
+use camera::*;
+use photon::*;
+use aov::*;
+use denoise::*;
+use sampler::*;
+use filter::*;
+use render::*;
+use checkpoint::*;
+use distributed::*;
+use server::*;
+use scene_file::*;
+use animation::*;
+
+//Passes every render writes, the normal pass replaces the old normals debug mode
+static AOVS : &[Aov] = &[Aov::Beauty, Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::ObjectId, Aov::MaterialId, Aov::Direct, Aov::Indirect, Aov::Emission, Aov::SampleCount];
+//Write every pass as a layer of one EXR instead of separate PNGs
+static WRITE_EXR : bool = false;
+//Filter the beauty pass before it's saved, makes low sample counts usable for previews
+static DENOISE : bool = false;
+//Keep sampling each pixel in batches until its noise drops under the threshold, so flat areas finish early and noisy ones get more
+static ADAPTIVE_SAMPLING : bool = false;
+static ADAPTIVE_MIN_SAMPLES : i32 = 16;
+static ADAPTIVE_MAX_SAMPLES : i32 = 1024;
+static ADAPTIVE_BATCH_SAMPLES : i32 = 16;
+static ADAPTIVE_NOISE_THRESHOLD : f32 = 0.01;
+//Where the random numbers for pixel positions, BSDFs and lights come from, the low discrepancy ones converge faster
+static SAMPLER : SamplerKind = SamplerKind::Sobol;
+//Every random number comes from this seed and the pixel and sample it's for, the same seed always gives the same image
+static SEED : u32 = 0;
+//How samples are weighted in to the pixels around them, radius is in pixels
+static FILTER_KIND : FilterKind = FilterKind::Gaussian;
+static FILTER_RADIUS : f32 = 1.5;
+//Square tiles handed to threads in this order, the unfinished image is written every FLUSH_INTERVAL seconds
+static TILE_SIZE : usize = 32;
+static TILE_ORDER : TileOrder = TileOrder::Spiral;
+static FLUSH_INTERVAL : f32 = 10.0;
+//Render one sample per pixel per pass until the sample count, TIME_LIMIT seconds (0 for none) or the average pixel noise
+//is reached, writing the image after every pass. --progressive turns it on and --time N sets the time limit
+static PROGRESSIVE : bool = false;
+static TIME_LIMIT : f32 = 600.0;
+static PROGRESSIVE_NOISE_THRESHOLD : f32 = 0.005;
+//Seconds between saving frame_NNNN.checkpoint, run with --resume to carry on from it and --samples N to change the sample count,
+//which also adds samples to a finished render
+static CHECKPOINT_INTERVAL : f32 = 300.0;
+//Frames rendered to frame_0001.png and on, following create_animation. --frames 1-120 or --frames 7 picks others
+static FIRST_FRAME : u32 = 1;
+static LAST_FRAME : u32 = 1;
+//Fractions of a frame the shutter is open for, anything animated or moving in that time is blurred. 0 and 0.5 is a
+//180 degree shutter, the same for both is no motion blur
+static SHUTTER_OPEN : f32 = 0.0;
+static SHUTTER_CLOSE : f32 = 0.0;
+static DEBUG_HEATMAP : bool = false;
+//Number of box and primitive tests a primary ray needs before it shows as red in the heatmap
+static HEATMAP_MAX_TESTS : u32 = 200;
+static ACCELERATION : Acceleration = Acceleration::None;
+static INTEGRATOR : Integrator = Integrator::Path;
+//Instead of rendering, time building and tracing primary rays through every acceleration structure
+static BENCHMARK_ACCELERATION : bool = false;
+static BENCHMARK_PASSES : usize = 4;
 static USE_LIGHTS : bool = true;
-
-pub fn colour(ray : Ray, scene : &Scene, depth : i32) -> alg::Vector3<f32> {
-	if let Some(hit) = scene.hit(&ray, 0.00001, 999.9) {
-		if DEBUG_NORMALS {
-			let normal_colour = 0.5 * alg::Vector3::new(hit.normal.x + 1.0,
-			hit.normal.y + 1.0,
-			hit.normal.z + 1.0);
-			return normal_colour;
-		}
-
-		if depth < 100 {
-			if let Some(bounce) = hit.material.bounce(&ray, 0.0001, 999.9, &hit) {
-				return multiply_colour(bounce.attenuation, colour(bounce.ray, &scene, depth + 1)) * bounce.absorption;
-			}
-			else {
-				return alg::Vector3::new(0.0, 0.0, 0.0);
-			}
-		}
-		else {
-			return alg::Vector3::new(1.0, 0.0, 1.0);
-		}
-	}
-
-	let unit_dir = alg::normalize::<alg::Vector3<f32>>(&ray.direction());
-	let t = 0.5 * (unit_dir.x + 1.0);
-	(1.0 - t) * alg::Vector3::new(1.0, 1.0, 1.0) + t * alg::Vector3::new(1.0, 0.0, 0.0)
+static USE_MESH : bool = false;
+//An equirectangular .hdr or .exr to light the scene with instead of the gradient sky, turned by degrees about the vertical
+static ENVIRONMENT_MAP : Option<&str> = None;
+static ENVIRONMENT_ROTATION : f32 = 0.0;
+static ENVIRONMENT_INTENSITY : f32 = 1.0;
+//Daylight instead of the gradient, with the sun this many degrees up and round from straight ahead. The real sun is about
+//0.27 degrees across its radius, bigger suns give softer shadows. The sky is in kcd/m^2 so it needs scaling down a lot
+static USE_SKY : bool = false;
+static SUN_ELEVATION : f32 = 30.0;
+static SUN_AZIMUTH : f32 = 45.0;
+static TURBIDITY : f32 = 3.0;
+static SUN_RADIUS : f32 = 0.27;
+static SKY_INTENSITY : f32 = 0.05;
+//Samples per pixel for the quick integrators, the rest use the full count
+static PREVIEW_SAMPLES : i32 = 4;
+
+#[derive(Copy, Clone, Debug)]
+enum Acceleration {
+	None,
+	Bvh,
+	Grid,
+	KdTree,
 }
 
-fn main()
-{	
-	let upscale = 1.0;
-	let width = (1920 as f32 * upscale) as usize;
-	let height = (1080 as f32 * upscale) as usize;
-	let mut zoom = 0.5;
-	let fov = width as f32 / 1280.0 * zoom;
-	let aspect = width as f32 / height as f32;
-	let mut lower_left_corner = alg::Vector3::new(-5.0 * zoom, -2.0 * zoom, -10.0);
-	let horizontal = alg::Vector3::new(4.0 / upscale * aspect * fov, 0.0, 0.0);
-	let vertical = alg::Vector3::new(0.0, 4.0 / upscale * fov, 0.0);
-	let mut origin = alg::Vector3::new(0.0, 0.0, 0.0);
-
-	let mut scene1 = Scene{hitables : Vec::new()};
-	
-	let sphere1 = Sphere::new(alg::Vector3::new(0.0, 0.0, -5.0), 0.5, alg::Vector3::new(1.0, 0.2, 0.2), Box::new(Diffuse::new()));
-	scene1.hitables.push(Box::new(sphere1));
-	scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(1.0, 0.0, -7.0), 2.2, alg::Vector3::new(1.0, 1.0, 0.0), Box::new(Diffuse::new()))));
-	scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(1.0, 0.5, -5.0), 0.75, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Metal::new(0.0)))));
-	scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(-1.0, 0.0, -4.0), 0.3, alg::Vector3::new(0.0, 1.0, 0.0), Box::new(Diffuse::new()))));
-	scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(-0.5, 0.0, -4.0), 0.3, alg::Vector3::new(0.0, 0.3, 0.8), Box::new(Diffuse::new()))));
-	scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(0.0, -19.2, -10.0), 20.0, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Metal::new(0.1)))));
+//Described rather than built so the animation can move things around before each frame is built
+fn create_scene() -> SceneDescription {
+	let mut scene1 = SceneDescription::new();
+
+	let sphere1 = ObjectDescription::Sphere { center : alg::Vector3::new(0.0, 0.0, -5.0), radius : 0.5, colour : alg::Vector3::new(1.0, 0.2, 0.2), material : MaterialDescription::Diffuse };
+	scene1.objects.push(sphere1);
+	scene1.objects.push(ObjectDescription::Sphere { center : alg::Vector3::new(1.0, 0.0, -7.0), radius : 2.2, colour : alg::Vector3::new(1.0, 1.0, 0.0), material : MaterialDescription::Diffuse });
+	scene1.objects.push(ObjectDescription::Sphere { center : alg::Vector3::new(1.0, 0.5, -5.0), radius : 0.75, colour : alg::Vector3::new(1.0, 1.0, 1.0), material : MaterialDescription::Metal(0.0) });
+	scene1.objects.push(ObjectDescription::Sphere { center : alg::Vector3::new(-1.0, 0.0, -4.0), radius : 0.3, colour : alg::Vector3::new(0.0, 1.0, 0.0), material : MaterialDescription::Diffuse });
+	scene1.objects.push(ObjectDescription::Sphere { center : alg::Vector3::new(-0.5, 0.0, -4.0), radius : 0.3, colour : alg::Vector3::new(0.0, 0.3, 0.8), material : MaterialDescription::Diffuse });
+	scene1.objects.push(ObjectDescription::Sphere { center : alg::Vector3::new(0.0, -19.2, -10.0), radius : 20.0, colour : alg::Vector3::new(1.0, 1.0, 1.0), material : MaterialDescription::Metal(0.1) });
+	//scene1.hitables.push(Box::new(Sphere::new(alg::Vector3::new(-1.0, 0.9, -4.0), 0.4, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Dielectric::new(1.5)))));
 	//scene.hitables.push(Box::new(Cube::new(alg::Vector3::new(2.0, 1.0, -2.7), 1.0, alg::Vector3::new(0.9, 1.0, 0.9), Box::new(Diffuse::new()))));
 	//scene.hitables.push(Box::new(Cube::new(alg::Vector3::new(-2.0, 1.0, -2.7), 1.0, alg::Vector3::new(0.9, 1.0, 0.9), Box::new(Diffuse::new()))));
 	//scene1.hitables.push(Box::new(Cube::new(alg::Vector3::new(-0.75, 0.0, -2.0), 0.25, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Metal::new(0.0)))));
@@ -110,224 +172,287 @@ fn main()
 	scene1.hitables.push(Box::new(triangle));*/
 	//scene1.hitables.push(Box::new(Cube::new(alg::Vector3::new(-0.95, -0.25, -1.9), 0.25, alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Diffuse::new()))));
 
-	let fox = tobj::load_obj(&Path::new("fox/Fox.obj"));
-	assert!(fox.is_ok());
-	let (models, materials) = fox.unwrap();
-	print_model_info(&models, &materials);
-	println!("# of models: {}", models.len());
-	println!("# of materials: {}", materials.len());
-	
-	for (i, m) in models.iter().enumerate() {
-		let mesh = &m.mesh;
-		println!("model[{}].name = \'{}\'", i, m.name);
-		println!("model[{}].mesh.material_id = {:?}", i, mesh.material_id);
-
-		println!("Size of model[{}].indices: {}", i, mesh.indices.len());
-		/*for f in 0..mesh.indices.len() / 3 {
-			println!("    idx[{}] = {}, {}, {}.", f, mesh.indices[3 * f],
-				mesh.indices[3 * f + 1], mesh.indices[3 * f + 2]);
-		}*/
-
-		println!("model[{}].vertices: {}", i, mesh.positions.len() / 3);
-		assert!(mesh.positions.len() % 3 == 0);
-		/*for v in 0..mesh.positions.len() / 3 {
-			println!("    v[{}] = ({}, {}, {})", v, mesh.positions[3 * v],
-				mesh.positions[3 * v + 1], mesh.positions[3 * v + 2]);
-		}*/
-
-		println!("Triangles: {}", mesh.indices.len() / 3);
+	if USE_MESH {
+		let fox = ObjectDescription::Mesh { path : "fox/Fox.obj".to_string(), scale : 0.2, offset : alg::Vector3::new(-1.0, 1.0, -4.0), colour : alg::Vector3::new(0.0, 1.0, 1.0), material : MaterialDescription::Diffuse };
+		scene1.objects.push(fox);
 	}
-	println!("hi");
-	/*for (i, m) in materials.iter().enumerate() {
-		println!("material[{}].name = \'{}\'", i, m.name);
-		println!("    material.Ka = ({}, {}, {})", m.ambient[0], m.ambient[1],
-			m.ambient[2]);
-		println!("    material.Kd = ({}, {}, {})", m.diffuse[0], m.diffuse[1],
-			m.diffuse[2]);
-		println!("    material.Ks = ({}, {}, {})", m.specular[0], m.specular[1],
-			m.specular[2]);
-		println!("    material.Ns = {}", m.shininess);
-		println!("    material.d = {}", m.dissolve);
-		println!("    material.map_Ka = {}", m.ambient_texture);
-		println!("    material.map_Kd = {}", m.diffuse_texture);
-		println!("    material.map_Ks = {}", m.specular_texture);
-		println!("    material.map_Ns = {}", m.normal_texture);
-		println!("    material.map_d = {}", m.dissolve_texture);
-		for (k, v) in &m.unknown_param {
-			println!("    material.{} = {}", k, v);
-		}
-	}*/
-	
-	for (_i, m) in models.iter().enumerate() {
-		let mesh = &m.mesh;
-		assert!(mesh.indices.len() % 3 == 0);
-		//Convert the indices that map to vertices in to a vector that stores the actual vertices values (one per index)
-		let mut vertices : Vec<alg::Vector3<f32>> = vec![];
-		let mut normals : Vec<alg::Vector3<f32>> = vec![];
-		println!("hi2");
-		for i in 0..mesh.indices.len() / 3 {
-			vertices.push(alg::Vector3::new(
-			mesh.positions[(3 * mesh.indices[3 * i]) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i] + 1) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i] + 2) as usize]));
-
-			vertices.push(alg::Vector3::new(
-			mesh.positions[(3 * mesh.indices[3 * i + 1]) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i + 1] + 1) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i + 1] + 2) as usize]));
-
-
-			vertices.push(alg::Vector3::new(
-			mesh.positions[(3 * mesh.indices[3 * i + 2]) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i + 2] + 1) as usize],
-			mesh.positions[(3 * mesh.indices[3 * i + 2] + 2) as usize]));
-
-
-			if mesh.normals.len() != 0
-			{
-				normals.push(alg::Vector3::new(
-				mesh.normals[(3 * mesh.indices[3 * i]) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i] + 1) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i] + 2) as usize]));
-
-				normals.push(alg::Vector3::new(
-				mesh.normals[(3 * mesh.indices[3 * i + 1]) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i + 1] + 1) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i + 1] + 2) as usize]));
-				
-				normals.push(alg::Vector3::new(
-				mesh.normals[(3 * mesh.indices[3 * i + 2]) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i + 2] + 1) as usize],
-				mesh.normals[(3 * mesh.indices[3 * i + 2] + 2) as usize]));
-			}
-		}
 
-		println!("hi2");
-
-		/*println!("Vertices: {}", vertices.len());
-
-		for v in 0..vertices.len(){
-			println!("v[{}] = ({}, {}, {})",
-			v, vertices[v].x, vertices[v].y, vertices[v].z);
-		}*/
-
-		//for every 3 real vertices create a triangle
-		let scale = 0.2;
-		let offset = alg::Vector3::new(-1.0, 1.0, -4.0);
-		assert!(vertices.len() % 3 == 0);
-		for v in 0..vertices.len() / 3 {
-			let mut verts = [alg::Vector3::new(0.0, 0.0, 0.0); 3];
-			verts[0] = vertices[3 * v];
-			verts[1] = vertices[3 * v + 1];
-			verts[2] = vertices[3 * v + 2];
-
-			verts[0] *= scale;
-			verts[1] *= scale;
-			verts[2] *= scale;
-
-			verts[0] += offset;
-			verts[1] += offset;
-			verts[2] += offset;
-
-			let mut normal = alg::Vector3::new(0.0, 0.0, 0.0);
-			if mesh.normals.len() != 0
-			{
-				normal = normals[3 * v];
-			}
+	if let Some(path) = ENVIRONMENT_MAP {
+		scene1.environment = Some(EnvironmentDescription::Map { path : path.to_string(), rotation : ENVIRONMENT_ROTATION, intensity : ENVIRONMENT_INTENSITY });
+	}
+
+	if USE_SKY {
+		scene1.environment = Some(EnvironmentDescription::Sky { elevation : SUN_ELEVATION, azimuth : SUN_AZIMUTH, turbidity : TURBIDITY, sun_radius : SUN_RADIUS, intensity : SKY_INTENSITY });
+	}
+
+	if USE_LIGHTS {
+		scene1.objects.push(ObjectDescription::Light { center : alg::Vector3::new(0.0, 0.0, 0.0), intensity : 10.0, colour : alg::Vector3::new(1.0, 1.0, 1.0) });
+		scene1.objects.push(ObjectDescription::SphereLight { center : alg::Vector3::new(-1.0, 2.5, -4.0), radius : 0.4, intensity : 8.0, colour : alg::Vector3::new(1.0, 0.9, 0.8) });
+	}
+
+	scene1
+}
+
+//Keyframes for the camera and the objects in create_scene by their index, frame 1 is the scene as it's described
+fn create_animation() -> Result<Animation, String> {
+	let mut animation = Animation::new();
+
+	//Drifts right and back like the camera always has, easing in and out through the middle
+	animation.camera.origin = Some(Track::new(vec![
+		Keyframe::new(1.0, alg::Vector3::new(0.0, 0.0, 0.0), Interpolation::CatmullRom),
+		Keyframe::new(60.0, alg::Vector3::new(3.0, 0.5, 3.0), Interpolation::CatmullRom),
+		Keyframe::new(120.0, alg::Vector3::new(6.0, 0.0, 6.0), Interpolation::CatmullRom),
+	])?);
+
+	//The green sphere hops once
+	let mut hop = ObjectAnimation::new();
+	hop.translation = Some(Track::new(vec![
+		Keyframe::new(1.0, alg::Vector3::new(0.0, 0.0, 0.0), Interpolation::Bezier(alg::Vector3::new(0.0, 0.6, 0.0), alg::Vector3::new(0.0, 0.6, 0.0))),
+		Keyframe::new(30.0, alg::Vector3::new(0.0, 0.0, 0.0), Interpolation::Linear),
+	])?);
+	animation.objects.push((3, hop));
+
+	//The metal sphere goes from a mirror to brushed
+	let mut brush = ObjectAnimation::new();
+	brush.material = Some(Track::new(vec![
+		Keyframe::new(1.0, 0.0, Interpolation::Linear),
+		Keyframe::new(120.0, 0.4, Interpolation::Linear),
+	])?);
+	animation.objects.push((2, brush));
+
+	Ok(animation)
+}
 
-			let triangle = Triangle::new(verts, normal, alg::Vector3::new(0.0, 1.0, 1.0), Box::new(Diffuse::new()));
-			//scene1.hitables.push(Box::new(triangle));
+//Move everything in the scene in to the chosen acceleration structure, bounding everything over the time the shutter's open
+fn accelerate(scene1 : Scene<'static>, acceleration : Acceleration) -> Scene<'static> {
+	let mut scene = Scene::new();
+	match acceleration {
+		Acceleration::None => {
+			return scene1;
+		},
+		Acceleration::Bvh => {
+			let bvh = BVHNode::new(scene1.hitables, SHUTTER_OPEN, SHUTTER_CLOSE);
+			BVHStats::gather(&bvh).print("Scene BVH");
+			scene.hitables.push(Box::new(bvh));
+		},
+		Acceleration::Grid => {
+			let grid = Grid::new(scene1.hitables, SHUTTER_OPEN, SHUTTER_CLOSE);
+			BVHStats::gather(&grid).print("Scene grid");
+			scene.hitables.push(Box::new(grid));
+		},
+		Acceleration::KdTree => {
+			let kdtree = KdTree::new(scene1.hitables, SHUTTER_OPEN, SHUTTER_CLOSE);
+			BVHStats::gather(&kdtree).print("Scene kd-tree");
+			scene.hitables.push(Box::new(kdtree));
+		},
+	}
+
+	scene.lights = scene1.lights;
+	scene.environment = scene1.environment;
+	scene
+}
+
+//The scene in the acceleration structure picked by ACCELERATION
+fn accelerate_configured(scene : Scene<'static>) -> Scene<'static> {
+	accelerate(scene, ACCELERATION)
+}
+
+//Builds every acceleration structure over the same scene and reports how fast each one traces one primary ray per pixel
+fn benchmark_acceleration(camera : &Camera, width : usize, height : usize) {
+	for acceleration in [Acceleration::None, Acceleration::Bvh, Acceleration::Grid, Acceleration::KdTree].iter() {
+		let build_start = Instant::now();
+		let scene = accelerate(create_scene().build().unwrap_or_else(|why| panic!("Couldn't build the scene: {}", why)), *acceleration);
+		let build_time = build_start.elapsed();
+
+		let trace_start = Instant::now();
+		let mut hits = 0;
+		for _pass in 0..BENCHMARK_PASSES {
+			hits += (0..height).into_par_iter().map(|y| {
+				(0..width).filter(|x| {
+					let u = (*x as f32 + 0.5) / width as f32;
+					let v = (y as f32 + 0.5) / height as f32;
+					scene.hit(&camera.get_ray(u, v), 0.00001, 999.9).is_some()
+				}).count()
+			}).sum::<usize>();
 		}
+		let trace_time = trace_start.elapsed();
+
+		let rays = width * height * BENCHMARK_PASSES;
+		let seconds = trace_time.as_secs() as f64 + trace_time.subsec_nanos() as f64 / 1000000000.0;
+		println!("{:?}: built in {}ms, {} rays ({} hits) in {:.3}s, {:.0} rays/second",
+			acceleration, build_time.as_secs() * 1000 + build_time.subsec_millis() as u64, rays, hits, seconds, rays as f64 / seconds);
+	}
+}
+
+//The value after a command line flag, like 600 for --time 600
+fn argument_value<'a>(args : &'a [String], flag : &str) -> Option<&'a str> {
+	let position = args.iter().position(|arg| arg == flag)?;
+	args.get(position + 1).map(|value| value.as_str())
+}
+
+fn main()
+{	
+	let upscale = 1.0;
+	let width = (1920_f32 * upscale) as usize;
+	let height = (1080_f32 * upscale) as usize;
+	let zoom = 0.5;
+	let fov = width as f32 / 1280.0 * zoom;
+	let aspect = width as f32 / height as f32;
+	let lower_left_corner = alg::Vector3::new(-5.0 * zoom, -2.0 * zoom, -10.0);
+	let horizontal = alg::Vector3::new(4.0 / upscale * aspect * fov, 0.0, 0.0);
+	let vertical = alg::Vector3::new(0.0, 4.0 / upscale * fov, 0.0);
+	let origin = alg::Vector3::new(0.0, 0.0, 0.0);
+
+	let camera = Camera::new(origin, lower_left_corner, horizontal, vertical);
+
+	if BENCHMARK_ACCELERATION {
+		benchmark_acceleration(&camera, width, height);
+		return;
 	}
 
-	let mut scene = Scene{hitables : Vec::new()};
-	if USE_BVH {
-		scene.hitables.push(Box::new(BVHNode::new(scene1.hitables, 0.0, 999.9)));
+	let mut samples = match INTEGRATOR {
+		Integrator::AmbientOcclusion | Integrator::Whitted => PREVIEW_SAMPLES,
+		_ => 512,
+	};
+	let mut adaptive_max_samples = ADAPTIVE_MAX_SAMPLES;
+
+	let args : Vec<String> = std::env::args().collect();
+	let resume = args.iter().any(|arg| arg == "--resume");
+	let progressive = PROGRESSIVE || args.iter().any(|arg| arg == "--progressive");
+	let mut time_limit = TIME_LIMIT;
+	if let Some(seconds) = argument_value(&args, "--time") {
+		match seconds.parse::<f32>() {
+			Ok(seconds) => time_limit = seconds,
+			Err(_) => panic!("--time needs a number of seconds after it"),
+		}
 	}
-	else {
-		scene = scene1;
+	if let Some(count) = argument_value(&args, "--samples") {
+		match count.parse::<i32>() {
+			Ok(count) => {
+				samples = count;
+				adaptive_max_samples = count;
+			},
+			Err(_) => panic!("--samples needs a number after it"),
+		}
 	}
 
-	let mut samples = 512;
-	if DEBUG_NORMALS {
-		samples = 1;
+	let settings = RenderSettings {
+		width,
+		height,
+		integrator : INTEGRATOR,
+		samples,
+		adaptive_sampling : ADAPTIVE_SAMPLING,
+		adaptive_min_samples : ADAPTIVE_MIN_SAMPLES,
+		adaptive_max_samples,
+		adaptive_batch_samples : ADAPTIVE_BATCH_SAMPLES,
+		adaptive_noise_threshold : ADAPTIVE_NOISE_THRESHOLD,
+		sampler : SAMPLER,
+		seed : SEED,
+		filter : Filter::new(FILTER_KIND, FILTER_RADIUS),
+		tile_size : TILE_SIZE,
+		tile_order : TILE_ORDER,
+		flush_interval : FLUSH_INTERVAL,
+		progressive,
+		time_limit,
+		progressive_noise_threshold : PROGRESSIVE_NOISE_THRESHOLD,
+		checkpoint_interval : CHECKPOINT_INTERVAL,
+		shutter_open : SHUTTER_OPEN,
+		shutter_close : SHUTTER_CLOSE,
+		debug_heatmap : DEBUG_HEATMAP,
+		heatmap_max_tests : HEATMAP_MAX_TESTS,
+	};
+
+	//--serve address:port renders scenes sent over HTTP instead, see server.rs
+	if let Some(address) = argument_value(&args, "--serve") {
+		serve(address, settings, camera.clone(), accelerate_configured);
+		return;
 	}
 
-	let mut lights : Vec<Light> = Vec::new();
+	let mut first_frame = FIRST_FRAME;
+	let mut last_frame = LAST_FRAME;
+	if let Some(range) = argument_value(&args, "--frames") {
+		let frames : Vec<Option<u32>> = range.splitn(2, '-').map(|frame| frame.parse::<u32>().ok()).collect();
+		match (frames.first().cloned(), frames.get(1).cloned()) {
+			(Some(Some(first)), Some(Some(last))) if first <= last => {
+				first_frame = first;
+				last_frame = last;
+			},
+			(Some(Some(frame)), None) => {
+				first_frame = frame;
+				last_frame = frame;
+			},
+			_ => panic!("--frames needs a frame or a range like 1-120 after it"),
+		}
+	}
 
-	if USE_LIGHTS {
-		lights.push(Light::new(alg::Vector3::<f32>::new(0.0, 0.0, 0.0), 10.0, alg::Vector3::<f32>::new(1.0, 1.0, 1.0)))
+	let description = create_scene();
+	let animation = create_animation().unwrap_or_else(|why| panic!("Couldn't create the animation: {}", why));
+
+	//--worker host:port renders tiles for a coordinator started with --coordinator address:port, which writes the images
+	if let Some(address) = argument_value(&args, "--worker") {
+		work(address, &description, &animation, &settings, accelerate_configured);
+		return;
+	}
+	let mut listener : Option<TcpListener> = None;
+	if let Some(address) = argument_value(&args, "--coordinator") {
+		match TcpListener::bind(address) {
+			Ok(bound) => listener = Some(bound),
+			Err(why) => panic!("Couldn't listen on {}: {}", address, why),
+		}
+		if resume || progressive {
+			println!("Distributed renders always start from scratch and aren't progressive");
+		}
 	}
 
-	for frames in 0..1 {
-		let raw_colours: Vec<Vec<alg::Vector3<f32>>> = (0..height).into_par_iter().map(|y| {
-			let row: Vec<alg::Vector3<f32>> = (0..width).into_par_iter().map(|x| {
-				let mut rng = rand::thread_rng();
-				let mut col  = alg::Vector3::new(0.0, 0.0, 0.0);
-				for _s in 0..samples {
-					let rand_u : f32 = rng.gen();
-					let rand_v : f32 = rng.gen();
-
-					let u : f32 = (x as f32 + rand_u) / width as f32;
-					let v : f32 = (y as f32 + rand_v) / height as f32;
-
-					let ray = Ray::new(origin, lower_left_corner + u*horizontal + v*vertical);
-					let temp_c : alg::Vector3<f32> = colour(ray, &scene, 0);
-					
-					col.x = col.x + temp_c.x;
-					col.y = col.y + temp_c.y;
-					col.z = col.z + temp_c.z;
-				}
-				col = col / samples as f32;
-				col
-			}).collect();
-			if y % ((height as f32 / 100.0) as usize) == 0 {
-				let percent = y as f32 / height as f32;
-				println!("{}%", (percent * 100.0) as i32);
-			}
-			row
-		}).collect();
-
-		let mut pixels : Vec<u8> = Vec::with_capacity(width * height * 3);
-
-		println!("Done raycasting frame{}", frames);
-
-		for y in (0..height).rev() {
-			for x in 0..width {
-				let mut col = raw_colours[y][x];
-				col = alg::Vector3::new((col.x).sqrt(), (col.y).sqrt(), (col.z).sqrt());
-				let ir = (255.99 * col.x) as u8;
-				let ig = (255.99 * col.y) as u8;
-				let ib = (255.99 * col.z) as u8;
-				pixels.push(ir);
-				pixels.push(ig);
-				pixels.push(ib);
+	//Only rebuilt when something in it moves, the coordinator leaves building scenes to its workers
+	let mut scene : Option<Scene> = None;
+	let mut photons : Option<PhotonMapper> = None;
+	for frame in first_frame..last_frame + 1 {
+		let name = format!("frame_{:04}", frame);
+		let camera = animation.camera_at(&camera, frame as f32);
+		if listener.is_none() && (scene.is_none() || animation.moves_objects()) {
+			let built = match animation.scene_at(&description, frame as f32).build() {
+				Ok(built) => accelerate(built, ACCELERATION),
+				Err(why) => panic!("Couldn't build frame {}: {}", frame, why),
+			};
+			photons = None;
+			if let Integrator::PhotonMapping = INTEGRATOR {
+				photons = Some(PhotonMapper::new(&built, SEED, settings.shutter_open, settings.shutter_close, settings.max_samples() as usize));
 			}
+			scene = Some(built);
 		}
 
-		println!("Saving png frame{}", frames);
-		let filename = format!("frame{}.png", frames);
-		let path = Path::new(&filename);
-		let display = path.display();
-
-		println!("Saving png2 frame{}", frames);
-		let file = match File::create(&path) {
-			Err(why) => panic!("Couldn't create {}: {}", display, why.description()),
-			Ok(file) => file,
-		};
+		let mut image = match listener {
+			Some(ref listener) => coordinate(listener, &camera, &settings, &name, frame, frame == last_frame),
+			None => {
+				let scene = scene.as_ref().unwrap();
+				let mut checkpoint : Option<Checkpoint> = None;
+				let checkpoint_path = format!("{}.checkpoint", name);
+				if resume {
+					if Path::new(&checkpoint_path).exists() {
+						println!("Resuming from {}", checkpoint_path);
+						match read_checkpoint(Path::new(&checkpoint_path), &settings) {
+							Ok(loaded) => checkpoint = Some(loaded),
+							Err(why) => panic!("Couldn't resume from {}: {}", checkpoint_path, why),
+						}
+					}
+					else {
+						println!("No {} to resume from, starting {} from scratch", checkpoint_path, name);
+					}
+				}
 
-		println!("Saving png3 frame{}", frames);
-		let writer = BufWriter::new(&file);
-		let out_image = image::png::PNGEncoder::new(writer);
-		let _okay = match out_image.encode(&pixels, width as u32, height as u32, image::ColorType::RGB(8)) {
-			Err(why) => panic!("hmm? {}", why.description()),
-			Ok(okay) => okay,
+				let control = RenderControl::new(CancellationToken::new(), Some(print_progress()), false);
+				render(scene, &camera, &settings, &photons, &name, checkpoint, Some(&control))
+			},
 		};
+		println!("Done raycasting {}", name);
 
-		//per frame scene changes
-		origin.x += 0.1;
-		origin.z += 0.1;
-		println!("Saving png4 frame{}", frames);
+		if DENOISE {
+			println!("Denoising {}", name);
+			let denoised = denoise(&image);
+			image.set_beauty(denoised);
+		}
+		image.save(&name, AOVS, WRITE_EXR);
 	}
 
 	println!("Done");
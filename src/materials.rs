@@ -1,93 +1,313 @@
-
 extern crate nalgebra as alg;
 
+use std::f32::consts::PI;
+
 use ray::*;
 use hits::*;
 use util::*;
 
-pub struct BounceInfo {
-	pub ray : Ray,
-	pub attenuation : alg::Vector3<f32>,
-	pub absorption : f32
+//weight is the BSDF times the cosine term divided by the pdf of the sampled direction. A single sample's weight can go
+//over 1 (glossy lobes do), it's only on average over all directions that a bounce never adds energy
+pub struct BsdfSample {
+	pub direction : alg::Vector3<f32>,
+	pub weight : alg::Vector3<f32>,
+	pub pdf : f32,
+	//perfect mirrors can only be sampled, evaluate and pdf are always zero for them
+	pub specular : bool
 }
 
+//Directions passed to evaluate and pdf point away from the surface, towards wherever the light is coming from
 pub trait Material {
-	fn bounce(&self, ray : &Ray, time_min : f32, time_max : f32, hit_info : &HitInfo, ) -> Option<BounceInfo>;
+	fn sample(&self, ray : &Ray, hit_info : &HitInfo, u : alg::Vector2<f32>) -> Option<BsdfSample>;
+
+	//BSDF times the cosine term
+	fn evaluate(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> alg::Vector3<f32>;
+
+	fn pdf(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> f32;
+
+	fn is_specular(&self) -> bool {
+		false
+	}
+
+	//0 for perfectly smooth up to 1 for completely rough, lets integrators treat glossy surfaces like mirrors
+	fn roughness(&self) -> f32 {
+		1.0
+	}
+
+	//Every perfect mirror and refraction direction with how much light it carries, what a Whitted tracer recurses down.
+	//Glossy materials give their mirror direction so previews still show reflections
+	fn specular_lobes(&self, _ray : &Ray, _hit_info : &HitInfo) -> Vec<BsdfSample> {
+		Vec::new()
+	}
+
+	//For the material id pass, given by whatever built the material so it's the same in every frame and process
+	fn id(&self) -> u32;
 
-	fn box_clone(&self) -> Box<Material + Sync>;
+	fn box_clone(&self) -> Box<dyn Material + Sync>;
 }
 
 //https://users.rust-lang.org/t/solved-is-it-possible-to-clone-a-boxed-trait-object/1714/5
 
 #[derive(Clone)]
 pub struct Diffuse {
-	pub albedo : alg::Vector3<f32>
+	id : u32
 }
 
 #[derive(Clone)]
 pub struct Metal {
-	pub fuzz : f32
+	pub fuzz : f32,
+	id : u32
 }
 
-impl Clone for Box<Material + Sync> {
-	fn clone(&self) -> Box<Material + Sync> {
+//Glass and other clear materials, refractive_index is relative to the air around them
+#[derive(Clone)]
+pub struct Dielectric {
+	pub refractive_index : f32,
+	id : u32
+}
+
+impl Clone for Box<dyn Material + Sync> {
+	fn clone(&self) -> Box<dyn Material + Sync> {
 		self.box_clone()
 	}
 }
 
+//The normal flipped to be on the same side as the ray that hit it
+pub fn facing_normal(ray : &Ray, hit_info : &HitInfo) -> alg::Vector3<f32> {
+	if ray.direction().dot(&hit_info.normal) > 0.0 {
+		return -hit_info.normal;
+	}
+	hit_info.normal
+}
+
 impl Diffuse {
-	pub fn new () -> Diffuse {
+	pub fn new (id : u32) -> Diffuse {
 		Diffuse {
-			albedo : alg::Vector3::new(0.0, 0.0, 0.0)
+			id
 		}
 	}
 }
 
 impl Material for Diffuse {
-	fn bounce(&self, ray : &Ray, time_min : f32, time_max : f32, hit_info : &HitInfo) -> Option<BounceInfo> {
-		let mut rand_normal = hit_info.normal + random_position_in_unit_sphere();
-		rand_normal = alg::normalize(&rand_normal);
-		let target = hit_info.pos + rand_normal;
+	fn sample(&self, ray : &Ray, hit_info : &HitInfo, u : alg::Vector2<f32>) -> Option<BsdfSample> {
+		//Cosine weighted, so the cosine and pdf cancel out and only the albedo is left
+		let normal = facing_normal(ray, hit_info);
+		let local = cosine_sample_hemisphere(u);
+		if local.z <= 0.0 {
+			return None;
+		}
+
+		Some(BsdfSample{direction : to_world(local, normal), weight : hit_info.colour, pdf : local.z / PI, specular : false})
+	}
 
-		let intersect_bias = rand_normal * 0.0001;
-		let direction = alg::normalize(&(target - hit_info.pos - intersect_bias));
-		let out_ray = Ray::new(hit_info.pos + intersect_bias, direction);
+	fn evaluate(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		let cos_theta = direction.dot(&facing_normal(ray, hit_info));
+		if cos_theta <= 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+		hit_info.colour * (cos_theta / PI)
+	}
 
-		Some(BounceInfo{ray : out_ray, attenuation: hit_info.colour, absorption: 0.9})
+	fn pdf(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> f32 {
+		direction.dot(&facing_normal(ray, hit_info)).max(0.0) / PI
 	}
 
-	fn box_clone(&self) ->Box<Material + Sync> {
+	fn id(&self) -> u32 {
+		self.id
+	}
+
+	fn box_clone(&self) ->Box<dyn Material + Sync> {
 		Box::new((*self).clone())
 	}
 }
 
 impl Metal {
-	pub fn new (fuzz : f32) -> Metal {
+	pub fn new (fuzz : f32, id : u32) -> Metal {
 		Metal {
 			fuzz : fuzz,
+			id,
 		}
 	}
+
+	//Fuzz is treated as roughness and turned in to the exponent of a normalised phong lobe around the mirror direction
+	fn exponent(&self) -> f32 {
+		(2.0 / (self.fuzz * self.fuzz) - 2.0).max(1.0)
+	}
 }
 
 pub fn reflect(a : alg::Vector3<f32>, b : alg::Vector3<f32>) -> alg::Vector3<f32> {
-	a - 2.0 * alg::dot(&a, &b) * b
+	a - 2.0 * a.dot(&b) * b
 }
 
 impl Material for Metal {
-	fn bounce(&self, ray : &Ray, time_min : f32, time_max : f32, hit_info : &HitInfo) -> Option<BounceInfo> {
-		let mut reflected = reflect(alg::normalize(&ray.direction()), hit_info.normal + self.fuzz * random_position_in_unit_sphere());
-		reflected = alg::normalize(&reflected);
-		let out_ray = Ray::new(hit_info.pos, reflected);
-		//if alg::dot(&out_ray.direction(), &hit_info.normal) > 0.001 {
-			return Some(BounceInfo{ray : out_ray, attenuation: hit_info.colour, absorption: 0.8});
-		//}
-		//else {
-			//under surface
-		//	return None;
-		//}
-	}
-
-	fn box_clone(&self) ->Box<Material + Sync> {
+	fn sample(&self, ray : &Ray, hit_info : &HitInfo, u : alg::Vector2<f32>) -> Option<BsdfSample> {
+		let normal = facing_normal(ray, hit_info);
+		let mirror = reflect(ray.direction().normalize(), normal).normalize();
+
+		if self.is_specular() {
+			return Some(BsdfSample{direction : mirror, weight : hit_info.colour, pdf : 0.0, specular : true});
+		}
+
+		let exponent = self.exponent();
+		let cos_alpha = u.x.powf(1.0 / (exponent + 1.0));
+		let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+		let phi = 2.0 * PI * u.y;
+		let direction = to_world(alg::Vector3::new(sin_alpha * phi.cos(), sin_alpha * phi.sin(), cos_alpha), mirror);
+
+		let pdf = self.pdf(ray, hit_info, direction);
+		if pdf <= 0.0 {
+			//the lobe pushed it under the surface, it gets absorbed
+			return None;
+		}
+
+		Some(BsdfSample{direction, weight : self.evaluate(ray, hit_info, direction) / pdf, pdf, specular : false})
+	}
+
+	fn evaluate(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		if self.is_specular() {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+
+		let normal = facing_normal(ray, hit_info);
+		let cos_theta = direction.dot(&normal);
+		if cos_theta <= 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+
+		let mirror = reflect(ray.direction().normalize(), normal).normalize();
+		let cos_alpha = direction.dot(&mirror).max(0.0);
+		let exponent = self.exponent();
+		hit_info.colour * ((exponent + 2.0) / (2.0 * PI) * cos_alpha.powf(exponent) * cos_theta)
+	}
+
+	fn pdf(&self, ray : &Ray, hit_info : &HitInfo, direction : alg::Vector3<f32>) -> f32 {
+		if self.is_specular() {
+			return 0.0;
+		}
+
+		let normal = facing_normal(ray, hit_info);
+		if direction.dot(&normal) <= 0.0 {
+			return 0.0;
+		}
+
+		let mirror = reflect(ray.direction().normalize(), normal).normalize();
+		let cos_alpha = direction.dot(&mirror).max(0.0);
+		let exponent = self.exponent();
+		(exponent + 1.0) / (2.0 * PI) * cos_alpha.powf(exponent)
+	}
+
+	fn is_specular(&self) -> bool {
+		self.fuzz <= 0.0
+	}
+
+	fn roughness(&self) -> f32 {
+		self.fuzz.min(1.0)
+	}
+
+	fn specular_lobes(&self, ray : &Ray, hit_info : &HitInfo) -> Vec<BsdfSample> {
+		let mirror = reflect(ray.direction().normalize(), facing_normal(ray, hit_info)).normalize();
+		vec![BsdfSample{direction : mirror, weight : hit_info.colour, pdf : 0.0, specular : true}]
+	}
+
+	fn id(&self) -> u32 {
+		self.id
+	}
+
+	fn box_clone(&self) ->Box<dyn Material + Sync> {
 		Box::new((*self).clone())
 	}
-}
\ No newline at end of file
+}
+
+impl Dielectric {
+	pub fn new (refractive_index : f32, id : u32) -> Dielectric {
+		Dielectric {
+			refractive_index,
+			id,
+		}
+	}
+}
+
+//None for total internal reflection
+pub fn refract(direction : alg::Vector3<f32>, normal : alg::Vector3<f32>, eta : f32) -> Option<alg::Vector3<f32>> {
+	let cos_theta = -direction.dot(&normal);
+	let k = 1.0 - eta * eta * (1.0 - cos_theta * cos_theta);
+	if k < 0.0 {
+		return None;
+	}
+	Some(eta * direction + (eta * cos_theta - k.sqrt()) * normal)
+}
+
+//Schlick's approximation of the fresnel reflectance
+pub fn schlick(cos_theta : f32, refractive_index : f32) -> f32 {
+	let r0 = ((1.0 - refractive_index) / (1.0 + refractive_index)).powi(2);
+	r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+impl Material for Dielectric {
+	//Reflects or refracts with the probability given by the fresnel term, so both only need weighting by the colour
+	fn sample(&self, ray : &Ray, hit_info : &HitInfo, u : alg::Vector2<f32>) -> Option<BsdfSample> {
+		let direction = ray.direction().normalize();
+		let entering = direction.dot(&hit_info.normal) < 0.0;
+		let normal = facing_normal(ray, hit_info);
+		let eta = if entering { 1.0 / self.refractive_index } else { self.refractive_index };
+		let cos_theta = -direction.dot(&normal);
+
+		let out_direction = match refract(direction, normal, eta) {
+			Some(refracted) => {
+				if u.x < schlick(cos_theta, self.refractive_index) {
+					reflect(direction, normal)
+				}
+				else {
+					refracted.normalize()
+				}
+			},
+			None => reflect(direction, normal),
+		};
+
+		Some(BsdfSample{direction : out_direction, weight : hit_info.colour, pdf : 0.0, specular : true})
+	}
+
+	fn evaluate(&self, _ray : &Ray, _hit_info : &HitInfo, _direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		alg::Vector3::new(0.0, 0.0, 0.0)
+	}
+
+	fn pdf(&self, _ray : &Ray, _hit_info : &HitInfo, _direction : alg::Vector3<f32>) -> f32 {
+		0.0
+	}
+
+	fn is_specular(&self) -> bool {
+		true
+	}
+
+	fn roughness(&self) -> f32 {
+		0.0
+	}
+
+	//Both directions at once, split by the fresnel term instead of picking one
+	fn specular_lobes(&self, ray : &Ray, hit_info : &HitInfo) -> Vec<BsdfSample> {
+		let direction = ray.direction().normalize();
+		let entering = direction.dot(&hit_info.normal) < 0.0;
+		let normal = facing_normal(ray, hit_info);
+		let eta = if entering { 1.0 / self.refractive_index } else { self.refractive_index };
+		let reflected = reflect(direction, normal);
+
+		match refract(direction, normal, eta) {
+			Some(refracted) => {
+				let fresnel = schlick(-direction.dot(&normal), self.refractive_index);
+				vec![
+					BsdfSample{direction : reflected, weight : hit_info.colour * fresnel, pdf : 0.0, specular : true},
+					BsdfSample{direction : refracted.normalize(), weight : hit_info.colour * (1.0 - fresnel), pdf : 0.0, specular : true},
+				]
+			},
+			None => vec![BsdfSample{direction : reflected, weight : hit_info.colour, pdf : 0.0, specular : true}],
+		}
+	}
+
+	fn id(&self) -> u32 {
+		self.id
+	}
+
+	fn box_clone(&self) ->Box<dyn Material + Sync> {
+		Box::new((*self).clone())
+	}
+}
@@ -0,0 +1,339 @@
+extern crate nalgebra as alg;
+extern crate tobj;
+
+use std::path::Path;
+use std::f32;
+use std::mem;
+
+use aabb::*;
+use hits::*;
+use ray::*;
+use materials::*;
+use cache::*;
+use stats::*;
+
+//Triangles stored as plain floats so they can be written to and read from the mesh cache without any conversion
+#[derive(Copy, Clone)]
+pub struct PackedTriangle {
+	pub vertices : [[f32; 3]; 3],
+	pub normal : [f32; 3],
+}
+
+//A node of the flattened mesh BVH. The left child of an interior node is always the next node in the list,
+//offset is the index of the right child. For leaves offset is the first triangle and count is how many there are
+#[derive(Clone)]
+pub struct MeshNode {
+	pub bbox : AABB,
+	pub offset : u32,
+	pub count : u32,
+}
+
+pub struct Mesh {
+	pub nodes : Vec<MeshNode>,
+	pub triangles : Vec<PackedTriangle>,
+	pub colour : alg::Vector3<f32>,
+	pub material : Box<dyn Material + Sync>,
+	pub id : u32
+}
+
+const MAX_TRIANGLES_PER_LEAF : usize = 4;
+
+fn to_vector(a : [f32; 3]) -> alg::Vector3<f32> {
+	alg::Vector3::new(a[0], a[1], a[2])
+}
+
+fn triangle_box(triangle : &PackedTriangle) -> AABB {
+	let v = &triangle.vertices;
+	AABB::new(alg::Vector3::new(v[0][0].min(v[1][0].min(v[2][0])), v[0][1].min(v[1][1].min(v[2][1])), v[0][2].min(v[1][2].min(v[2][2]))),
+	alg::Vector3::new(v[0][0].max(v[1][0].max(v[2][0])), v[0][1].max(v[1][1].max(v[2][1])), v[0][2].max(v[1][2].max(v[2][2]))))
+}
+
+fn triangle_centroid(triangle : &PackedTriangle, axis : usize) -> f32 {
+	(triangle.vertices[0][axis] + triangle.vertices[1][axis] + triangle.vertices[2][axis]) / 3.0
+}
+
+//Convert every model in the obj file in to a flat list of triangles, one normal per triangle taken from its first vertex
+pub fn load_obj_triangles(path : &Path) -> Result<Vec<PackedTriangle>, String> {
+	let (models, _materials) = match tobj::load_obj(path) {
+		Ok(obj) => obj,
+		Err(why) => return Err(format!("Couldn't load {}: {:?}", path.display(), why)),
+	};
+
+	let mut triangles : Vec<PackedTriangle> = vec![];
+	for m in models.iter() {
+		let mesh = &m.mesh;
+		if mesh.indices.len() % 3 != 0 || mesh.positions.len() % 3 != 0 {
+			return Err(format!("{} has a model that isn't made of triangles", path.display()));
+		}
+
+		let position = |index : u32| -> [f32; 3] {
+			let i = 3 * index as usize;
+			[mesh.positions[i], mesh.positions[i + 1], mesh.positions[i + 2]]
+		};
+
+		for i in 0..mesh.indices.len() / 3 {
+			let mut normal = [0.0; 3];
+			if !mesh.normals.is_empty() {
+				let n = 3 * mesh.indices[3 * i] as usize;
+				normal = [mesh.normals[n], mesh.normals[n + 1], mesh.normals[n + 2]];
+			}
+
+			triangles.push(PackedTriangle {
+				vertices : [position(mesh.indices[3 * i]), position(mesh.indices[3 * i + 1]), position(mesh.indices[3 * i + 2])],
+				normal,
+			});
+		}
+
+		println!("model '{}': {} vertices, {} triangles", m.name, mesh.positions.len() / 3, mesh.indices.len() / 3);
+	}
+
+	if triangles.is_empty() {
+		return Err(format!("{} has no faces", path.display()));
+	}
+	Ok(triangles)
+}
+
+//Recursively build the flattened BVH over triangles[start..end], sorting along the longest axis and splitting with the SAH like BVHNode
+fn build_node(triangles : &mut [PackedTriangle], start : usize, nodes : &mut Vec<MeshNode>) {
+	let length = triangles.len();
+	let boxes : Vec<AABB> = triangles.iter().map(triangle_box).collect();
+	let bbox = boxes.iter().skip(1).fold(boxes[0].clone(), |a, b| surrounding_box(&a, b));
+
+	let index = nodes.len();
+	nodes.push(MeshNode { bbox : bbox.clone(), offset : start as u32, count : length as u32 });
+	if length <= MAX_TRIANGLES_PER_LEAF {
+		return;
+	}
+
+	let axis = match bbox.longest_axis() {
+		Axis::X => 0,
+		Axis::Y => 1,
+		Axis::Z => 2,
+	};
+	triangles.sort_by(|a, b| triangle_centroid(a, axis).partial_cmp(&triangle_centroid(b, axis)).unwrap());
+	let boxes : Vec<AABB> = triangles.iter().map(triangle_box).collect();
+
+	//Surface area of everything to the right of each split point
+	let mut right_areas = vec![0.0; length];
+	let mut right_box = boxes[length - 1].clone();
+	for i in (1..length).rev() {
+		right_box = surrounding_box(&right_box, &boxes[i]);
+		right_areas[i] = right_box.area();
+	}
+
+	let mut min_sah = f32::MAX;
+	let mut split = length / 2;
+	let mut left_box = boxes[0].clone();
+	for i in 1..length {
+		left_box = surrounding_box(&left_box, &boxes[i - 1]);
+		let sah = i as f32 * left_box.area() + (length - i) as f32 * right_areas[i];
+		if sah < min_sah {
+			min_sah = sah;
+			split = i;
+		}
+	}
+
+	let (left, right) = triangles.split_at_mut(split);
+	build_node(left, start, nodes);
+	let right_index = nodes.len();
+	build_node(right, start + split, nodes);
+
+	nodes[index].offset = right_index as u32;
+	nodes[index].count = 0;
+}
+
+pub fn build_mesh_bvh(triangles : &mut [PackedTriangle]) -> Vec<MeshNode> {
+	assert_ne!(triangles.len(), 0);
+	let mut nodes : Vec<MeshNode> = Vec::with_capacity(2 * triangles.len() / MAX_TRIANGLES_PER_LEAF + 1);
+	build_node(triangles, 0, &mut nodes);
+	nodes
+}
+
+impl Mesh {
+	//Loads the obj through the mesh cache next to it, rebuilding and rewriting the cache if it's missing, stale or corrupted
+	pub fn load(path : &Path, scale : f32, offset : alg::Vector3<f32>, p_colour : alg::Vector3<f32>, p_material : Box<dyn Material + Sync>, p_id : u32) -> Result<Mesh, String> {
+		let cache_path = cache_path_for(path);
+
+		let (mut nodes, mut triangles) = match load_mesh_cache(&cache_path, path) {
+			Ok(cached) => {
+				println!("Loaded mesh cache {}", cache_path.display());
+				cached
+			},
+			Err(why) => {
+				println!("Rebuilding mesh cache {}: {}", cache_path.display(), why);
+				let mut triangles = load_obj_triangles(path)?;
+				let nodes = build_mesh_bvh(&mut triangles);
+				if let Err(why) = save_mesh_cache(&cache_path, path, &nodes, &triangles) {
+					println!("Couldn't write mesh cache {}: {}", cache_path.display(), why);
+				}
+				(nodes, triangles)
+			}
+		};
+
+		//The cache is stored in object space, a uniform scale and offset doesn't change the structure of the tree
+		for triangle in triangles.iter_mut() {
+			for vertex in triangle.vertices.iter_mut() {
+				for axis in 0..3 {
+					vertex[axis] = vertex[axis] * scale + offset[axis];
+				}
+			}
+		}
+
+		//A negative scale mirrors the mesh and swaps which corner is the smallest
+		for node in nodes.iter_mut() {
+			let a = node.bbox.min() * scale + offset;
+			let b = node.bbox.max() * scale + offset;
+			node.bbox = AABB::new(alg::Vector3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)), alg::Vector3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)));
+		}
+
+		let mesh = Mesh {
+			nodes,
+			triangles,
+			colour : p_colour,
+			material : p_material,
+			id : p_id
+		};
+
+		BVHStats::gather(&mesh).print(&format!("Mesh {}", path.display()));
+		Ok(mesh)
+	}
+
+	fn accumulate_node_stats(&self, index : usize, stats : &mut BVHStats, depth : usize) {
+		let node = &self.nodes[index];
+		if node.count == 0 {
+			stats.add_node(&node.bbox, depth, mem::size_of::<MeshNode>());
+			self.accumulate_node_stats(index + 1, stats, depth + 1);
+			self.accumulate_node_stats(node.offset as usize, stats, depth + 1);
+		}
+		else {
+			let size = mem::size_of::<MeshNode>() + node.count as usize * mem::size_of::<PackedTriangle>();
+			stats.add_leaf(&node.bbox, depth, node.count as usize, size);
+		}
+	}
+
+	//Möller–Trumbore, returns the distance along the ray and the geometric normal
+	fn hit_triangle(&self, triangle : &PackedTriangle, ray : &Ray, time_min : f32, time_max : f32) -> Option<(f32, alg::Vector3<f32>)> {
+		count_primitive_test(ray);
+		let v0 = to_vector(triangle.vertices[0]);
+		let edge1 = to_vector(triangle.vertices[1]) - v0;
+		let edge2 = to_vector(triangle.vertices[2]) - v0;
+
+		let p = ray.direction().cross(&edge2);
+		let determinant = edge1.dot(&p);
+		if determinant.abs() < 0.0000001 {
+			return None;
+		}
+
+		let inverse = 1.0 / determinant;
+		let s = ray.origin() - v0;
+		let u = s.dot(&p) * inverse;
+		if !(0.0..=1.0).contains(&u) {
+			return None;
+		}
+
+		let q = s.cross(&edge1);
+		let v = ray.direction().dot(&q) * inverse;
+		if v < 0.0 || u + v > 1.0 {
+			return None;
+		}
+
+		let t = edge2.dot(&q) * inverse;
+		if t < time_max && t > time_min {
+			return Some((t, edge1.cross(&edge2).normalize()));
+		}
+
+		None
+	}
+}
+
+impl Hitable for Mesh {
+	fn hit(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<HitInfo> {
+		let mut closest = time_max;
+		let mut closest_hit : Option<(usize, alg::Vector3<f32>)> = None;
+
+		let mut stack : Vec<usize> = Vec::with_capacity(64);
+		stack.push(0);
+		while let Some(index) = stack.pop() {
+			let node = &self.nodes[index];
+			if !node.bbox.hit(ray, time_min, closest) {
+				continue;
+			}
+
+			if node.count == 0 {
+				stack.push(node.offset as usize);
+				stack.push(index + 1);
+				continue;
+			}
+
+			for i in node.offset as usize..(node.offset + node.count) as usize {
+				if let Some((t, normal)) = self.hit_triangle(&self.triangles[i], ray, time_min, closest) {
+					closest = t;
+					closest_hit = Some((i, normal));
+				}
+			}
+		}
+
+		if let Some((i, geometric_normal)) = closest_hit {
+			let stored_normal = to_vector(self.triangles[i].normal);
+			let mut normal = geometric_normal;
+			if stored_normal != alg::Vector3::new(0.0, 0.0, 0.0) {
+				normal = stored_normal.normalize();
+			}
+
+			return Some(HitInfo {
+				time : closest,
+				pos : ray.point_at_parameter(closest),
+				normal,
+				colour : self.colour,
+				material : self.material.clone(),
+				object_id : self.id
+			});
+		}
+
+		None
+	}
+
+	fn bounding_box(&self, _time_min : f32, _time_max : f32) -> Option<AABB> {
+		Some(self.nodes[0].bbox.clone())
+	}
+
+	fn accumulate_stats(&self, stats : &mut BVHStats, depth : usize) {
+		self.accumulate_node_stats(0, stats, depth);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+
+	fn write_obj(name : &str, contents : &str) -> PathBuf {
+		let path = env::temp_dir().join(name);
+		fs::write(&path, contents).unwrap();
+		let _ = fs::remove_file(cache_path_for(&path));
+		path
+	}
+
+	#[test]
+	fn mirrored_mesh_can_still_be_hit() {
+		let path = write_obj("rust_raytracer_mirrored.obj", "v 0 0 0\nv 1 0 1\nv 0 1 0\nf 1 2 3\n");
+		let mesh = Mesh::load(&path, -1.0, alg::Vector3::new(0.0, 0.0, -2.0), alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Diffuse::new(1)), 1).unwrap();
+		let ray = Ray::new(alg::Vector3::new(-0.25, -0.25, 0.0), alg::Vector3::new(0.0, 0.0, -1.0));
+		let hit = mesh.hit(&ray, 0.0001, 999.9).expect("the mirrored triangle should be hit");
+		assert!((hit.time - 2.25).abs() < 0.0001);
+
+		//The scene's BVH builds its boxes around this one, so its corners have to stay the right way round
+		let bbox = mesh.bounding_box(0.0, 0.0).unwrap();
+		for axis in 0..3 {
+			assert!(bbox.min()[axis] <= bbox.max()[axis]);
+		}
+	}
+
+	#[test]
+	fn obj_without_faces_is_an_error() {
+		let path = write_obj("rust_raytracer_no_faces.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\n");
+		assert!(Mesh::load(&path, 1.0, alg::Vector3::new(0.0, 0.0, 0.0), alg::Vector3::new(1.0, 1.0, 1.0), Box::new(Diffuse::new(1)), 1).is_err());
+	}
+}
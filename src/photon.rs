@@ -0,0 +1,303 @@
+extern crate nalgebra as alg;
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::f32::consts::PI;
+
+use ray::*;
+use hits::*;
+use scene::*;
+use util::*;
+use integrator::*;
+use sampler::*;
+
+pub static PHOTONS_PER_PASS : usize = 200000;
+pub static PHOTON_RADIUS : f32 = 0.1;
+//Instead of one photon map, build a map per sample with a shrinking radius so sample N of every pixel uses map N. The
+//radius keeps shrinking as samples go up, which takes the bias away with them
+pub static PROGRESSIVE_PHOTON_MAPPING : bool = false;
+//Fewer photons per map when there's one for every sample, they all have to fit in memory at once
+pub static PHOTONS_PER_PROGRESSIVE_PASS : usize = 20000;
+//How quickly the radius shrinks between progressive passes, lower shrinks faster
+pub static PHOTON_ALPHA : f32 = 0.7;
+static MAX_PHOTON_BOUNCES : usize = 16;
+//Surfaces smoother than this are followed like mirrors instead of storing photons, density estimates blur glossy reflections
+pub static PHOTON_GLOSSY_ROUGHNESS : f32 = 0.5;
+
+#[derive(Copy, Clone)]
+pub struct Photon {
+	pub pos : alg::Vector3<f32>,
+	//direction the photon was travelling when it landed
+	pub direction : alg::Vector3<f32>,
+	pub power : alg::Vector3<f32>,
+	//axis this photon splits its part of the kd-tree on
+	axis : usize,
+}
+
+//Photons stored as an implicit kd-tree, the middle of every range splits the rest of it in two
+pub struct PhotonMap {
+	photons : Vec<Photon>,
+	pub radius : f32,
+}
+
+fn build_photon_tree(photons : &mut [Photon]) {
+	if photons.len() <= 1 {
+		return;
+	}
+
+	let mut min = photons[0].pos;
+	let mut max = photons[0].pos;
+	for photon in photons.iter() {
+		for axis in 0..3 {
+			min[axis] = min[axis].min(photon.pos[axis]);
+			max[axis] = max[axis].max(photon.pos[axis]);
+		}
+	}
+
+	let extent = max - min;
+	let axis = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+	let mid = photons.len() / 2;
+	photons.select_nth_unstable_by(mid, |a, b| a.pos[axis].partial_cmp(&b.pos[axis]).unwrap_or(Ordering::Equal));
+	photons[mid].axis = axis;
+
+	let (below, rest) = photons.split_at_mut(mid);
+	build_photon_tree(below);
+	build_photon_tree(&mut rest[1..]);
+}
+
+impl PhotonMap {
+	pub fn new(mut photons : Vec<Photon>, radius : f32) -> PhotonMap {
+		build_photon_tree(&mut photons[..]);
+		PhotonMap {
+			photons,
+			radius,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.photons.len()
+	}
+
+	//Calls found for every photon within radius of pos
+	pub fn gather<F : FnMut(&Photon)>(&self, pos : alg::Vector3<f32>, radius : f32, found : &mut F) {
+		self.gather_range(0, self.photons.len(), pos, radius * radius, found);
+	}
+
+	fn gather_range<F : FnMut(&Photon)>(&self, start : usize, end : usize, pos : alg::Vector3<f32>, radius_squared : f32, found : &mut F) {
+		if start >= end {
+			return;
+		}
+
+		let mid = (start + end) / 2;
+		let photon = &self.photons[mid];
+		if squared_length(photon.pos - pos) <= radius_squared {
+			found(photon);
+		}
+
+		if end - start == 1 {
+			return;
+		}
+
+		let distance = pos[photon.axis] - photon.pos[photon.axis];
+		if distance <= 0.0 {
+			self.gather_range(start, mid, pos, radius_squared, found);
+			if distance * distance <= radius_squared {
+				self.gather_range(mid + 1, end, pos, radius_squared, found);
+			}
+		}
+		else {
+			self.gather_range(mid + 1, end, pos, radius_squared, found);
+			if distance * distance <= radius_squared {
+				self.gather_range(start, mid, pos, radius_squared, found);
+			}
+		}
+	}
+}
+
+//Shoot photons out of the lights and store them wherever they land on a non-specular surface after at least one bounce,
+//light arriving straight from the lights is handled by sampling them directly instead
+//Every photon is its own independent sample from stream, so the map comes out the same every time. Like camera samples each
+//one is at its own time while the shutter's open, so caustics from moving objects blur with them
+fn trace_photons(scene : &Scene, count : usize, seed : u32, stream : u32, shutter_open : f32, shutter_close : f32) -> Vec<Photon> {
+	let mut photons : Vec<Photon> = Vec::with_capacity(count);
+	if scene.lights.is_empty() {
+		return photons;
+	}
+
+	let light_pdf = 1.0 / scene.lights.len() as f32;
+	for i in 0..count {
+		start_independent_sample(seed, stream, i as u32);
+		let mut time = shutter_open;
+		if shutter_close > shutter_open {
+			time += next_sample_1d() * (shutter_close - shutter_open);
+		}
+		set_sample_time(time);
+
+		let index = ((next_sample_1d() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+		let light = &scene.lights[index];
+		let (pos, normal, pdf_position) = light.sample_position(next_sample_2d());
+		let (direction, pdf_direction) = light.sample_direction(normal, next_sample_2d());
+		if pdf_direction == 0.0 {
+			continue;
+		}
+
+		let mut cos_theta = 1.0;
+		if !light.is_delta() {
+			cos_theta = normal.dot(&direction).abs();
+		}
+
+		let mut power = light.emitted_towards(normal, direction) * (cos_theta / (light_pdf * pdf_position * pdf_direction * count as f32));
+		let mut ray = Ray::new(pos + direction * 0.0001, direction);
+
+		for bounce in 0..MAX_PHOTON_BOUNCES {
+			let hit = match scene.hit(&ray, 0.00001, 999.9) {
+				Some(hit) => hit,
+				None => break,
+			};
+
+			if bounce > 0 && hit.material.roughness() >= PHOTON_GLOSSY_ROUGHNESS {
+				photons.push(Photon {
+					pos : hit.pos,
+					direction : ray.direction().normalize(),
+					power,
+					axis : 0,
+				});
+			}
+
+			let sample = match hit.material.sample(&ray, &hit, next_sample_2d()) {
+				Some(sample) => sample,
+				None => break,
+			};
+
+			let new_power = multiply_colour(power, sample.weight);
+
+			//Russian roulette on how much of the photon's power survives the bounce
+			let survival = (new_power.x.max(new_power.y.max(new_power.z)) / power.x.max(power.y.max(power.z))).min(1.0);
+			if next_sample_1d() >= survival {
+				break;
+			}
+
+			power = new_power / survival;
+			ray = spawn_ray(&hit, sample.direction);
+		}
+	}
+
+	photons
+}
+
+//Radius of every progressive pass, r(i+1)^2 = r(i)^2 * (i + alpha) / (i + 1)
+pub fn progressive_radii(radius : f32, alpha : f32, passes : usize) -> Vec<f32> {
+	let mut radii : Vec<f32> = vec![radius];
+	for i in 1..passes {
+		let previous = radii[i - 1];
+		radii.push((previous * previous * (i as f32 + alpha) / (i as f32 + 1.0)).sqrt());
+	}
+	radii
+}
+
+//One photon map, or one for every sample of a pixel with progressive photon mapping
+pub struct PhotonMapper {
+	pub maps : Vec<PhotonMap>,
+}
+
+impl PhotonMapper {
+	pub fn new(scene : &Scene, seed : u32, shutter_open : f32, shutter_close : f32, samples_per_pixel : usize) -> PhotonMapper {
+		let (radii, photons) = if PROGRESSIVE_PHOTON_MAPPING {
+			(progressive_radii(PHOTON_RADIUS, PHOTON_ALPHA, samples_per_pixel.max(1)), PHOTONS_PER_PROGRESSIVE_PASS)
+		} else {
+			(vec![PHOTON_RADIUS], PHOTONS_PER_PASS)
+		};
+
+		let maps : Vec<PhotonMap> = radii.into_par_iter().enumerate().map(|(pass, radius)| {
+			PhotonMap::new(trace_photons(scene, photons, seed, pass as u32, shutter_open, shutter_close), radius)
+		}).collect();
+
+		println!("Traced {} photon maps with {} photons", maps.len(), maps.iter().map(|m| m.len()).sum::<usize>());
+		PhotonMapper {
+			maps,
+		}
+	}
+
+	//Density estimate of light reflected back along the ray from the photons near the hit, using the map for this sample
+	fn estimate(&self, ray : &Ray, hit_info : &HitInfo, sample_index : u32) -> alg::Vector3<f32> {
+		let map = &self.maps[(sample_index as usize).min(self.maps.len() - 1)];
+
+		let mut reflected = alg::Vector3::new(0.0, 0.0, 0.0);
+		map.gather(hit_info.pos, map.radius, &mut |photon : &Photon| {
+			let direction = -photon.direction;
+			let cos_theta = direction.dot(&hit_info.normal).abs();
+			if cos_theta > 0.0 {
+				let f = hit_info.material.evaluate(ray, hit_info, direction) / cos_theta;
+				reflected += multiply_colour(f, photon.power);
+			}
+		});
+
+		reflected / (PI * map.radius * map.radius)
+	}
+}
+
+//Follows specular and glossy bounces from the camera, then at the first other surface adds direct light from the lights and sky
+//and everything else from the photon map
+pub fn photon_colour(mut ray : Ray, scene : &Scene, photons : &PhotonMapper, sample_index : u32) -> alg::Vector3<f32> {
+	let mut throughput = alg::Vector3::new(1.0, 1.0, 1.0);
+
+	for _depth in 0..MAX_PHOTON_BOUNCES {
+		let hit = scene.hit(&ray, 0.00001, 999.9);
+		let time_max = match hit {
+			Some(ref hit) => hit.time,
+			None => 999.9,
+		};
+
+		if let Some((index, time)) = scene.hit_light(&ray, 0.00001, time_max) {
+			return multiply_colour(throughput, scene.lights[index].emitted_at(&ray, time));
+		}
+
+		let hit = match hit {
+			Some(hit) => hit,
+			None => return multiply_colour(throughput, background(&ray, scene) + infinite_light_radiance(&ray, scene)),
+		};
+
+		let sample = hit.material.sample(&ray, &hit, next_sample_2d());
+		if hit.material.roughness() < PHOTON_GLOSSY_ROUGHNESS {
+			match sample {
+				Some(sample) => {
+					throughput = multiply_colour(throughput, sample.weight);
+					ray = spawn_ray(&hit, sample.direction);
+					continue;
+				},
+				None => break,
+			}
+		}
+
+		let mut radiance = sample_lights(&ray, &hit, scene, false) + photons.estimate(&ray, &hit, sample_index);
+
+		//The sky isn't a photon source, so one BSDF sample picks up the light coming straight from it, unless it was already
+		//sampled along with the lights
+		if let (Some(sample), false) = (sample, scene.environment.is_importance_sampled()) {
+			let sky_ray = spawn_ray(&hit, sample.direction);
+			if scene.hit(&sky_ray, 0.00001, 999.9).is_none() && scene.hit_light(&sky_ray, 0.00001, 999.9).is_none() {
+				radiance += multiply_colour(sample.weight, background(&sky_ray, scene));
+			}
+		}
+
+		return multiply_colour(throughput, radiance);
+	}
+
+	alg::Vector3::new(0.0, 0.0, 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn progressive_radius_keeps_shrinking() {
+		let radii = progressive_radii(0.1, 0.7, 1024);
+		assert_eq!(radii.len(), 1024);
+		assert_eq!(radii[0], 0.1);
+		for pair in radii.windows(2) {
+			assert!(pair[1] < pair[0], "{} isn't smaller than {}", pair[1], pair[0]);
+		}
+		//r^2 falls off like i^(alpha - 1), so it should be well on its way to zero by now
+		assert!(radii[1023] < 0.05);
+	}
+}
@@ -1,10 +1,26 @@
 
 extern crate nalgebra as alg;
 
+use std::cell::Cell;
+
+//Every ray traced for a sample is at the same point in the shutter, so like the sampler it's set once per sample on each
+//thread and picked up by every ray made after, instead of being passed through all the integrators
+thread_local! {
+	static SAMPLE_TIME : Cell<f32> = const { Cell::new(0.0) };
+}
+
+//Time is in frames from the start of the frame being rendered
+pub fn set_sample_time(time : f32) {
+	SAMPLE_TIME.with(|current| current.set(time));
+}
+
 #[derive(Copy, Clone)]
 pub struct Ray {
 	pub a: alg::Vector3<f32>,
 	pub b: alg::Vector3<f32>,
+	pub time: f32,
+	//Only the heatmap's rays count their box and primitive tests, every other ray skips it
+	pub count_tests: bool,
 }
 
 impl Ray {
@@ -13,6 +29,8 @@ impl Ray {
 		Ray {
 			a: a,
 			b: b,
+			time: SAMPLE_TIME.with(|current| current.get()),
+			count_tests: false,
 		}
 	}
 
@@ -0,0 +1,824 @@
+extern crate nalgebra as alg;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use ray::*;
+use hits::*;
+use scene::*;
+use camera::*;
+use integrator::*;
+use bdpt::*;
+use photon::*;
+use aov::*;
+use film::*;
+use checkpoint::*;
+use filter::*;
+use sampler::*;
+use stats::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Integrator {
+	Path,
+	Bidirectional,
+	PhotonMapping,
+	AmbientOcclusion,
+	Whitted,
+}
+
+//Which tiles get rendered first
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TileOrder {
+	//Left to right, top to bottom
+	Scanline,
+	//Outwards from the middle of the image, where the subject usually is
+	Spiral,
+	//Along a Hilbert curve, so tiles being rendered at the same time are close together and share what's in the caches
+	Hilbert,
+}
+
+//Everything about how an image is rendered, apart from the scene and camera
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+	pub width : usize,
+	pub height : usize,
+	pub integrator : Integrator,
+	pub samples : i32,
+	//Keep sampling each pixel in batches until its noise drops under the threshold, so flat areas finish early and noisy ones get more
+	pub adaptive_sampling : bool,
+	pub adaptive_min_samples : i32,
+	pub adaptive_max_samples : i32,
+	pub adaptive_batch_samples : i32,
+	pub adaptive_noise_threshold : f32,
+	pub sampler : SamplerKind,
+	pub seed : u32,
+	pub filter : Filter,
+	//Tiles are square, apart from the ones at the right and top edges
+	pub tile_size : usize,
+	pub tile_order : TileOrder,
+	//Seconds between writing the unfinished beauty image to disk, 0 only writes it when it's done
+	pub flush_interval : f32,
+	//Sample the whole image one sample per pixel at a time, writing it out as it improves, until the sample count,
+	//the time limit or the noise threshold is reached
+	pub progressive : bool,
+	//Seconds before a progressive render stops, 0 for no limit
+	pub time_limit : f32,
+	//A progressive render stops once the average pixel noise is under this, 0 to ignore it
+	pub progressive_noise_threshold : f32,
+	//Seconds between saving everything accumulated so far to name.checkpoint so the render can be resumed, 0 never saves one.
+	//One is also saved when the render finishes so more samples can be added to it later
+	pub checkpoint_interval : f32,
+	//When the shutter opens and closes, in frames from the start of the frame. Every sample is at its own time in between,
+	//so anything moving blurs. The same open and close is a still with no blur
+	pub shutter_open : f32,
+	pub shutter_close : f32,
+	//Colour pixels by how many box and primitive tests their camera rays needed instead of rendering them
+	pub debug_heatmap : bool,
+	//Number of tests that shows as red in the heatmap
+	pub heatmap_max_tests : u32,
+}
+
+impl RenderSettings {
+	//The most samples any pixel gets
+	pub fn max_samples(&self) -> i32 {
+		if self.debug_heatmap {
+			return 1;
+		}
+		if self.adaptive_sampling {
+			return self.adaptive_max_samples;
+		}
+		self.samples
+	}
+}
+
+//Pixels x0..x1 and y0..y1, y going up like the camera
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tile {
+	pub x0 : usize,
+	pub y0 : usize,
+	pub x1 : usize,
+	pub y1 : usize,
+}
+
+//Position of (x, y) along a Hilbert curve filling an n by n grid, n a power of two
+fn hilbert_index(n : usize, mut x : usize, mut y : usize) -> usize {
+	let mut index = 0;
+	let mut s = n / 2;
+	while s > 0 {
+		let rx = if x & s > 0 { 1 } else { 0 };
+		let ry = if y & s > 0 { 1 } else { 0 };
+		index += s * s * ((3 * rx) ^ ry);
+
+		//Rotate the quadrant so the curve inside it joins up with the next one
+		if ry == 0 {
+			if rx == 1 {
+				x = n - 1 - x;
+				y = n - 1 - y;
+			}
+			::std::mem::swap(&mut x, &mut y);
+		}
+		s /= 2;
+	}
+	index
+}
+
+//Tile coordinates walking out from the middle in a square spiral
+fn spiral_order(x_tiles : usize, y_tiles : usize) -> Vec<(usize, usize)> {
+	let mut order = Vec::with_capacity(x_tiles * y_tiles);
+	let mut x = ((x_tiles as isize) - 1) / 2;
+	let mut y = ((y_tiles as isize) - 1) / 2;
+	let directions = [(1, 0), (0, -1), (-1, 0), (0, 1)];
+	let mut direction = 0;
+	let mut length = 1;
+
+	while order.len() < x_tiles * y_tiles {
+		//Each length is walked twice before it grows, right then down, left then up
+		for _leg in 0..2 {
+			for _step in 0..length {
+				if x >= 0 && y >= 0 && (x as usize) < x_tiles && (y as usize) < y_tiles {
+					order.push((x as usize, y as usize));
+				}
+				x += directions[direction].0;
+				y += directions[direction].1;
+			}
+			direction = (direction + 1) % 4;
+		}
+		length += 1;
+	}
+
+	order
+}
+
+pub fn tiles(width : usize, height : usize, tile_size : usize, order : TileOrder) -> Vec<Tile> {
+	let tile_size = tile_size.max(1);
+	let x_tiles = width.div_ceil(tile_size);
+	let y_tiles = height.div_ceil(tile_size);
+
+	//Tile rows counted from the top, so every order starts at the top of the image like it's viewed
+	let mut coordinates : Vec<(usize, usize)> = Vec::with_capacity(x_tiles * y_tiles);
+	match order {
+		TileOrder::Scanline => {
+			for row in 0..y_tiles {
+				for column in 0..x_tiles {
+					coordinates.push((column, row));
+				}
+			}
+		},
+		TileOrder::Spiral => coordinates = spiral_order(x_tiles, y_tiles),
+		TileOrder::Hilbert => {
+			let n = x_tiles.max(y_tiles).next_power_of_two();
+			for row in 0..y_tiles {
+				for column in 0..x_tiles {
+					coordinates.push((column, row));
+				}
+			}
+			coordinates.sort_by_key(|&(column, row)| hilbert_index(n, column, row));
+		},
+	}
+
+	coordinates.into_iter().map(|(column, row)| {
+		let y1 = height - row * tile_size;
+		Tile {
+			x0 : column * tile_size,
+			y0 : y1.saturating_sub(tile_size),
+			x1 : ((column + 1) * tile_size).min(width),
+			y1,
+		}
+	}).collect()
+}
+
+pub fn radiance(ray : Ray, scene : &Scene, integrator : Integrator, photons : &Option<PhotonMapper>, sample_index : u32) -> alg::Vector3<f32> {
+	match integrator {
+		Integrator::Path => colour(ray, scene),
+		Integrator::Bidirectional => bidirectional_colour(ray, scene),
+		Integrator::PhotonMapping => photon_colour(ray, scene, photons.as_ref().unwrap(), sample_index),
+		Integrator::AmbientOcclusion => ambient_occlusion(ray, scene),
+		Integrator::Whitted => whitted_colour(ray, scene, 0),
+	}
+}
+
+//Colours the pixel by how many box and primitive tests its primary ray needed
+pub fn heatmap(mut ray : Ray, scene : &Scene, max_tests : u32) -> alg::Vector3<f32> {
+	reset_traversal_counts();
+	ray.count_tests = true;
+	scene.hit(&ray, 0.00001, 999.9);
+	let (box_tests, primitive_tests) = traversal_counts();
+	heatmap_colour(box_tests + primitive_tests, max_tests)
+}
+
+//One camera sample with every pass filled in, the path tracer also splits its light in to emission, direct and indirect.
+//sample_index is which of the pixel's samples it is
+pub fn render_sample(ray : Ray, scene : &Scene, settings : &RenderSettings, photons : &Option<PhotonMapper>, sample_index : u32) -> AovSample {
+	if settings.debug_heatmap {
+		let beauty = heatmap(ray, scene, settings.heatmap_max_tests);
+		let mut sample = AovSample::new(&ray, scene);
+		sample.beauty = beauty;
+		return sample;
+	}
+
+	let mut sample = AovSample::new(&ray, scene);
+	match settings.integrator {
+		Integrator::Path => {
+			sample.light = colour_split(ray, scene);
+			sample.beauty = sample.light.total();
+		},
+		_ => sample.beauty = radiance(ray, scene, settings.integrator, photons, sample_index),
+	}
+	sample
+}
+
+//A finished tile's pixels, bottom row first, and its part of the film
+pub struct RenderedTile {
+	pub tile : Tile,
+	pub pixels : Vec<AovPixel>,
+	pub film_tile : FilmTile,
+	//New samples taken in this tile
+	pub samples : u64,
+}
+
+//How far a pass takes every tile in it
+pub struct TilePass<'a> {
+	//The image the pixels carry on from, if there is one
+	pub start : Option<&'a AovImage>,
+	//The sample count the samplers are made for
+	pub sampler_samples : u32,
+	//No pixel goes past this, which is how progressive passes add one sample at a time
+	pub sample_limit : i32,
+	//Set when the render is stopped early
+	pub stop : &'a AtomicBool,
+}
+
+//Pixels carry on from the samples they already have in the pass's start image until they reach the target or are quiet
+//enough. Once the pass is stopped the rows left keep the samples they started with, so a cancelled render still gets back
+//everything it finished
+pub fn render_tile(tile : Tile, scene : &Scene, camera : &Camera, settings : &RenderSettings, photons : &Option<PhotonMapper>, pass : &TilePass) -> RenderedTile {
+	let mut film_tile = FilmTile::new(settings.width, settings.height, settings.filter, tile.x0, tile.y0, tile.x1, tile.y1);
+	let mut pixels : Vec<AovPixel> = Vec::with_capacity((tile.x1 - tile.x0) * (tile.y1 - tile.y0));
+	let mut new_samples = 0;
+
+	let adaptive = settings.adaptive_sampling && !settings.debug_heatmap;
+	let max_samples = settings.max_samples().min(pass.sample_limit);
+	let mut first_batch = max_samples;
+	if adaptive {
+		first_batch = settings.adaptive_min_samples;
+	}
+
+	for y in tile.y0..tile.y1 {
+		let stopped = pass.stop.load(Ordering::Relaxed);
+		for x in tile.x0..tile.x1 {
+			let mut pixel = match pass.start {
+				Some(image) => image.pixel(x, y),
+				None => AovPixel::new(),
+			};
+
+			loop {
+				let taken = pixel.samples() as i32;
+				if stopped || taken >= max_samples || (adaptive && taken >= first_batch && pixel.noise() < settings.adaptive_noise_threshold) {
+					break;
+				}
+
+				let mut batch = max_samples - taken;
+				if taken < first_batch {
+					batch = batch.min(first_batch - taken);
+				}
+				else if adaptive {
+					batch = batch.min(settings.adaptive_batch_samples);
+				}
+
+				for _s in 0..batch {
+					let sample_index = pixel.samples();
+					start_pixel_sample(settings.sampler, settings.seed, pass.sampler_samples, x as u32, y as u32, sample_index);
+					let jitter = next_sample_2d();
+					let mut time = settings.shutter_open;
+					if settings.shutter_close > settings.shutter_open {
+						time += next_sample_1d() * (settings.shutter_close - settings.shutter_open);
+					}
+					set_sample_time(time);
+
+					let u : f32 = (x as f32 + jitter.x) / settings.width as f32;
+					let v : f32 = (y as f32 + jitter.y) / settings.height as f32;
+
+					let ray = camera.get_ray(u, v);
+					let sample = render_sample(ray, scene, settings, photons, sample_index);
+					film_tile.add_sample(x as f32 + jitter.x, y as f32 + jitter.y, &sample);
+					pixel.add(&sample);
+				}
+				new_samples += batch as u64;
+			}
+			pixels.push(pixel);
+		}
+	}
+
+	RenderedTile {
+		tile,
+		pixels,
+		film_tile,
+		samples : new_samples,
+	}
+}
+
+//The image so far with the film's filtered light resolved in to it, tiles that aren't done yet stay black
+fn resolve_image(image : &AovImage, film : &Film) -> AovImage {
+	let mut resolved = image.clone();
+	film.resolve(&mut resolved);
+	resolved
+}
+
+//A checkpoint that can't be written shouldn't stop the render, the last good one is still there
+fn save_checkpoint(path : &Path, settings : &RenderSettings, sampler_samples : u32, image : &AovImage, film : &Film) {
+	println!("Saving {}", path.display());
+	if let Err(why) = write_checkpoint(path, settings, sampler_samples, image, film) {
+		println!("Couldn't write {}: {}", path.display(), why);
+	}
+}
+
+fn seconds(duration : Duration) -> f32 {
+	duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1000000000.0
+}
+
+//Average over the pixels of how visible their noise still is, infinite until every pixel has two samples
+fn mean_noise(image : &AovImage) -> f32 {
+	let total : f32 = image.pixels.iter().map(|pixel| pixel.noise()).sum();
+	total / image.pixels.len().max(1) as f32
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderProgress {
+	//In the current pass, progressive renders go through every tile once per pass
+	pub finished_tiles : usize,
+	pub total_tiles : usize,
+	//Pixels in the finished tiles, also just for the current pass
+	pub finished_pixels : usize,
+	pub total_pixels : usize,
+	//Counting from 1, always 1 of 1 when the render isn't progressive
+	pub pass : i32,
+	pub passes : i32,
+	//New samples taken since the render started
+	pub samples : u64,
+	pub elapsed_seconds : f32,
+	//Estimated seconds left, None until there's something to go on
+	pub remaining_seconds : Option<f32>,
+}
+
+impl RenderProgress {
+	pub fn new() -> RenderProgress {
+		RenderProgress {
+			finished_tiles : 0,
+			total_tiles : 0,
+			finished_pixels : 0,
+			total_pixels : 0,
+			pass : 0,
+			passes : 0,
+			samples : 0,
+			elapsed_seconds : 0.0,
+			remaining_seconds : None,
+		}
+	}
+
+	//From 0 to 1, assuming a progressive render goes all the way to its sample count
+	pub fn fraction(&self) -> f32 {
+		let pass_fraction = self.finished_pixels as f32 / self.total_pixels.max(1) as f32;
+		((self.pass - 1).max(0) as f32 + pass_fraction) / self.passes.max(1) as f32
+	}
+}
+
+//Shared with whatever wants to stop a render, like a GUI's cancel button. Renders check it between tiles and between the
+//rows of a tile, and return the image as it is when they stop
+#[derive(Clone)]
+pub struct CancellationToken {
+	cancelled : Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+	pub fn new() -> CancellationToken {
+		CancellationToken {
+			cancelled : Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+	}
+}
+
+pub type ProgressCallback = Box<dyn Fn(&RenderProgress) + Send + Sync>;
+
+//Lets other threads follow a render and stop it. The callback is called on the render's own thread every time a tile
+//finishes, so it should be quick. With keep_preview the unfinished image goes to the control instead of to disk
+pub struct RenderControl {
+	pub cancellation : CancellationToken,
+	callback : Option<ProgressCallback>,
+	progress : Mutex<RenderProgress>,
+	keep_preview : bool,
+	//The resolved beauty so far as 8 bit RGB, top row first
+	preview : Mutex<Option<Vec<u8>>>,
+}
+
+impl RenderControl {
+	pub fn new(cancellation : CancellationToken, callback : Option<ProgressCallback>, keep_preview : bool) -> RenderControl {
+		RenderControl {
+			cancellation,
+			callback,
+			progress : Mutex::new(RenderProgress::new()),
+			keep_preview,
+			preview : Mutex::new(None),
+		}
+	}
+
+	pub fn cancel(&self) {
+		self.cancellation.cancel();
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancellation.is_cancelled()
+	}
+
+	//The latest progress, for threads that would rather ask than be called back
+	pub fn progress(&self) -> RenderProgress {
+		*self.progress.lock().unwrap()
+	}
+
+	fn report(&self, progress : &RenderProgress) {
+		*self.progress.lock().unwrap() = *progress;
+		if let Some(ref callback) = self.callback {
+			callback(progress);
+		}
+	}
+
+	pub fn preview(&self) -> Option<Vec<u8>> {
+		self.preview.lock().unwrap().clone()
+	}
+
+	fn set_preview(&self, image : &AovImage) {
+		*self.preview.lock().unwrap() = Some(image.pass_pixels(Aov::Beauty));
+	}
+}
+
+//Prints the progress whenever it goes up by a percent, with how long is left
+pub fn print_progress() -> ProgressCallback {
+	let last_percent = Mutex::new(-1);
+	Box::new(move |progress : &RenderProgress| {
+		let percent = (progress.fraction() * 100.0) as i32;
+		let mut last_percent = last_percent.lock().unwrap();
+		if percent == *last_percent {
+			return;
+		}
+		*last_percent = percent;
+		match progress.remaining_seconds {
+			Some(remaining) => println!("{}%, {} samples, {:.0}s left", percent, progress.samples, remaining),
+			None => println!("{}%", percent),
+		}
+	})
+}
+
+//An image being rendered and what's needed to write it out along the way
+pub struct RenderState<'a> {
+	settings : &'a RenderSettings,
+	name : &'a str,
+	checkpoint_path : PathBuf,
+	image : AovImage,
+	film : Film,
+	//Whether the image has samples new tiles have to carry on from
+	started : bool,
+	pub sampler_samples : u32,
+	start_time : Instant,
+	last_flush : Instant,
+	last_checkpoint : Instant,
+	control : Option<&'a RenderControl>,
+	progress : RenderProgress,
+	//Finished tiles wait here until every tile before them in the pass has been merged. Tiles overlap by the filter radius
+	//and float addition depends on order, so merging in tile order keeps the image the same whichever thread finishes first
+	pending : BTreeMap<usize, RenderedTile>,
+	next_merge : usize,
+}
+
+impl<'a> RenderState<'a> {
+	//Starts from the checkpoint if there is one, otherwise from an image with no samples
+	pub fn new(settings : &'a RenderSettings, name : &'a str, resume : Option<Checkpoint>, control : Option<&'a RenderControl>) -> RenderState<'a> {
+		let (image, film, started, sampler_samples) = match resume {
+			Some(checkpoint) => (checkpoint.image, checkpoint.film, true, checkpoint.sampler_samples),
+			None => (AovImage::new(settings.width, settings.height), Film::new(settings.width, settings.height), false, settings.max_samples() as u32),
+		};
+
+		let now = Instant::now();
+		RenderState {
+			settings,
+			name,
+			checkpoint_path : PathBuf::from(format!("{}.checkpoint", name)),
+			image,
+			film,
+			started,
+			sampler_samples,
+			start_time : now,
+			last_flush : now,
+			last_checkpoint : now,
+			control,
+			progress : RenderProgress {
+				pass : 1,
+				passes : if settings.progressive { settings.max_samples() } else { 1 },
+				total_pixels : settings.width * settings.height,
+				..RenderProgress::new()
+			},
+			pending : BTreeMap::new(),
+			next_merge : 0,
+		}
+	}
+
+	fn cancelled(&self) -> bool {
+		self.control.is_some_and(|control| control.is_cancelled())
+	}
+
+	//Works out the time left from how long the render's taken so far, which a time limit can cut short
+	fn report_progress(&mut self) {
+		let elapsed = seconds(self.start_time.elapsed());
+		let fraction = self.progress.fraction();
+		self.progress.elapsed_seconds = elapsed;
+		self.progress.remaining_seconds = None;
+		if fraction > 0.0 {
+			let mut remaining = elapsed * (1.0 - fraction) / fraction;
+			if self.settings.progressive && self.settings.time_limit > 0.0 {
+				remaining = remaining.min((self.settings.time_limit - elapsed).max(0.0));
+			}
+			self.progress.remaining_seconds = Some(remaining);
+		}
+
+		if let Some(control) = self.control {
+			control.report(&self.progress);
+		}
+	}
+
+	//The resolved beauty so far goes to the control if it keeps previews, otherwise to name.png
+	fn flush(&mut self) {
+		let resolved = resolve_image(&self.image, &self.film);
+		match self.control {
+			Some(control) if control.keep_preview => control.set_preview(&resolved),
+			_ => resolved.save(self.name, &[Aov::Beauty], false),
+		}
+		self.last_flush = Instant::now();
+	}
+
+	//Tiles are numbered by where they are in the pass's list
+	pub fn add_tile(&mut self, index : usize, rendered : RenderedTile) {
+		self.pending.insert(index, rendered);
+		while let Some(rendered) = self.pending.remove(&self.next_merge) {
+			self.merge_tile(&rendered);
+			self.next_merge += 1;
+		}
+	}
+
+	fn merge_tile(&mut self, rendered : &RenderedTile) {
+		let tile = rendered.tile;
+		self.image.place_tile(tile.x0, tile.y0, tile.x1, tile.y1, &rendered.pixels);
+		self.film.merge(&rendered.film_tile);
+	}
+
+	//Merges whatever's still waiting, still in order, for passes cut short with tiles missing. Ready for the next pass after
+	pub fn end_pass(&mut self) {
+		let pending = ::std::mem::take(&mut self.pending);
+		for (_index, rendered) in pending {
+			self.merge_tile(&rendered);
+		}
+		self.next_merge = 0;
+	}
+
+	//Saves the final checkpoint if there are any and resolves the film in to the image, which is also the control's last preview
+	pub fn finish(self) -> AovImage {
+		if self.settings.checkpoint_interval > 0.0 {
+			save_checkpoint(&self.checkpoint_path, self.settings, self.sampler_samples, &self.image, &self.film);
+		}
+		let mut image = self.image;
+		self.film.resolve(&mut image);
+		if let Some(control) = self.control {
+			if control.keep_preview {
+				control.set_preview(&image);
+			}
+		}
+		image
+	}
+
+	fn out_of_time(&self) -> bool {
+		self.settings.progressive && self.settings.time_limit > 0.0 && seconds(self.start_time.elapsed()) >= self.settings.time_limit
+	}
+
+	//Writes the beauty and checkpoint if it's been long enough since they were last written
+	pub fn save_if_due(&mut self) {
+		if self.settings.flush_interval > 0.0 && seconds(self.last_flush.elapsed()) >= self.settings.flush_interval {
+			self.flush();
+		}
+		if self.settings.checkpoint_interval > 0.0 && seconds(self.last_checkpoint.elapsed()) >= self.settings.checkpoint_interval {
+			save_checkpoint(&self.checkpoint_path, self.settings, self.sampler_samples, &self.image, &self.film);
+			self.last_checkpoint = Instant::now();
+		}
+	}
+
+	//Renders every tile on as many threads as rayon would use, each thread taking the next tile in order when it's free,
+	//with no pixel going past sample_limit. Finished tiles are gathered on this thread. Returns the number of new samples
+	fn render_pass(&mut self, scene : &Scene, camera : &Camera, photons : &Option<PhotonMapper>, tiles : &[Tile], sample_limit : i32) -> u64 {
+		let settings = self.settings;
+		let start = if self.started { Some(self.image.clone()) } else { None };
+		let next_tile = AtomicUsize::new(0);
+		//Set when the time runs out or the render is cancelled, threads stop taking tiles and the rest of the pass is skipped
+		let stop = AtomicBool::new(false);
+		let pass = TilePass {
+			start : start.as_ref(),
+			sampler_samples : self.sampler_samples,
+			sample_limit,
+			stop : &stop,
+		};
+		let (sender, receiver) = mpsc::channel::<(usize, RenderedTile)>();
+		let threads = rayon::current_num_threads().max(1);
+		let mut new_samples = 0;
+
+		thread::scope(|scope| {
+			for _thread in 0..threads {
+				let sender = sender.clone();
+				let next_tile = &next_tile;
+				let pass = &pass;
+				scope.spawn(move || {
+					loop {
+						let index = next_tile.fetch_add(1, Ordering::Relaxed);
+						if index >= tiles.len() || pass.stop.load(Ordering::Relaxed) {
+							break;
+						}
+
+						let rendered = render_tile(tiles[index], scene, camera, settings, photons, pass);
+						if sender.send((index, rendered)).is_err() {
+							break;
+						}
+					}
+				});
+			}
+			drop(sender);
+
+			let mut finished = 0;
+			self.progress.finished_tiles = 0;
+			self.progress.total_tiles = tiles.len();
+			self.progress.finished_pixels = 0;
+			self.report_progress();
+			loop {
+				match receiver.recv_timeout(Duration::from_millis(100)) {
+					Ok((index, rendered)) => {
+						new_samples += rendered.samples;
+						finished += 1;
+						self.progress.finished_tiles = finished;
+						self.progress.finished_pixels += rendered.pixels.len();
+						self.progress.samples += rendered.samples;
+						self.add_tile(index, rendered);
+						self.report_progress();
+					},
+					Err(mpsc::RecvTimeoutError::Timeout) => {},
+					Err(mpsc::RecvTimeoutError::Disconnected) => break,
+				}
+
+				if self.out_of_time() || self.cancelled() {
+					stop.store(true, Ordering::Relaxed);
+				}
+				if finished < tiles.len() {
+					self.save_if_due();
+				}
+			}
+		});
+
+		self.end_pass();
+		self.started = true;
+		new_samples
+	}
+}
+
+//Renders the image in tiles, all at once or in progressive passes of one sample per pixel. The beauty so far is written to
+//name.png every flush_interval seconds and after every progressive pass, and everything accumulated to name.checkpoint every
+//checkpoint_interval seconds. A resumed render carries on from its checkpoint. With a control the render can be followed
+//and cancelled from other threads, a cancelled render returns the image as far as it got
+pub fn render(scene : &Scene, camera : &Camera, settings : &RenderSettings, photons : &Option<PhotonMapper>, name : &str, resume : Option<Checkpoint>, control : Option<&RenderControl>) -> AovImage {
+	let tiles = tiles(settings.width, settings.height, settings.tile_size, settings.tile_order);
+	let mut state = RenderState::new(settings, name, resume, control);
+
+	if !settings.progressive {
+		state.render_pass(scene, camera, photons, &tiles, settings.max_samples());
+	}
+	else {
+		//A resumed render picks up at the pass its least sampled pixel got to
+		let mut pass = state.image.pixels.iter().map(|pixel| pixel.samples() as i32).min().unwrap_or(0);
+		loop {
+			pass += 1;
+			state.progress.pass = pass;
+			let new_samples = state.render_pass(scene, camera, photons, &tiles, pass);
+			let noise = mean_noise(&state.image);
+			println!("Pass {}: {} samples, {:.1}s, noise {:.4}", pass, new_samples, seconds(state.start_time.elapsed()), noise);
+
+			if new_samples == 0 || pass >= settings.max_samples() {
+				println!("Reached {} samples", pass.min(settings.max_samples()));
+				break;
+			}
+			if state.out_of_time() {
+				println!("Reached the time limit of {}s", settings.time_limit);
+				break;
+			}
+			if state.cancelled() {
+				println!("Cancelled");
+				break;
+			}
+			if noise < settings.progressive_noise_threshold {
+				println!("Reached the noise threshold of {}", settings.progressive_noise_threshold);
+				break;
+			}
+
+			//Every pass is a whole image worth showing
+			state.flush();
+			state.save_if_due();
+		}
+	}
+
+	state.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate rayon;
+
+	use super::*;
+	use scene_file::*;
+
+	fn render_on(threads : usize) -> AovImage {
+		let description = parse_scene("
+			sphere 0 0 -3 0.5 1 0.2 0.2 diffuse
+			sphere 0.8 0.2 -3.5 0.4 1 1 1 metal 0.2
+			sphere 0 -100.5 -3 100 0.8 0.8 0.8 diffuse
+			light 0 2 -2 10 1 1 1
+		").unwrap();
+		let scene = description.build().unwrap();
+		let camera = Camera::new(alg::Vector3::new(0.0, 0.0, 0.0), alg::Vector3::new(-1.5, -1.0, -1.0), alg::Vector3::new(3.0, 0.0, 0.0), alg::Vector3::new(0.0, 2.0, 0.0));
+		let settings = RenderSettings {
+			width : 48,
+			height : 32,
+			integrator : Integrator::Path,
+			samples : 4,
+			adaptive_sampling : false,
+			adaptive_min_samples : 4,
+			adaptive_max_samples : 4,
+			adaptive_batch_samples : 4,
+			adaptive_noise_threshold : 0.0,
+			sampler : SamplerKind::Sobol,
+			seed : 1,
+			filter : Filter::new(FilterKind::Gaussian, 1.5),
+			tile_size : 8,
+			tile_order : TileOrder::Spiral,
+			flush_interval : 0.0,
+			progressive : false,
+			time_limit : 0.0,
+			progressive_noise_threshold : 0.0,
+			checkpoint_interval : 0.0,
+			shutter_open : 0.0,
+			shutter_close : 0.0,
+			debug_heatmap : false,
+			heatmap_max_tests : 200,
+		};
+
+		let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+		pool.install(|| render(&scene, &camera, &settings, &None, "thread_count_test", None, None))
+	}
+
+	//Tiles share the pixels along their edges, which mustn't depend on which thread finished first
+	#[test]
+	fn same_image_on_any_number_of_threads() {
+		let one = render_on(1);
+		for threads in [2, 4, 8].iter() {
+			let many = render_on(*threads);
+			for (a, b) in one.pixels.iter().zip(many.pixels.iter()) {
+				let (a, b) = (a.beauty(), b.beauty());
+				assert!(a.x.to_bits() == b.x.to_bits() && a.y.to_bits() == b.y.to_bits() && a.z.to_bits() == b.z.to_bits(), "{} threads rendered {:?} instead of {:?}", threads, b, a);
+			}
+		}
+	}
+
+	//Spiral and Hilbert orders walk a square, so images that aren't one must still get every tile once and nothing else
+	#[test]
+	fn every_order_covers_every_tile_once() {
+		let sizes = [(1, 1, 8), (64, 64, 16), (100, 37, 16), (37, 100, 16), (130, 70, 32), (200, 9, 8), (9, 200, 8), (45, 45, 7)];
+		for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+			for &(width, height, tile_size) in sizes.iter() {
+				let tiles = tiles(width, height, tile_size, *order);
+				assert_eq!(tiles.len(), width.div_ceil(tile_size) * height.div_ceil(tile_size), "{:?} {}x{} has the wrong number of tiles", order, width, height);
+
+				let mut covered = vec![0; width * height];
+				for tile in tiles.iter() {
+					assert!(tile.x0 < tile.x1 && tile.x1 <= width && tile.y0 < tile.y1 && tile.y1 <= height, "{:?} {}x{} has {:?}", order, width, height, tile);
+					for y in tile.y0..tile.y1 {
+						for x in tile.x0..tile.x1 {
+							covered[y * width + x] += 1;
+						}
+					}
+				}
+				assert!(covered.iter().all(|&count| count == 1), "{:?} {}x{} doesn't cover every pixel once", order, width, height);
+			}
+		}
+	}
+}
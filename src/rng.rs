@@ -0,0 +1,35 @@
+//PCG32 (XSH RR) from https://www.pcg-random.org, small and fast with independent streams, so every pixel sample and photon
+//can have its own sequence that doesn't depend on which thread happens to run it
+#[derive(Copy, Clone)]
+pub struct Pcg32 {
+	state : u64,
+	increment : u64,
+}
+
+static PCG_MULTIPLIER : u64 = 6364136223846793005;
+
+impl Pcg32 {
+	pub fn new(seed : u64, stream : u64) -> Pcg32 {
+		let mut rng = Pcg32 {
+			state : 0,
+			increment : (stream << 1) | 1,
+		};
+		rng.next_u32();
+		rng.state = rng.state.wrapping_add(seed);
+		rng.next_u32();
+		rng
+	}
+
+	pub fn next_u32(&mut self) -> u32 {
+		let old = self.state;
+		self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(self.increment);
+		let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+		let rotation = (old >> 59) as u32;
+		xorshifted.rotate_right(rotation)
+	}
+
+	//Uniform in [0, 1)
+	pub fn next_f32(&mut self) -> f32 {
+		(self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+	}
+}
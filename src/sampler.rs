@@ -0,0 +1,496 @@
+extern crate nalgebra as alg;
+
+use std::cell::RefCell;
+
+use rng::*;
+
+//Samplers hand out the random numbers for one camera sample at a time. Every request for a sample moves on to the next
+//dimension, so as long as the integrators ask for them in the same order the pixel, BSDF and light samples of different
+//camera samples line up and the low discrepancy samplers can spread each of them evenly over the pixel's samples.
+//Everything is worked out from the seed, pixel and sample index, so renders come out the same however they're threaded
+pub trait Sampler {
+	//Start sample index of pixel (x, y), going back to the first dimension
+	fn start_sample(&mut self, x : u32, y : u32, index : u32);
+	fn next_1d(&mut self) -> f32;
+	fn next_2d(&mut self) -> alg::Vector2<f32>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerKind {
+	Independent,
+	Stratified,
+	Halton,
+	Sobol,
+}
+
+pub fn create_sampler(kind : SamplerKind, seed : u32, samples_per_pixel : u32) -> Box<dyn Sampler> {
+	match kind {
+		SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+		SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+		SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+		SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+	}
+}
+
+//A sampler along with the kind, seed and sample count it was made for
+type CurrentSampler = (SamplerKind, u32, u32, Box<dyn Sampler>);
+
+//The sampler the current thread is rendering a pixel with, so integrators don't need one passed all the way down
+thread_local! {
+	static CURRENT_SAMPLER : RefCell<Option<CurrentSampler>> = const { RefCell::new(None) };
+}
+
+pub fn start_pixel_sample(kind : SamplerKind, seed : u32, samples_per_pixel : u32, x : u32, y : u32, index : u32) {
+	CURRENT_SAMPLER.with(|current| {
+		let mut current = current.borrow_mut();
+		let rebuild = match *current {
+			Some((current_kind, current_seed, current_samples, _)) => current_kind != kind || current_seed != seed || current_samples != samples_per_pixel,
+			None => true,
+		};
+
+		if rebuild {
+			*current = Some((kind, seed, samples_per_pixel, create_sampler(kind, seed, samples_per_pixel)));
+		}
+
+		if let Some((_, _, _, ref mut sampler)) = *current {
+			sampler.start_sample(x, y, index);
+		}
+	});
+}
+
+//Anything random that isn't part of a camera sample, like tracing a photon, gets its own independent stream instead
+pub fn start_independent_sample(seed : u32, stream : u32, index : u32) {
+	start_pixel_sample(SamplerKind::Independent, seed, 1, stream, 0, index);
+}
+
+fn with_sampler<T, F : FnOnce(&mut dyn Sampler) -> T>(f : F) -> T {
+	CURRENT_SAMPLER.with(|current| {
+		match *current.borrow_mut() {
+			Some((_, _, _, ref mut sampler)) => f(&mut **sampler),
+			None => panic!("Samples were asked for before a pixel or independent sample was started on this thread"),
+		}
+	})
+}
+
+pub fn next_sample_1d() -> f32 {
+	with_sampler(|sampler| sampler.next_1d())
+}
+
+pub fn next_sample_2d() -> alg::Vector2<f32> {
+	with_sampler(|sampler| sampler.next_2d())
+}
+
+//Largest float below 1, samples have to stay in [0, 1)
+const ONE_MINUS_EPSILON : f32 = 0.99999994;
+
+fn to_unit_float(bits : u32) -> f32 {
+	((bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)).min(ONE_MINUS_EPSILON)
+}
+
+//Cheap integer hash (lowbias32) used to give every pixel and dimension its own scrambling
+pub fn hash_u32(mut x : u32) -> u32 {
+	x ^= x >> 16;
+	x = x.wrapping_mul(0x7feb352d);
+	x ^= x >> 15;
+	x = x.wrapping_mul(0x846ca68b);
+	x ^= x >> 16;
+	x
+}
+
+pub fn hash_combine(seed : u32, value : u32) -> u32 {
+	hash_u32(seed ^ value.wrapping_add(0x9e3779b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+}
+
+fn hash_float(seed : u32) -> f32 {
+	to_unit_float(hash_u32(seed))
+}
+
+//Random permutation of 0..length picked by seed, from Kensler's "Correlated Multi-Jittered Sampling"
+fn permute(mut i : u32, length : u32, seed : u32) -> u32 {
+	let mut mask = length.wrapping_sub(1);
+	mask |= mask >> 1;
+	mask |= mask >> 2;
+	mask |= mask >> 4;
+	mask |= mask >> 8;
+	mask |= mask >> 16;
+
+	loop {
+		i ^= seed;
+		i = i.wrapping_mul(0xe170893d);
+		i ^= seed >> 16;
+		i ^= (i & mask) >> 4;
+		i ^= seed >> 8;
+		i = i.wrapping_mul(0x0929eb3f);
+		i ^= seed >> 23;
+		i ^= (i & mask) >> 1;
+		i = i.wrapping_mul(1 | seed >> 27);
+		i = i.wrapping_mul(0x6935fa69);
+		i ^= (i & mask) >> 11;
+		i = i.wrapping_mul(0x74dcb303);
+		i ^= (i & mask) >> 2;
+		i = i.wrapping_mul(0x9e501cc3);
+		i ^= (i & mask) >> 2;
+		i = i.wrapping_mul(0xc860a3df);
+		i &= mask;
+		i ^= i >> 5;
+		if i < length {
+			break;
+		}
+	}
+
+	(i.wrapping_add(seed)) % length
+}
+
+//Where the sampler has got to in the current camera sample
+#[derive(Copy, Clone)]
+struct SampleState {
+	seed : u32,
+	pixel_seed : u32,
+	index : u32,
+	dimension : u32,
+}
+
+impl SampleState {
+	fn new(seed : u32) -> SampleState {
+		SampleState {
+			seed,
+			pixel_seed : 0,
+			index : 0,
+			dimension : 0,
+		}
+	}
+
+	fn start(&mut self, x : u32, y : u32, index : u32) {
+		self.pixel_seed = hash_combine(hash_combine(hash_u32(self.seed), x), y);
+		self.index = index;
+		self.dimension = 0;
+	}
+
+	//A seed unique to this pixel and the dimension about to be used
+	fn next_dimension_seed(&mut self) -> u32 {
+		let seed = hash_combine(self.pixel_seed, self.dimension);
+		self.dimension += 1;
+		seed
+	}
+}
+
+//Plain uniform random numbers, the baseline everything else is compared against. Every pixel gets its own PCG stream
+//and every sample its own starting point in it
+pub struct IndependentSampler {
+	state : SampleState,
+	rng : Pcg32,
+}
+
+impl IndependentSampler {
+	pub fn new(seed : u32) -> IndependentSampler {
+		IndependentSampler {
+			state : SampleState::new(seed),
+			rng : Pcg32::new(0, 0),
+		}
+	}
+}
+
+impl Sampler for IndependentSampler {
+	fn start_sample(&mut self, x : u32, y : u32, index : u32) {
+		self.state.start(x, y, index);
+		self.rng = Pcg32::new(index as u64, self.state.pixel_seed as u64);
+	}
+
+	fn next_1d(&mut self) -> f32 {
+		self.rng.next_f32()
+	}
+
+	fn next_2d(&mut self) -> alg::Vector2<f32> {
+		let x = self.rng.next_f32();
+		let y = self.rng.next_f32();
+		alg::Vector2::new(x, y)
+	}
+}
+
+//Splits every dimension in to one stratum per sample and jitters within it. Each pixel and dimension shuffles which sample
+//gets which stratum, so the dimensions don't correlate. Samples past samples_per_pixel start another round of strata
+pub struct StratifiedSampler {
+	samples_per_pixel : u32,
+	x_strata : u32,
+	y_strata : u32,
+	state : SampleState,
+}
+
+impl StratifiedSampler {
+	pub fn new(seed : u32, samples_per_pixel : u32) -> StratifiedSampler {
+		let samples_per_pixel = samples_per_pixel.max(1);
+		let x_strata = ((samples_per_pixel as f32).sqrt().round() as u32).max(1);
+		let y_strata = samples_per_pixel.div_ceil(x_strata);
+		StratifiedSampler {
+			samples_per_pixel,
+			x_strata,
+			y_strata,
+			state : SampleState::new(seed),
+		}
+	}
+
+	fn stratum(&mut self, count : u32) -> (u32, u32) {
+		let round = self.state.index / self.samples_per_pixel;
+		let seed = hash_combine(self.state.next_dimension_seed(), round);
+		(permute(self.state.index % self.samples_per_pixel, count, seed), seed)
+	}
+}
+
+impl Sampler for StratifiedSampler {
+	fn start_sample(&mut self, x : u32, y : u32, index : u32) {
+		self.state.start(x, y, index);
+	}
+
+	fn next_1d(&mut self) -> f32 {
+		let count = self.samples_per_pixel;
+		let (stratum, seed) = self.stratum(count);
+		let jitter = hash_float(hash_combine(seed, stratum));
+		((stratum as f32 + jitter) / count as f32).min(ONE_MINUS_EPSILON)
+	}
+
+	fn next_2d(&mut self) -> alg::Vector2<f32> {
+		let count = self.x_strata * self.y_strata;
+		let (stratum, seed) = self.stratum(count);
+		let jitter_x = hash_float(hash_combine(seed, stratum * 2));
+		let jitter_y = hash_float(hash_combine(seed, stratum * 2 + 1));
+		alg::Vector2::new(
+			(((stratum % self.x_strata) as f32 + jitter_x) / self.x_strata as f32).min(ONE_MINUS_EPSILON),
+			(((stratum / self.x_strata) as f32 + jitter_y) / self.y_strata as f32).min(ONE_MINUS_EPSILON))
+	}
+}
+
+static PRIMES : [u32; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+//Digits of index in base mirrored around the decimal point, with every digit shuffled by a permutation that depends on
+//seed and the digits before it (Owen scrambling). Keeps going past the last digit of index until f32 precision runs out,
+//because the scrambled zeros still count. Going on to f64 precision would overflow reversed for the larger bases
+fn scrambled_radical_inverse(base : u32, mut index : u32, seed : u32) -> f32 {
+	let inverse_base = 1.0 / base as f64;
+	let mut inverse_base_n : f64 = 1.0;
+	let mut reversed : u64 = 0;
+	while 1.0 - (base - 1) as f32 * (inverse_base_n as f32) < 1.0 {
+		let next = index / base;
+		let digit = index - next * base;
+		let digit_seed = hash_combine(seed, (reversed ^ (reversed >> 32)) as u32);
+		reversed = reversed * base as u64 + permute(digit, base, digit_seed) as u64;
+		inverse_base_n *= inverse_base;
+		index = next;
+	}
+	((reversed as f64 * inverse_base_n) as f32).min(ONE_MINUS_EPSILON)
+}
+
+//Halton sequence with a prime base per dimension. Each pixel Owen scrambles the digits with its own seed, so neighbouring
+//pixels don't share a pattern and the large bases don't line up with each other. Dimensions past the prime table fall back
+//to hashed random numbers
+pub struct HaltonSampler {
+	state : SampleState,
+}
+
+impl HaltonSampler {
+	pub fn new(seed : u32) -> HaltonSampler {
+		HaltonSampler {
+			state : SampleState::new(seed),
+		}
+	}
+
+	fn sample_dimension(&mut self) -> f32 {
+		let dimension = self.state.dimension as usize;
+		let seed = self.state.next_dimension_seed();
+		if dimension >= PRIMES.len() {
+			return hash_float(hash_combine(seed, self.state.index));
+		}
+
+		scrambled_radical_inverse(PRIMES[dimension], self.state.index, seed)
+	}
+}
+
+impl Sampler for HaltonSampler {
+	fn start_sample(&mut self, x : u32, y : u32, index : u32) {
+		self.state.start(x, y, index);
+	}
+
+	fn next_1d(&mut self) -> f32 {
+		self.sample_dimension()
+	}
+
+	fn next_2d(&mut self) -> alg::Vector2<f32> {
+		let x = self.sample_dimension();
+		let y = self.sample_dimension();
+		alg::Vector2::new(x, y)
+	}
+}
+
+//Owen scrambling by hashing, from Burley's "Practical Hash-based Owen Scrambling"
+fn laine_karras_permutation(mut x : u32, seed : u32) -> u32 {
+	x = x.wrapping_add(seed);
+	x ^= x.wrapping_mul(0x6c50b47c);
+	x ^= x.wrapping_mul(0xb82f1e52);
+	x ^= x.wrapping_mul(0xc7afe638);
+	x ^= x.wrapping_mul(0x8d22f6e6);
+	x
+}
+
+fn nested_uniform_scramble(x : u32, seed : u32) -> u32 {
+	laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+//First two dimensions of the Sobol sequence as 32 bit fractions
+fn sobol_2d(index : u32) -> (u32, u32) {
+	let mut x : u32 = 0;
+	let mut y : u32 = 0;
+	let mut direction_y : u32 = 1 << 31;
+	let mut i = index;
+	let mut bit = 0;
+	while i > 0 {
+		if i & 1 == 1 {
+			x ^= 1 << (31 - bit);
+			y ^= direction_y;
+		}
+		direction_y ^= direction_y >> 1;
+		i >>= 1;
+		bit += 1;
+	}
+	(x, y)
+}
+
+//Owen scrambled Sobol. Every 2D sample comes from the first two Sobol dimensions, which together are a (0, 2) sequence, and
+//each pixel and dimension shuffles the sample order and scrambles the values with its own seed so dimensions stay independent
+pub struct SobolSampler {
+	state : SampleState,
+}
+
+impl SobolSampler {
+	pub fn new(seed : u32) -> SobolSampler {
+		SobolSampler {
+			state : SampleState::new(seed),
+		}
+	}
+
+	fn shuffled_index(&self, seed : u32) -> u32 {
+		nested_uniform_scramble(self.state.index, hash_u32(seed))
+	}
+}
+
+impl Sampler for SobolSampler {
+	fn start_sample(&mut self, x : u32, y : u32, index : u32) {
+		self.state.start(x, y, index);
+	}
+
+	fn next_1d(&mut self) -> f32 {
+		let seed = self.state.next_dimension_seed();
+		let (x, _) = sobol_2d(self.shuffled_index(seed));
+		to_unit_float(nested_uniform_scramble(x, hash_combine(seed, 1)))
+	}
+
+	fn next_2d(&mut self) -> alg::Vector2<f32> {
+		let seed = self.state.next_dimension_seed();
+		let (x, y) = sobol_2d(self.shuffled_index(seed));
+		alg::Vector2::new(
+			to_unit_float(nested_uniform_scramble(x, hash_combine(seed, 1))),
+			to_unit_float(nested_uniform_scramble(y, hash_combine(seed, 2))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	//Which of count equal steps from 0 to 1 each value falls in, checking every step gets exactly one
+	fn one_in_each_step(values : &[f32], count : usize) -> bool {
+		let mut steps = vec![0; count];
+		for value in values.iter() {
+			steps[(value * count as f32) as usize] += 1;
+		}
+		steps.iter().all(|&step| step == 1)
+	}
+
+	//Every way of cutting the square in to count boxes of the same area along powers of two, each with one point in
+	fn one_in_each_elementary_interval(points : &[alg::Vector2<f32>], log2_count : u32) -> bool {
+		(0..log2_count + 1).all(|x_bits| {
+			let (columns, rows) = (1 << x_bits, 1 << (log2_count - x_bits));
+			let mut boxes = vec![0; columns * rows];
+			for point in points.iter() {
+				boxes[(point.y * rows as f32) as usize * columns + (point.x * columns as f32) as usize] += 1;
+			}
+			boxes.iter().all(|&count| count == 1)
+		})
+	}
+
+	//The first count samples of pixel (x, y), as a 1D value from dimension 0 and a 2D point from dimensions 1 and 2
+	fn first_samples(kind : SamplerKind, samples_per_pixel : u32, x : u32, y : u32, count : u32) -> (Vec<f32>, Vec<alg::Vector2<f32>>) {
+		let mut sampler = create_sampler(kind, 9, samples_per_pixel);
+		let mut values = Vec::new();
+		let mut points = Vec::new();
+		for index in 0..count {
+			sampler.start_sample(x, y, index);
+			values.push(sampler.next_1d());
+			points.push(sampler.next_2d());
+		}
+		(values, points)
+	}
+
+	#[test]
+	fn samples_are_between_zero_and_one() {
+		for kind in [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol].iter() {
+			let mut sampler = create_sampler(*kind, 1, 64);
+			for pixel in 0..16 {
+				for index in 0..128 {
+					sampler.start_sample(pixel % 4, pixel / 4, index);
+					//Past the end of the Halton prime table too
+					for _ in 0..20 {
+						let value = sampler.next_1d();
+						let point = sampler.next_2d();
+						for value in [value, point.x, point.y].iter() {
+							assert!(*value >= 0.0 && *value < 1.0, "{:?} gave {}", kind, value);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn first_samples_of_a_pixel_are_stratified() {
+		for pixel in 0..8 {
+			let (values, points) = first_samples(SamplerKind::Sobol, 16, pixel, 3, 16);
+			assert!(one_in_each_step(&values, 16), "Sobol 1D isn't stratified");
+			assert!(one_in_each_elementary_interval(&points, 4), "Sobol 2D isn't stratified");
+
+			//Dimension 0 is base 2 and dimension 1 base 3
+			let (values, _) = first_samples(SamplerKind::Halton, 16, pixel, 3, 16);
+			assert!(one_in_each_step(&values, 16), "Halton base 2 isn't stratified");
+			let (_, points) = first_samples(SamplerKind::Halton, 16, pixel, 3, 27);
+			let base_3 : Vec<f32> = points.iter().map(|point| point.x).collect();
+			assert!(one_in_each_step(&base_3, 27), "Halton base 3 isn't stratified");
+
+			//The strata start over for the second round of samples
+			let (values, points) = first_samples(SamplerKind::Stratified, 16, pixel, 3, 32);
+			for round in 0..2 {
+				assert!(one_in_each_step(&values[round * 16..(round + 1) * 16], 16), "Stratified 1D isn't stratified");
+				let strata : Vec<usize> = points[round * 16..(round + 1) * 16].iter().map(|point| (point.y * 4.0) as usize * 4 + (point.x * 4.0) as usize).collect();
+				assert!((0..16).all(|stratum| strata.iter().filter(|&&s| s == stratum).count() == 1), "Stratified 2D isn't stratified");
+			}
+		}
+	}
+
+	#[test]
+	fn owen_scrambling_keeps_the_stratification() {
+		for seed in 0..32 {
+			let seed = hash_u32(seed);
+			for &(base, count) in [(2, 64), (3, 81), (5, 125), (7, 49)].iter() {
+				let values : Vec<f32> = (0..count).map(|index| scrambled_radical_inverse(base, index, seed)).collect();
+				assert!(one_in_each_step(&values, count as usize), "base {} lost its strata with seed {}", base, seed);
+			}
+
+			let points : Vec<alg::Vector2<f32>> = (0..64).map(|index| {
+				let (x, y) = sobol_2d(index);
+				alg::Vector2::new(to_unit_float(nested_uniform_scramble(x, seed)), to_unit_float(nested_uniform_scramble(y, hash_u32(seed))))
+			}).collect();
+			assert!(one_in_each_elementary_interval(&points, 6), "Sobol lost its strata with seed {}", seed);
+		}
+
+		//Scrambling has to actually move the points, or every pixel would get the same pattern
+		assert!(scrambled_radical_inverse(2, 5, 1) != scrambled_radical_inverse(2, 5, 2));
+		assert!(nested_uniform_scramble(sobol_2d(5).0, 1) != nested_uniform_scramble(sobol_2d(5).0, 2));
+	}
+}
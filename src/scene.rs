@@ -4,14 +4,35 @@ extern crate nalgebra as alg;
 use hits::*;
 use ray::*;
 use aabb::*;
+use light::*;
+use environment::*;
 
 pub struct Scene<'a> {
-	pub hitables : Vec<Box<Hitable + Sync + 'a>>
+	pub hitables : Vec<Box<dyn Hitable + Sync + 'a>>,
+	pub lights : Vec<Box<dyn Light + Sync>>,
+	pub environment : Box<dyn Environment + Sync>,
 }
 
 impl<'a> Scene<'a> {
 	pub fn new() -> Scene<'a> {
-		Scene { hitables : Vec::new() }
+		Scene { hitables : Vec::new(), lights : Vec::new(), environment : Box::new(GradientEnvironment::white_to_red()) }
+	}
+
+	//The closest light the ray hits before time_max, as (index, time)
+	pub fn hit_light(&self, ray : &Ray, time_min : f32, time_max : f32) -> Option<(usize, f32)> {
+		let mut closest : Option<(usize, f32)> = None;
+		for (i, light) in self.lights.iter().enumerate() {
+			let limit = match closest {
+				Some((_, t)) => t,
+				None => time_max,
+			};
+
+			if let Some(t) = light.hit(ray, time_min, limit) {
+				closest = Some((i, t));
+			}
+		}
+
+		closest
 	}
 
 	pub fn add<T : Hitable + Sync + 'a>(&mut self, hitable : T) {
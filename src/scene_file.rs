@@ -0,0 +1,459 @@
+extern crate nalgebra as alg;
+
+use std::path::Path;
+
+use scene::*;
+use shapes::*;
+use materials::*;
+use light::*;
+use mesh::*;
+use camera::*;
+use render::*;
+use hits::*;
+use animation::*;
+use environment::*;
+use sky::*;
+use util::*;
+use sampler::*;
+use filter::*;
+
+//A scene as plain text, one thing per line and # for comments, so it can be sent from other tools:
+//
+//	size 480 270
+//	samples 64
+//	integrator path|bidirectional|photon|ao|whitted
+//	sampler independent|stratified|halton|sobol
+//	filter box|tent|gaussian|mitchell|lanczos radius_in_pixels
+//	tile_order scanline|spiral|hilbert
+//	camera origin_x origin_y origin_z  lower_left_x .. ..  horizontal_x .. ..  vertical_x .. ..
+//	sphere x y z radius r g b material
+//	moving_sphere x y z  to_x to_y to_z radius r g b material   at the first center when the frame starts and the second a frame later
+//	cube x y z extents r g b material
+//	mesh path.obj scale x y z r g b material
+//	light x y z intensity r g b
+//	sphere_light x y z radius intensity r g b
+//	spot_light x y z  dir_x dir_y dir_z  cone_degrees falloff_degrees intensity r g b
+//	directional_light dir_x dir_y dir_z intensity r g b   dir is the way the light travels
+//	rect_light x y z  u_x u_y u_z  v_x v_y v_z intensity r g b   lit on the side u x v points to
+//	environment constant r g b
+//	environment gradient r g b  to_r to_g to_b  axis_x axis_y axis_z
+//	environment map path.hdr|path.exr rotation_degrees intensity
+//	environment sky sun_elevation_degrees sun_azimuth_degrees turbidity sun_radius_degrees intensity
+//
+//where material is diffuse, metal fuzz or dielectric refractive_index. Anything left out keeps the renderer's setting
+
+#[derive(Clone)]
+pub enum MaterialDescription {
+	Diffuse,
+	Metal(f32),
+	Dielectric(f32),
+}
+
+#[derive(Clone)]
+pub enum ObjectDescription {
+	Sphere { center : alg::Vector3<f32>, radius : f32, colour : alg::Vector3<f32>, material : MaterialDescription },
+	MovingSphere { center0 : alg::Vector3<f32>, center1 : alg::Vector3<f32>, radius : f32, colour : alg::Vector3<f32>, material : MaterialDescription },
+	Cube { center : alg::Vector3<f32>, extents : f32, colour : alg::Vector3<f32>, material : MaterialDescription },
+	Mesh { path : String, scale : f32, offset : alg::Vector3<f32>, colour : alg::Vector3<f32>, material : MaterialDescription },
+	Light { center : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32> },
+	SphereLight { center : alg::Vector3<f32>, radius : f32, intensity : f32, colour : alg::Vector3<f32> },
+	SpotLight { center : alg::Vector3<f32>, direction : alg::Vector3<f32>, cone_angle : f32, falloff : f32, intensity : f32, colour : alg::Vector3<f32> },
+	DirectionalLight { direction : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32> },
+	RectLight { corner : alg::Vector3<f32>, edge_u : alg::Vector3<f32>, edge_v : alg::Vector3<f32>, intensity : f32, colour : alg::Vector3<f32> },
+	//Another object moved by keyframes while the frame is rendered, lights can't be instanced
+	Instance { object : Box<ObjectDescription>, pivot : alg::Vector3<f32>, translation : Option<Track<alg::Vector3<f32>>>,
+		rotation : Option<Track<f32>>, scale : Option<Track<f32>>, frame : f32 },
+}
+
+#[derive(Clone)]
+pub enum EnvironmentDescription {
+	Constant(alg::Vector3<f32>),
+	Gradient { start : alg::Vector3<f32>, end : alg::Vector3<f32>, axis : alg::Vector3<f32> },
+	Map { path : String, rotation : f32, intensity : f32 },
+	Sky { elevation : f32, azimuth : f32, turbidity : f32, sun_radius : f32, intensity : f32 },
+}
+
+//Only plain data, so it can be parsed on one thread and built in to a scene on another
+#[derive(Clone)]
+pub struct SceneDescription {
+	pub size : Option<(usize, usize)>,
+	pub samples : Option<i32>,
+	pub integrator : Option<Integrator>,
+	pub sampler : Option<SamplerKind>,
+	pub filter : Option<Filter>,
+	pub tile_order : Option<TileOrder>,
+	pub camera : Option<Camera>,
+	pub objects : Vec<ObjectDescription>,
+	//The white to red gradient if there isn't one
+	pub environment : Option<EnvironmentDescription>,
+}
+
+impl MaterialDescription {
+	fn build(&self, id : u32) -> Box<dyn Material + Sync> {
+		match *self {
+			MaterialDescription::Diffuse => Box::new(Diffuse::new(id)),
+			MaterialDescription::Metal(fuzz) => Box::new(Metal::new(fuzz, id)),
+			MaterialDescription::Dielectric(refractive_index) => Box::new(Dielectric::new(refractive_index, id)),
+		}
+	}
+}
+
+impl ObjectDescription {
+	//None for lights, errors for meshes that can't be loaded. The object and its material are both given id
+	fn build_hitable(&self, id : u32) -> Result<Option<Box<dyn Hitable + Sync>>, String> {
+		match *self {
+			ObjectDescription::Sphere { center, radius, colour, ref material } => {
+				Ok(Some(Box::new(Sphere::new(center, radius, colour, material.build(id), id))))
+			},
+			ObjectDescription::MovingSphere { center0, center1, radius, colour, ref material } => {
+				Ok(Some(Box::new(MovingSphere::new(center0, center1, radius, colour, material.build(id), id))))
+			},
+			ObjectDescription::Cube { center, extents, colour, ref material } => {
+				Ok(Some(Box::new(Cube::new(center, extents, colour, material.build(id), id))))
+			},
+			ObjectDescription::Mesh { ref path, scale, offset, colour, ref material } => {
+				Ok(Some(Box::new(Mesh::load(Path::new(path), scale, offset, colour, material.build(id), id)?)))
+			},
+			ObjectDescription::Instance { ref object, pivot, ref translation, ref rotation, ref scale, frame } => {
+				let hitable = match object.build_hitable(id)? {
+					Some(hitable) => hitable,
+					None => return Ok(None),
+				};
+				let mut instance = Instance::new(hitable, pivot, frame);
+				instance.translation = translation.clone();
+				instance.rotation = rotation.clone();
+				instance.scale = scale.clone();
+				Ok(Some(Box::new(instance)))
+			},
+			ObjectDescription::Light { .. } | ObjectDescription::SphereLight { .. } | ObjectDescription::SpotLight { .. } |
+			ObjectDescription::DirectionalLight { .. } | ObjectDescription::RectLight { .. } => Ok(None),
+		}
+	}
+}
+
+impl SceneDescription {
+	pub fn new() -> SceneDescription {
+		SceneDescription {
+			size : None,
+			samples : None,
+			integrator : None,
+			sampler : None,
+			filter : None,
+			tile_order : None,
+			camera : None,
+			objects : Vec::new(),
+			environment : None,
+		}
+	}
+
+	//Not accelerated, that's up to whoever renders it. Ids come from where objects are in the description, starting from 1,
+	//so they match from one frame to the next and wherever the scene's built. Fails if a mesh or environment map can't be loaded
+	pub fn build(&self) -> Result<Scene<'static>, String> {
+		let mut scene = Scene::new();
+		for (index, object) in self.objects.iter().enumerate() {
+			match *object {
+				ObjectDescription::Light { center, intensity, colour } => {
+					scene.lights.push(Box::new(PointLight::new(center, intensity, colour)));
+				},
+				ObjectDescription::SphereLight { center, radius, intensity, colour } => {
+					scene.lights.push(Box::new(SphereLight::new(center, radius, intensity, colour)));
+				},
+				ObjectDescription::SpotLight { center, direction, cone_angle, falloff, intensity, colour } => {
+					scene.lights.push(Box::new(SpotLight::new(center, direction, cone_angle, falloff, intensity, colour)));
+				},
+				ObjectDescription::DirectionalLight { direction, intensity, colour } => {
+					scene.lights.push(Box::new(DirectionalLight::new(direction, intensity, colour)));
+				},
+				ObjectDescription::RectLight { corner, edge_u, edge_v, intensity, colour } => {
+					scene.lights.push(Box::new(RectLight::new(corner, edge_u, edge_v, intensity, colour)));
+				},
+				_ => {
+					if let Some(hitable) = object.build_hitable(index as u32 + 1)? {
+						scene.hitables.push(hitable);
+					}
+				},
+			}
+		}
+
+		match self.environment {
+			Some(EnvironmentDescription::Constant(colour)) => scene.environment = Box::new(ConstantEnvironment::new(colour)),
+			Some(EnvironmentDescription::Gradient { start, end, axis }) => scene.environment = Box::new(GradientEnvironment::new(start, end, axis)),
+			Some(EnvironmentDescription::Map { ref path, rotation, intensity }) => {
+				match EquirectEnvironment::load(Path::new(path), rotation, intensity) {
+					Ok(environment) => scene.environment = Box::new(environment),
+					Err(why) => return Err(format!("Couldn't load {}: {}", path, why)),
+				}
+			},
+			Some(EnvironmentDescription::Sky { elevation, azimuth, turbidity, sun_radius, intensity }) => {
+				let sky = SkyEnvironment::new(elevation, azimuth, turbidity, sun_radius, intensity);
+				if let Some(sun) = sky.sun_light() {
+					scene.lights.push(Box::new(sun));
+				}
+				scene.environment = Box::new(sky);
+			},
+			None => {},
+		}
+
+		//Directional lights send light paths across the whole scene, over everything the shutter could see
+		if let Some(bounds) = scene.bounding_box(0.0, 1.0) {
+			let center = (bounds.min() + bounds.max()) * 0.5;
+			let radius = squared_length(bounds.max() - center).sqrt();
+			for light in scene.lights.iter_mut() {
+				light.set_scene_bounds(center, radius);
+			}
+		}
+		Ok(scene)
+	}
+}
+
+//The words of one line, read off in order
+struct Words<'a> {
+	words : Vec<&'a str>,
+	next : usize,
+}
+
+impl<'a> Words<'a> {
+	fn word(&mut self) -> Result<&'a str, String> {
+		let word = self.words.get(self.next).cloned().ok_or_else(|| "the line ends too early".to_string())?;
+		self.next += 1;
+		Ok(word)
+	}
+
+	fn float(&mut self) -> Result<f32, String> {
+		let word = self.word()?;
+		word.parse::<f32>().map_err(|_| format!("{} isn't a number", word))
+	}
+
+	fn count(&mut self) -> Result<usize, String> {
+		let word = self.word()?;
+		match word.parse::<usize>() {
+			Ok(count) if count > 0 => Ok(count),
+			_ => Err(format!("{} isn't a positive whole number", word)),
+		}
+	}
+
+	fn vector(&mut self) -> Result<alg::Vector3<f32>, String> {
+		let x = self.float()?;
+		let y = self.float()?;
+		let z = self.float()?;
+		Ok(alg::Vector3::new(x, y, z))
+	}
+
+	fn material(&mut self) -> Result<MaterialDescription, String> {
+		match self.word()? {
+			"diffuse" => Ok(MaterialDescription::Diffuse),
+			"metal" => Ok(MaterialDescription::Metal(self.float()?)),
+			"dielectric" => Ok(MaterialDescription::Dielectric(self.float()?)),
+			other => Err(format!("unknown material {}", other)),
+		}
+	}
+
+	fn end(&self) -> Result<(), String> {
+		if self.next < self.words.len() {
+			return Err(format!("unexpected {}", self.words[self.next]));
+		}
+		Ok(())
+	}
+}
+
+fn parse_line(words : &mut Words, description : &mut SceneDescription) -> Result<(), String> {
+	match words.word()? {
+		"size" => {
+			let width = words.count()?;
+			let height = words.count()?;
+			description.size = Some((width, height));
+		},
+		"samples" => description.samples = Some(words.count()? as i32),
+		"integrator" => {
+			description.integrator = Some(match words.word()? {
+				"path" => Integrator::Path,
+				"bidirectional" => Integrator::Bidirectional,
+				"photon" => Integrator::PhotonMapping,
+				"ao" => Integrator::AmbientOcclusion,
+				"whitted" => Integrator::Whitted,
+				other => return Err(format!("unknown integrator {}", other)),
+			});
+		},
+		"sampler" => {
+			description.sampler = Some(match words.word()? {
+				"independent" => SamplerKind::Independent,
+				"stratified" => SamplerKind::Stratified,
+				"halton" => SamplerKind::Halton,
+				"sobol" => SamplerKind::Sobol,
+				other => return Err(format!("unknown sampler {}", other)),
+			});
+		},
+		"filter" => {
+			let kind = match words.word()? {
+				"box" => FilterKind::Box,
+				"tent" => FilterKind::Tent,
+				"gaussian" => FilterKind::Gaussian,
+				"mitchell" => FilterKind::Mitchell,
+				"lanczos" => FilterKind::Lanczos,
+				other => return Err(format!("unknown filter {}", other)),
+			};
+			description.filter = Some(Filter::new(kind, words.float()?));
+		},
+		"tile_order" => {
+			description.tile_order = Some(match words.word()? {
+				"scanline" => TileOrder::Scanline,
+				"spiral" => TileOrder::Spiral,
+				"hilbert" => TileOrder::Hilbert,
+				other => return Err(format!("unknown tile order {}", other)),
+			});
+		},
+		"camera" => {
+			let origin = words.vector()?;
+			let lower_left_corner = words.vector()?;
+			let horizontal = words.vector()?;
+			let vertical = words.vector()?;
+			description.camera = Some(Camera::new(origin, lower_left_corner, horizontal, vertical));
+		},
+		"sphere" => {
+			let center = words.vector()?;
+			let radius = words.float()?;
+			let colour = words.vector()?;
+			let material = words.material()?;
+			description.objects.push(ObjectDescription::Sphere { center, radius, colour, material });
+		},
+		"moving_sphere" => {
+			let center0 = words.vector()?;
+			let center1 = words.vector()?;
+			let radius = words.float()?;
+			let colour = words.vector()?;
+			let material = words.material()?;
+			description.objects.push(ObjectDescription::MovingSphere { center0, center1, radius, colour, material });
+		},
+		"cube" => {
+			let center = words.vector()?;
+			let extents = words.float()?;
+			let colour = words.vector()?;
+			let material = words.material()?;
+			description.objects.push(ObjectDescription::Cube { center, extents, colour, material });
+		},
+		"mesh" => {
+			let path = words.word()?.to_string();
+			if !Path::new(&path).exists() {
+				return Err(format!("there's no mesh at {}", path));
+			}
+			let scale = words.float()?;
+			let offset = words.vector()?;
+			let colour = words.vector()?;
+			let material = words.material()?;
+			description.objects.push(ObjectDescription::Mesh { path, scale, offset, colour, material });
+		},
+		"light" => {
+			let center = words.vector()?;
+			let intensity = words.float()?;
+			let colour = words.vector()?;
+			description.objects.push(ObjectDescription::Light { center, intensity, colour });
+		},
+		"sphere_light" => {
+			let center = words.vector()?;
+			let radius = words.float()?;
+			let intensity = words.float()?;
+			let colour = words.vector()?;
+			description.objects.push(ObjectDescription::SphereLight { center, radius, intensity, colour });
+		},
+		"spot_light" => {
+			let center = words.vector()?;
+			let direction = words.vector()?;
+			let cone_angle = words.float()?;
+			let falloff = words.float()?;
+			let intensity = words.float()?;
+			let colour = words.vector()?;
+			if direction == alg::Vector3::new(0.0, 0.0, 0.0) {
+				return Err("the spot light's direction can't be zero".to_string());
+			}
+			if cone_angle <= 0.0 || cone_angle > 180.0 {
+				return Err("the spot light's cone has to be between 0 and 180 degrees".to_string());
+			}
+			description.objects.push(ObjectDescription::SpotLight { center, direction, cone_angle, falloff, intensity, colour });
+		},
+		"directional_light" => {
+			let direction = words.vector()?;
+			let intensity = words.float()?;
+			let colour = words.vector()?;
+			if direction == alg::Vector3::new(0.0, 0.0, 0.0) {
+				return Err("the directional light's direction can't be zero".to_string());
+			}
+			description.objects.push(ObjectDescription::DirectionalLight { direction, intensity, colour });
+		},
+		"rect_light" => {
+			let corner = words.vector()?;
+			let edge_u = words.vector()?;
+			let edge_v = words.vector()?;
+			let intensity = words.float()?;
+			let colour = words.vector()?;
+			if edge_u.cross(&edge_v) == alg::Vector3::new(0.0, 0.0, 0.0) {
+				return Err("the rect light's edges can't be parallel".to_string());
+			}
+			description.objects.push(ObjectDescription::RectLight { corner, edge_u, edge_v, intensity, colour });
+		},
+		"environment" => {
+			description.environment = Some(match words.word()? {
+				"constant" => EnvironmentDescription::Constant(words.vector()?),
+				"gradient" => {
+					let start = words.vector()?;
+					let end = words.vector()?;
+					let axis = words.vector()?;
+					if axis == alg::Vector3::new(0.0, 0.0, 0.0) {
+						return Err("the gradient's axis can't be zero".to_string());
+					}
+					EnvironmentDescription::Gradient { start, end, axis }
+				},
+				"map" => {
+					let path = words.word()?.to_string();
+					if !Path::new(&path).exists() {
+						return Err(format!("there's no environment map at {}", path));
+					}
+					let rotation = words.float()?;
+					let intensity = words.float()?;
+					EnvironmentDescription::Map { path, rotation, intensity }
+				},
+				"sky" => {
+					let elevation = words.float()?;
+					let azimuth = words.float()?;
+					let turbidity = words.float()?;
+					let sun_radius = words.float()?;
+					let intensity = words.float()?;
+					if turbidity < 1.0 {
+						return Err("the sky's turbidity can't be under 1".to_string());
+					}
+					if sun_radius <= 0.0 {
+						return Err("the sun's radius has to be more than 0".to_string());
+					}
+					EnvironmentDescription::Sky { elevation, azimuth, turbidity, sun_radius, intensity }
+				},
+				other => return Err(format!("unknown environment {}", other)),
+			});
+		},
+		other => return Err(format!("unknown {}", other)),
+	}
+	words.end()
+}
+
+//Errors say which line was wrong and why
+pub fn parse_scene(text : &str) -> Result<SceneDescription, String> {
+	let mut description = SceneDescription::new();
+
+	for (number, line) in text.lines().enumerate() {
+		let line = match line.find('#') {
+			Some(comment) => &line[..comment],
+			None => line,
+		};
+		let mut words = Words {
+			words : line.split_whitespace().collect(),
+			next : 0,
+		};
+		if words.words.is_empty() {
+			continue;
+		}
+
+		if let Err(why) = parse_line(&mut words, &mut description) {
+			return Err(format!("line {}: {}", number + 1, why));
+		}
+	}
+
+	Ok(description)
+}
@@ -0,0 +1,489 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use scene::*;
+use camera::*;
+use photon::*;
+use aov::*;
+use render::*;
+use scene_file::*;
+
+//A small HTTP API for rendering scenes sent by other tools, one job at a time on the normal renderer:
+//
+//	POST /jobs              a scene description in the body (see scene_file.rs), answers with the new job's id
+//	GET /jobs               every job's status
+//	GET /jobs/id            one job's status and progress
+//	GET /jobs/id/image      the image so far as a PNG
+//	DELETE /jobs/id         cancels the job, a job that's rendering keeps the tiles it finished
+//
+//Only meant for local use, there's no authentication and the whole body is read in to memory
+static MAX_BODY_BYTES : usize = 16 * 1024 * 1024;
+//Seconds between preview updates while a job renders
+static SERVER_PREVIEW_INTERVAL : f32 = 2.0;
+static CONNECTION_TIMEOUT : u64 = 30;
+
+#[derive(Clone, Debug, PartialEq)]
+enum JobStatus {
+	Queued,
+	Rendering,
+	Done,
+	Cancelled,
+	Failed(String),
+}
+
+struct Job {
+	id : usize,
+	status : JobStatus,
+	//Taken by the render thread when the job starts
+	description : Option<SceneDescription>,
+	control : Arc<RenderControl>,
+	width : usize,
+	height : usize,
+}
+
+struct Jobs {
+	jobs : Vec<Job>,
+	next_id : usize,
+}
+
+//Everything the connection and render threads share, the condition variable wakes the render thread for new jobs
+struct Server {
+	jobs : Mutex<Jobs>,
+	queued : Condvar,
+	settings : RenderSettings,
+	camera : Camera,
+	accelerate : fn(Scene<'static>) -> Scene<'static>,
+}
+
+struct Request {
+	method : String,
+	path : String,
+	body : Vec<u8>,
+}
+
+struct Response {
+	status : &'static str,
+	content_type : &'static str,
+	body : Vec<u8>,
+}
+
+impl Response {
+	fn json(status : &'static str, body : String) -> Response {
+		Response {
+			status,
+			content_type : "application/json",
+			body : body.into_bytes(),
+		}
+	}
+
+	fn error(status : &'static str, message : &str) -> Response {
+		Response::json(status, format!("{{\"error\":{}}}", json_string(message)))
+	}
+}
+
+fn json_string(value : &str) -> String {
+	let mut escaped = String::with_capacity(value.len() + 2);
+	escaped.push('"');
+	for character in value.chars() {
+		match character {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			character if (character as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+			character => escaped.push(character),
+		}
+	}
+	escaped.push('"');
+	escaped
+}
+
+fn status_name(status : &JobStatus) -> &'static str {
+	match *status {
+		JobStatus::Queued => "queued",
+		JobStatus::Rendering => "rendering",
+		JobStatus::Done => "done",
+		JobStatus::Cancelled => "cancelled",
+		JobStatus::Failed(_) => "failed",
+	}
+}
+
+fn job_json(job : &Job) -> String {
+	let progress = job.control.progress();
+	let fraction = match job.status {
+		JobStatus::Queued => 0.0,
+		JobStatus::Done => 1.0,
+		_ => progress.fraction(),
+	};
+	let mut json = format!("{{\"id\":{},\"status\":\"{}\",\"width\":{},\"height\":{},\"progress\":{:.4},\"pass\":{},\"passes\":{},\"finished_tiles\":{},\"total_tiles\":{},\"samples\":{}",
+		job.id, status_name(&job.status), job.width, job.height, fraction, progress.pass, progress.passes, progress.finished_tiles, progress.total_tiles, progress.samples);
+	if let JobStatus::Failed(ref why) = job.status {
+		json.push_str(&format!(",\"error\":{}", json_string(why)));
+	}
+	json.push('}');
+	json
+}
+
+fn read_request(stream : &TcpStream) -> io::Result<Request> {
+	let mut reader = BufReader::new(stream);
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line)?;
+	let mut parts = request_line.split_whitespace();
+	let method = parts.next().unwrap_or("").to_string();
+	let path = parts.next().unwrap_or("").to_string();
+
+	let mut content_length = 0;
+	loop {
+		let mut header = String::new();
+		if reader.read_line(&mut header)? == 0 {
+			break;
+		}
+		let header = header.trim();
+		if header.is_empty() {
+			break;
+		}
+		if let Some(colon) = header.find(':') {
+			if header[..colon].trim().eq_ignore_ascii_case("content-length") {
+				content_length = header[colon + 1..].trim().parse::<usize>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?;
+			}
+		}
+	}
+	if content_length > MAX_BODY_BYTES {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "body too large"));
+	}
+
+	let mut body = vec![0; content_length];
+	reader.read_exact(&mut body)?;
+	Ok(Request {
+		method,
+		path,
+		body,
+	})
+}
+
+fn write_response(mut stream : &TcpStream, response : &Response) -> io::Result<()> {
+	write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.status, response.content_type, response.body.len())?;
+	stream.write_all(&response.body)?;
+	stream.flush()
+}
+
+fn submit(server : &Server, body : &[u8]) -> Response {
+	let text = match String::from_utf8(body.to_vec()) {
+		Ok(text) => text,
+		Err(_) => return Response::error("400 Bad Request", "the scene isn't UTF-8"),
+	};
+	let description = match parse_scene(&text) {
+		Ok(description) => description,
+		Err(why) => return Response::error("400 Bad Request", &why),
+	};
+
+	let (width, height) = description.size.unwrap_or((server.settings.width, server.settings.height));
+	let mut jobs = server.jobs.lock().unwrap();
+	let id = jobs.next_id;
+	jobs.next_id += 1;
+	jobs.jobs.push(Job {
+		id,
+		status : JobStatus::Queued,
+		description : Some(description),
+		control : Arc::new(RenderControl::new(CancellationToken::new(), None, true)),
+		width,
+		height,
+	});
+	server.queued.notify_one();
+	println!("Queued job {}", id);
+	Response::json("201 Created", format!("{{\"id\":{}}}", id))
+}
+
+fn preview(job : &Job) -> Response {
+	let pixels = match job.control.preview() {
+		Some(pixels) => pixels,
+		None => return Response::error("404 Not Found", "there's no image yet"),
+	};
+
+	let mut png : Vec<u8> = Vec::new();
+	if let Err(why) = encode_png(&mut png, job.width, job.height, &pixels) {
+		return Response::error("500 Internal Server Error", &why.to_string());
+	}
+	Response {
+		status : "200 OK",
+		content_type : "image/png",
+		body : png,
+	}
+}
+
+fn cancel(job : &mut Job) -> Response {
+	match job.status {
+		JobStatus::Queued => {
+			job.status = JobStatus::Cancelled;
+			job.description = None;
+		},
+		JobStatus::Rendering => job.control.cancel(),
+		_ => return Response::error("409 Conflict", "the job has already finished"),
+	}
+	println!("Cancelling job {}", job.id);
+	Response::json("200 OK", job_json(job))
+}
+
+fn route(server : &Server, request : &Request) -> Response {
+	let segments : Vec<&str> = request.path.trim_matches('/').split('/').collect();
+	let (id, image) = match segments.as_slice() {
+		["jobs"] => {
+			return match request.method.as_str() {
+				"POST" => submit(server, &request.body),
+				"GET" => {
+					let jobs = server.jobs.lock().unwrap();
+					let list : Vec<String> = jobs.jobs.iter().map(job_json).collect();
+					Response::json("200 OK", format!("[{}]", list.join(",")))
+				},
+				_ => Response::error("405 Method Not Allowed", "use GET or POST on /jobs"),
+			};
+		},
+		["jobs", id] => (*id, false),
+		["jobs", id, "image"] => (*id, true),
+		["jobs", ..] => return Response::error("404 Not Found", "try GET or DELETE on /jobs/id, or GET on /jobs/id/image"),
+		_ => return Response::error("404 Not Found", "everything is under /jobs"),
+	};
+
+	let id = match id.parse::<usize>() {
+		Ok(id) => id,
+		Err(_) => return Response::error("404 Not Found", "job ids are numbers"),
+	};
+	let mut jobs = server.jobs.lock().unwrap();
+	let job = match jobs.jobs.iter_mut().find(|job| job.id == id) {
+		Some(job) => job,
+		None => return Response::error("404 Not Found", "there's no job with that id"),
+	};
+
+	match (request.method.as_str(), image) {
+		("GET", false) => Response::json("200 OK", job_json(job)),
+		("DELETE", false) => cancel(job),
+		("GET", true) => preview(job),
+		_ => Response::error("405 Method Not Allowed", "try GET or DELETE on /jobs/id, or GET on /jobs/id/image"),
+	}
+}
+
+fn handle_connection(server : &Server, stream : TcpStream) {
+	let _ = stream.set_read_timeout(Some(Duration::from_secs(CONNECTION_TIMEOUT)));
+	let response = match read_request(&stream) {
+		Ok(request) => route(server, &request),
+		Err(why) => Response::error("400 Bad Request", &why.to_string()),
+	};
+	if let Err(why) = write_response(&stream, &response) {
+		println!("Couldn't answer a request: {}", why);
+	}
+}
+
+//Renders queued jobs one after another, each using every thread like a normal render
+fn render_jobs(server : &Server) {
+	loop {
+		let (id, description, control) = {
+			let mut jobs = server.jobs.lock().unwrap();
+			loop {
+				if let Some(job) = jobs.jobs.iter_mut().find(|job| job.status == JobStatus::Queued) {
+					job.status = JobStatus::Rendering;
+					break (job.id, job.description.take().unwrap(), job.control.clone());
+				}
+				jobs = server.queued.wait(jobs).unwrap();
+			}
+		};
+
+		let mut settings = server.settings;
+		if let Some((width, height)) = description.size {
+			settings.width = width;
+			settings.height = height;
+		}
+		if let Some(samples) = description.samples {
+			settings.samples = samples;
+			settings.adaptive_max_samples = samples;
+		}
+		if let Some(integrator) = description.integrator {
+			settings.integrator = integrator;
+		}
+		if let Some(sampler) = description.sampler {
+			settings.sampler = sampler;
+		}
+		if let Some(filter) = description.filter {
+			settings.filter = filter;
+		}
+		if let Some(tile_order) = description.tile_order {
+			settings.tile_order = tile_order;
+		}
+		//The preview is what the job gives back, there's nothing to resume
+		settings.flush_interval = SERVER_PREVIEW_INTERVAL;
+		settings.checkpoint_interval = 0.0;
+		let camera = description.camera.clone().unwrap_or_else(|| server.camera.clone());
+
+		println!("Rendering job {}", id);
+		//A scene that can't be built or rendered fails its job instead of taking the server down
+		let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), String> {
+			let scene = (server.accelerate)(description.build()?);
+			let mut photons : Option<PhotonMapper> = None;
+			if settings.integrator == Integrator::PhotonMapping {
+				photons = Some(PhotonMapper::new(&scene, settings.seed, settings.shutter_open, settings.shutter_close, settings.max_samples() as usize));
+			}
+			render(&scene, &camera, &settings, &photons, &format!("job{}", id), None, Some(&control));
+			Ok(())
+		}));
+
+		let mut jobs = server.jobs.lock().unwrap();
+		if let Some(job) = jobs.jobs.iter_mut().find(|job| job.id == id) {
+			job.status = match result {
+				Err(why) => JobStatus::Failed(why.downcast_ref::<String>().cloned()
+					.or_else(|| why.downcast_ref::<&str>().map(|why| why.to_string()))
+					.unwrap_or_else(|| "the render panicked".to_string())),
+				Ok(Err(why)) => JobStatus::Failed(why),
+				Ok(Ok(_)) if control.is_cancelled() => JobStatus::Cancelled,
+				Ok(Ok(_)) => JobStatus::Done,
+			};
+			println!("Job {} {}", id, status_name(&job.status));
+		}
+	}
+}
+
+//Serves the API on address until the process is stopped. Jobs start from settings and camera, with anything their
+//description sets replacing it, and their scenes go through accelerate before they're rendered
+pub fn serve(address : &str, settings : RenderSettings, camera : Camera, accelerate : fn(Scene<'static>) -> Scene<'static>) {
+	let listener = match TcpListener::bind(address) {
+		Ok(listener) => listener,
+		Err(why) => panic!("Couldn't listen on {}: {}", address, why),
+	};
+	println!("Serving render jobs on {}", address);
+
+	let server = Arc::new(Server {
+		jobs : Mutex::new(Jobs {
+			jobs : Vec::new(),
+			next_id : 1,
+		}),
+		queued : Condvar::new(),
+		settings,
+		camera,
+		accelerate,
+	});
+
+	let render_server = server.clone();
+	thread::spawn(move || render_jobs(&render_server));
+
+	for stream in listener.incoming() {
+		match stream {
+			Ok(stream) => {
+				let server = server.clone();
+				thread::spawn(move || handle_connection(&server, stream));
+			},
+			Err(why) => println!("Couldn't accept a connection: {}", why),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate nalgebra as alg;
+
+	use std::net::TcpStream;
+
+	use super::*;
+	use filter::*;
+	use sampler::*;
+
+	fn unchanged(scene : Scene<'static>) -> Scene<'static> {
+		scene
+	}
+
+	//No render thread, so jobs stay queued
+	fn server() -> Server {
+		Server {
+			jobs : Mutex::new(Jobs {
+				jobs : Vec::new(),
+				next_id : 1,
+			}),
+			queued : Condvar::new(),
+			settings : RenderSettings {
+				width : 6,
+				height : 4,
+				integrator : Integrator::Path,
+				samples : 4,
+				adaptive_sampling : false,
+				adaptive_min_samples : 4,
+				adaptive_max_samples : 4,
+				adaptive_batch_samples : 4,
+				adaptive_noise_threshold : 0.0,
+				sampler : SamplerKind::Sobol,
+				seed : 1,
+				filter : Filter::new(FilterKind::Gaussian, 1.5),
+				tile_size : 8,
+				tile_order : TileOrder::Spiral,
+				flush_interval : 0.0,
+				progressive : false,
+				time_limit : 0.0,
+				progressive_noise_threshold : 0.0,
+				checkpoint_interval : 0.0,
+				shutter_open : 0.0,
+				shutter_close : 0.0,
+				debug_heatmap : false,
+				heatmap_max_tests : 200,
+			},
+			camera : Camera::new(alg::Vector3::new(0.0, 0.0, 0.0), alg::Vector3::new(-1.5, -1.0, -1.0), alg::Vector3::new(3.0, 0.0, 0.0), alg::Vector3::new(0.0, 2.0, 0.0)),
+			accelerate : unchanged,
+		}
+	}
+
+	fn request(server : &Server, method : &str, path : &str, body : &str) -> (&'static str, String) {
+		let response = route(server, &Request {
+			method : method.to_string(),
+			path : path.to_string(),
+			body : body.as_bytes().to_vec(),
+		});
+		(response.status, String::from_utf8(response.body).unwrap())
+	}
+
+	#[test]
+	fn jobs_can_be_posted_listed_and_cancelled() {
+		let server = server();
+		assert_eq!(request(&server, "POST", "/jobs", "size 8 6\nsphere 0 0 -1 0.5 1 1 1 diffuse\n"), ("201 Created", "{\"id\":1}".to_string()));
+		assert_eq!(request(&server, "POST", "/jobs", "not a scene\n").0, "400 Bad Request");
+
+		let (status, list) = request(&server, "GET", "/jobs", "");
+		assert_eq!(status, "200 OK");
+		assert!(list.starts_with("[{\"id\":1,\"status\":\"queued\",\"width\":8,\"height\":6,"), "{}", list);
+
+		let (status, job) = request(&server, "GET", "/jobs/1", "");
+		assert_eq!(status, "200 OK");
+		assert!(job.contains("\"status\":\"queued\""), "{}", job);
+		assert_eq!(request(&server, "GET", "/jobs/1/image", "").0, "404 Not Found");
+
+		let (status, job) = request(&server, "DELETE", "/jobs/1", "");
+		assert_eq!(status, "200 OK");
+		assert!(job.contains("\"status\":\"cancelled\""), "{}", job);
+		assert_eq!(request(&server, "DELETE", "/jobs/1", "").0, "409 Conflict");
+	}
+
+	#[test]
+	fn unknown_paths_and_jobs_are_not_found() {
+		let server = server();
+		assert_eq!(request(&server, "GET", "/", "").0, "404 Not Found");
+		assert_eq!(request(&server, "GET", "/scenes", "").0, "404 Not Found");
+		assert_eq!(request(&server, "GET", "/jobs/7", "").0, "404 Not Found");
+		assert_eq!(request(&server, "GET", "/jobs/seven", "").0, "404 Not Found");
+		assert_eq!(request(&server, "GET", "/jobs/1/image/large", "").0, "404 Not Found");
+		assert_eq!(request(&server, "DELETE", "/jobs", "").0, "405 Method Not Allowed");
+	}
+
+	#[test]
+	fn body_over_the_limit_is_rejected_before_reading_it() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		write!(client, "POST /jobs HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1).unwrap();
+
+		let (stream, _) = listener.accept().unwrap();
+		match read_request(&stream) {
+			Err(why) => assert_eq!(why.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("A body over the limit was read"),
+		}
+	}
+}
@@ -2,21 +2,26 @@
 extern crate nalgebra as alg;
 
 use materials::*;
+use hits::*;
+use animation::*;
 
+//Every shape is given its id for the object id pass when it's made, 0 is left for the background
 pub struct Sphere {
 	pub center: alg::Vector3<f32>,
 	pub radius: f32,
 	pub colour: alg::Vector3<f32>,
-	pub material: Box<Material + Sync>
+	pub material: Box<dyn Material + Sync>,
+	pub id: u32
 }
 
 impl Sphere {
-	pub fn new (p_center : alg::Vector3<f32>, p_radius : f32, p_colour : alg::Vector3<f32>, p_material : Box<Material + Sync>) -> Sphere {
+	pub fn new (p_center : alg::Vector3<f32>, p_radius : f32, p_colour : alg::Vector3<f32>, p_material : Box<dyn Material + Sync>, p_id : u32) -> Sphere {
 		Sphere {
 			center: p_center,
 			radius: p_radius,
 			colour: p_colour,
-			material: p_material
+			material: p_material,
+			id: p_id
 		}
 	}
 }
@@ -25,16 +30,18 @@ pub struct Cube {
 	pub center: alg::Vector3<f32>,
 	pub extents: f32,
 	pub colour: alg::Vector3<f32>,
-	pub material: Box<Material + Sync>
+	pub material: Box<dyn Material + Sync>,
+	pub id: u32
 }
 
 impl Cube {
-	pub fn new (p_center : alg::Vector3<f32>, p_extents : f32, p_colour : alg::Vector3<f32>, p_material : Box<Material + Sync>) -> Cube {
+	pub fn new (p_center : alg::Vector3<f32>, p_extents : f32, p_colour : alg::Vector3<f32>, p_material : Box<dyn Material + Sync>, p_id : u32) -> Cube {
 		Cube {
 			center: p_center,
 			extents: p_extents,
 			colour: p_colour,
-			material: p_material
+			material: p_material,
+			id: p_id
 		}
 	}
 }
@@ -43,16 +50,119 @@ pub struct Triangle {
 	pub vertices: [alg::Vector3<f32>; 3],
 	pub normal: alg::Vector3<f32>,
 	pub colour: alg::Vector3<f32>,
-	pub material: Box<Material + Sync>
+	pub material: Box<dyn Material + Sync>,
+	pub id: u32
 }
 
 impl Triangle {
-	pub fn new (p_vertices : [alg::Vector3<f32>; 3], p_normal : alg::Vector3<f32>, p_colour : alg::Vector3<f32>, p_material : Box<Material + Sync>) -> Triangle {
+	pub fn new (p_vertices : [alg::Vector3<f32>; 3], p_normal : alg::Vector3<f32>, p_colour : alg::Vector3<f32>, p_material : Box<dyn Material + Sync>, p_id : u32) -> Triangle {
 		Triangle {
 			vertices: p_vertices,
 			normal: p_normal,
 			colour: p_colour,
-			material: p_material
+			material: p_material,
+			id: p_id
 		}
 	}
-}
\ No newline at end of file
+}
+
+//A sphere moving in a straight line, at center0 when the frame starts and center1 a frame later, carrying on the same way
+//either side
+pub struct MovingSphere {
+	pub center0: alg::Vector3<f32>,
+	pub center1: alg::Vector3<f32>,
+	pub radius: f32,
+	pub colour: alg::Vector3<f32>,
+	pub material: Box<dyn Material + Sync>,
+	pub id: u32
+}
+
+impl MovingSphere {
+	pub fn new (p_center0 : alg::Vector3<f32>, p_center1 : alg::Vector3<f32>, p_radius : f32, p_colour : alg::Vector3<f32>, p_material : Box<dyn Material + Sync>, p_id : u32) -> MovingSphere {
+		MovingSphere {
+			center0: p_center0,
+			center1: p_center1,
+			radius: p_radius,
+			colour: p_colour,
+			material: p_material,
+			id: p_id
+		}
+	}
+
+	pub fn center(&self, time : f32) -> alg::Vector3<f32> {
+		self.center0 + (self.center1 - self.center0) * time
+	}
+}
+
+//Another hitable moved, turned about the vertical axis through its pivot and scaled about the pivot by keyframed tracks.
+//The tracks are sampled at frame plus the ray's time, so anything moving while the shutter's open is blurred
+pub struct Instance {
+	pub hitable: Box<dyn Hitable + Sync>,
+	pub pivot: alg::Vector3<f32>,
+	pub translation: Option<Track<alg::Vector3<f32>>>,
+	//Degrees
+	pub rotation: Option<Track<f32>>,
+	pub scale: Option<Track<f32>>,
+	pub frame: f32,
+}
+
+//Where an instance is at one moment
+#[derive(Copy, Clone)]
+pub struct InstanceTransform {
+	pub pivot: alg::Vector3<f32>,
+	pub translation: alg::Vector3<f32>,
+	pub angle: f32,
+	pub scale: f32,
+}
+
+fn rotate_y(v : alg::Vector3<f32>, angle : f32) -> alg::Vector3<f32> {
+	let (sin, cos) = angle.sin_cos();
+	alg::Vector3::new(cos * v.x + sin * v.z, v.y, cos * v.z - sin * v.x)
+}
+
+impl InstanceTransform {
+	pub fn is_identity(&self) -> bool {
+		self.translation == alg::Vector3::new(0.0, 0.0, 0.0) && self.angle == 0.0 && self.scale == 1.0
+	}
+
+	pub fn point_to_world(&self, point : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.pivot + self.translation + rotate_y((point - self.pivot) * self.scale, self.angle)
+	}
+
+	pub fn point_to_object(&self, point : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.pivot + rotate_y(point - self.pivot - self.translation, -self.angle) / self.scale
+	}
+
+	//Directions keep their length relative to the points, so distances along a ray stay the same in both spaces
+	pub fn direction_to_object(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		rotate_y(direction, -self.angle) / self.scale
+	}
+
+	//Normals only turn with a uniform scale
+	pub fn normal_to_world(&self, normal : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		rotate_y(normal, self.angle)
+	}
+}
+
+impl Instance {
+	pub fn new (p_hitable : Box<dyn Hitable + Sync>, p_pivot : alg::Vector3<f32>, p_frame : f32) -> Instance {
+		Instance {
+			hitable: p_hitable,
+			pivot: p_pivot,
+			translation: None,
+			rotation: None,
+			scale: None,
+			frame: p_frame,
+		}
+	}
+
+	pub fn transform(&self, time : f32) -> InstanceTransform {
+		let frame = self.frame + time;
+		InstanceTransform {
+			pivot: self.pivot,
+			translation: self.translation.as_ref().map_or(alg::Vector3::new(0.0, 0.0, 0.0), |track| track.sample(frame)),
+			angle: self.rotation.as_ref().map_or(0.0, |track| track.sample(frame).to_radians()),
+			scale: self.scale.as_ref().map_or(1.0, |track| track.sample(frame)),
+		}
+	}
+}
@@ -0,0 +1,180 @@
+extern crate nalgebra as alg;
+
+use std::f32::consts::PI;
+
+use environment::*;
+use light::*;
+
+//Table size the sky is sampled from, the radiance itself is always worked out exactly
+const SKY_TABLE_WIDTH : usize = 256;
+const SKY_TABLE_HEIGHT : usize = 128;
+//Radiance of the sun's disc above the atmosphere, in the same kcd/m^2 as the sky so the two stay in proportion
+const SUN_RADIANCE : f32 = 1.6e6;
+
+//Preetham, Shirley and Smits' analytic daylight: a clear sky for a sun at elevation degrees above the horizon and azimuth
+//degrees round from -z towards +x, hazier with turbidity (2 is very clear, 10 is hazy). Below the horizon is black, there
+//should be ground there. intensity scales everything down from kcd/m^2 to the renderer's units. The sun itself isn't
+//part of the sky, sun_light() gives it as a directional light so photons and light paths can start from it too
+pub struct SkyEnvironment {
+	pub sun_direction : alg::Vector3<f32>,
+	//Radians
+	pub sun_radius : f32,
+	pub intensity : f32,
+	//Sky radiance at the zenith as xyY, and the Perez coefficients for each
+	zenith : [f32; 3],
+	perez : [[f32; 5]; 3],
+	sun_radiance : alg::Vector3<f32>,
+	//Tabulated for importance sampling
+	table : EquirectEnvironment,
+}
+
+impl SkyEnvironment {
+	pub fn new(elevation : f32, azimuth : f32, turbidity : f32, sun_radius : f32, intensity : f32) -> SkyEnvironment {
+		let elevation = elevation.to_radians();
+		let azimuth = azimuth.to_radians();
+		let sun_direction = alg::Vector3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos());
+		let t = turbidity;
+		//The model doesn't go past sunset, so the sky stays as it is with the sun on the horizon
+		let theta_s = (PI / 2.0 - elevation).min(PI / 2.0);
+
+		let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+		let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+		let theta2 = theta_s * theta_s;
+		let theta3 = theta2 * theta_s;
+		let zenith_x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta_s)
+			+ t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta_s + 0.00394)
+			+ (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta_s + 0.25886);
+		let zenith_y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta_s)
+			+ t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta_s + 0.00516)
+			+ (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta_s + 0.26688);
+
+		let perez = [
+			[0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+			[-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+			[-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+		];
+
+		let sun_radius = sun_radius.to_radians();
+		let mut sky = SkyEnvironment {
+			sun_direction,
+			sun_radius,
+			intensity,
+			zenith : [zenith_luminance, zenith_x, zenith_y],
+			perez,
+			sun_radiance : sun_transmittance(theta_s, turbidity) * SUN_RADIANCE,
+			table : EquirectEnvironment::new(1, 1, vec![alg::Vector3::new(0.0, 0.0, 0.0)], 0.0, 1.0),
+		};
+
+		let mut pixels = Vec::with_capacity(SKY_TABLE_WIDTH * SKY_TABLE_HEIGHT);
+		for y in 0..SKY_TABLE_HEIGHT {
+			let theta = PI * (y as f32 + 0.5) / SKY_TABLE_HEIGHT as f32;
+			for x in 0..SKY_TABLE_WIDTH {
+				//The same layout the equirect environment reads back
+				let phi = ((x as f32 + 0.5) / SKY_TABLE_WIDTH as f32 - 0.5) * 2.0 * PI;
+				pixels.push(sky.sky_radiance(alg::Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())));
+			}
+		}
+		sky.table = EquirectEnvironment::new(SKY_TABLE_WIDTH, SKY_TABLE_HEIGHT, pixels, 0.0, 1.0);
+		sky
+	}
+
+	//The Perez distribution for theta from the zenith and gamma from the sun
+	fn perez(&self, coefficients : &[f32; 5], cos_theta : f32, gamma : f32) -> f32 {
+		let [a, b, c, d, e] = *coefficients;
+		let cos_gamma = gamma.cos();
+		(1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+	}
+
+	fn sky_radiance(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		let direction = direction.normalize();
+		if direction.y <= 0.0 {
+			return alg::Vector3::new(0.0, 0.0, 0.0);
+		}
+
+		//Kept off zero so the horizon doesn't blow up
+		let cos_theta = direction.y.max(0.01);
+		let gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+		let theta_s = self.sun_direction.y.clamp(0.0, 1.0).acos();
+		let mut xyy = [0.0; 3];
+		for (i, value) in xyy.iter_mut().enumerate() {
+			*value = self.zenith[i] * self.perez(&self.perez[i], cos_theta, gamma) / self.perez(&self.perez[i], 1.0, theta_s);
+		}
+
+		xyy_to_rgb(xyy[1], xyy[2], xyy[0]) * self.intensity
+	}
+
+	//The sun's disc, reddened by the same atmosphere, as a light giving the irradiance the disc would. None once it's set
+	pub fn sun_light(&self) -> Option<DirectionalLight> {
+		if self.sun_direction.y <= -self.sun_radius.sin() {
+			return None;
+		}
+		let solid_angle = 2.0 * PI * (1.0 - self.sun_radius.cos());
+		let irradiance = self.sun_radiance * (self.intensity * solid_angle);
+		let intensity = irradiance.x.max(irradiance.y.max(irradiance.z));
+		if intensity <= 0.0 {
+			return None;
+		}
+		Some(DirectionalLight::with_radius(-self.sun_direction, self.sun_radius.to_degrees(), intensity, irradiance / intensity))
+	}
+}
+
+impl Environment for SkyEnvironment {
+	fn radiance(&self, direction : alg::Vector3<f32>) -> alg::Vector3<f32> {
+		self.sky_radiance(direction)
+	}
+
+	fn is_importance_sampled(&self) -> bool {
+		true
+	}
+
+	fn sample(&self, u : alg::Vector2<f32>) -> Option<EnvironmentSample> {
+		let direction = match self.table.sample(u) {
+			Some(sample) => sample.direction,
+			None => return None,
+		};
+
+		let pdf = self.pdf(direction);
+		if pdf <= 0.0 {
+			return None;
+		}
+		Some(EnvironmentSample {
+			direction,
+			radiance : self.radiance(direction),
+			pdf,
+		})
+	}
+
+	fn pdf(&self, direction : alg::Vector3<f32>) -> f32 {
+		self.table.pdf(direction)
+	}
+}
+
+//How much of the sun's light gets through the air to the ground at red, green and blue, from Rayleigh and aerosol
+//scattering along the path through the atmosphere (Preetham et al. appendix 2, leaving out ozone and water)
+fn sun_transmittance(theta_s : f32, turbidity : f32) -> alg::Vector3<f32> {
+	let relative_air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).max(0.01).powf(-1.253));
+	let beta = 0.04608 * turbidity - 0.04586;
+	let alpha : f32 = 1.3;
+	//Micrometres
+	let wavelengths : [f32; 3] = [0.65, 0.55, 0.45];
+	let mut transmittance = [0.0; 3];
+	for (i, &wavelength) in wavelengths.iter().enumerate() {
+		let rayleigh = (-0.008735 * wavelength.powf(-4.08) * relative_air_mass).exp();
+		let aerosol = (-beta * wavelength.powf(-alpha) * relative_air_mass).exp();
+		transmittance[i] = rayleigh * aerosol;
+	}
+	alg::Vector3::new(transmittance[0], transmittance[1], transmittance[2])
+}
+
+//Chromaticity and luminance in to linear sRGB
+fn xyy_to_rgb(x : f32, y : f32, luminance : f32) -> alg::Vector3<f32> {
+	if y <= 0.0 {
+		return alg::Vector3::new(0.0, 0.0, 0.0);
+	}
+	let cie_x = x / y * luminance;
+	let cie_z = (1.0 - x - y) / y * luminance;
+	alg::Vector3::new(
+		(3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z).max(0.0),
+		(-0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z).max(0.0),
+		(0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z).max(0.0))
+}
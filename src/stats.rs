@@ -0,0 +1,122 @@
+extern crate nalgebra as alg;
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+use aabb::*;
+use hits::*;
+use ray::*;
+
+//Relative costs used for the SAH estimate of the whole tree
+pub const TRAVERSAL_COST : f32 = 1.0;
+pub const INTERSECTION_COST : f32 = 1.0;
+
+//Every box and primitive test made on this thread since the last reset by rays counting them, used for the heatmap debug view
+thread_local! {
+	static BOX_TESTS : Cell<u32> = const { Cell::new(0) };
+	static PRIMITIVE_TESTS : Cell<u32> = const { Cell::new(0) };
+}
+
+pub fn count_box_test(ray : &Ray) {
+	if ray.count_tests {
+		BOX_TESTS.with(|count| count.set(count.get() + 1));
+	}
+}
+
+pub fn count_primitive_test(ray : &Ray) {
+	if ray.count_tests {
+		PRIMITIVE_TESTS.with(|count| count.set(count.get() + 1));
+	}
+}
+
+pub fn reset_traversal_counts() {
+	BOX_TESTS.with(|count| count.set(0));
+	PRIMITIVE_TESTS.with(|count| count.set(0));
+}
+
+//(box tests, primitive tests)
+pub fn traversal_counts() -> (u32, u32) {
+	(BOX_TESTS.with(|count| count.get()), PRIMITIVE_TESTS.with(|count| count.get()))
+}
+
+//Blue for cheap rays through green and yellow to red for rays that made max_tests or more tests
+pub fn heatmap_colour(tests : u32, max_tests : u32) -> alg::Vector3<f32> {
+	let t = (tests as f32 / max_tests as f32).min(1.0);
+	if t < 0.25 {
+		alg::Vector3::new(0.0, t * 4.0, 1.0)
+	}
+	else if t < 0.5 {
+		alg::Vector3::new(0.0, 1.0, 1.0 - (t - 0.25) * 4.0)
+	}
+	else if t < 0.75 {
+		alg::Vector3::new((t - 0.5) * 4.0, 1.0, 0.0)
+	}
+	else {
+		alg::Vector3::new(1.0, 1.0 - (t - 0.75) * 4.0, 0.0)
+	}
+}
+
+pub struct BVHStats {
+	pub node_count : usize,
+	pub leaf_count : usize,
+	pub primitive_count : usize,
+	pub max_depth : usize,
+	//number of primitives in a leaf -> how many leaves have that many
+	pub leaf_sizes : BTreeMap<usize, usize>,
+	pub sah_cost : f32,
+	pub memory : usize,
+	root_area : f32,
+}
+
+impl BVHStats {
+	pub fn gather(root : &dyn Hitable) -> BVHStats {
+		let root_area = match root.bounding_box(0.0, 0.0) {
+			Some(bbox) => bbox.area(),
+			None => 1.0,
+		};
+
+		let mut stats = BVHStats {
+			node_count : 0,
+			leaf_count : 0,
+			primitive_count : 0,
+			max_depth : 0,
+			leaf_sizes : BTreeMap::new(),
+			sah_cost : 0.0,
+			memory : 0,
+			root_area : root_area.max(0.000001),
+		};
+
+		root.accumulate_stats(&mut stats, 0);
+		stats
+	}
+
+	pub fn add_node(&mut self, bbox : &AABB, depth : usize, size : usize) {
+		self.node_count += 1;
+		self.max_depth = self.max_depth.max(depth);
+		self.sah_cost += TRAVERSAL_COST * bbox.area() / self.root_area;
+		self.memory += size;
+	}
+
+	pub fn add_leaf(&mut self, bbox : &AABB, depth : usize, primitives : usize, size : usize) {
+		self.leaf_count += 1;
+		self.primitive_count += primitives;
+		self.max_depth = self.max_depth.max(depth);
+		*self.leaf_sizes.entry(primitives).or_insert(0) += 1;
+		self.sah_cost += INTERSECTION_COST * primitives as f32 * bbox.area() / self.root_area;
+		self.memory += size;
+	}
+
+	pub fn print(&self, name : &str) {
+		println!("{} statistics:", name);
+		println!("    nodes: {}", self.node_count);
+		println!("    leaves: {}", self.leaf_count);
+		println!("    primitives: {}", self.primitive_count);
+		println!("    max depth: {}", self.max_depth);
+		println!("    SAH cost: {:.3}", self.sah_cost);
+		println!("    memory: {:.1} KiB", self.memory as f32 / 1024.0);
+		println!("    leaf sizes:");
+		for (size, count) in &self.leaf_sizes {
+			println!("        {:>3} primitives: {}", size, count);
+		}
+	}
+}
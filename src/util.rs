@@ -1,7 +1,7 @@
 
 extern crate nalgebra as alg;
 extern crate rand;
-use rand::prelude::*;
+use std::f32::consts::PI;
 
 pub fn multiply_colour(a : alg::Vector3<f32>, b : alg::Vector3<f32>) -> alg::Vector3<f32> {
 	alg::Vector3::new(a.x * b.x, a.y * b.y, a.z * b.z)
@@ -11,16 +11,56 @@ pub fn squared_length(vec : alg::Vector3<f32>) -> f32{
 	(vec.x * vec.x) + (vec.y * vec.y) + (vec.z * vec.z)
 }
 
-pub fn random_position_in_unit_sphere() -> alg::Vector3<f32> {
-	let mut pos : alg::Vector3<f32>;
+//Two vectors perpendicular to n and each other, so directions can be built around a normal
+pub fn orthonormal_basis(n : alg::Vector3<f32>) -> (alg::Vector3<f32>, alg::Vector3<f32>) {
+	let helper = if n.x.abs() > 0.9 { alg::Vector3::new(0.0, 1.0, 0.0) } else { alg::Vector3::new(1.0, 0.0, 0.0) };
+	let tangent = n.cross(&helper).normalize();
+	let bitangent = n.cross(&tangent);
+	(tangent, bitangent)
+}
 
-	loop {
-		let mut rng = rand::thread_rng();
-		pos = 2.0 as f32 * alg::Vector3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) - alg::Vector3::new(1.0, 1.0, 1.0);
-		if squared_length(pos) < 1.0 {
-			break
-		}
-	}
+//Turn a direction given relative to the normal (z up) in to world space
+pub fn to_world(local : alg::Vector3<f32>, n : alg::Vector3<f32>) -> alg::Vector3<f32> {
+	let (tangent, bitangent) = orthonormal_basis(n);
+	local.x * tangent + local.y * bitangent + local.z * n
+}
+
+//Directions around the z axis with a pdf of cos(theta) / PI
+pub fn cosine_sample_hemisphere(u : alg::Vector2<f32>) -> alg::Vector3<f32> {
+	let r = u.x.sqrt();
+	let phi = 2.0 * PI * u.y;
+	alg::Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u.x).max(0.0).sqrt())
+}
+
+//Directions within cos_theta_max of the z axis, each with a pdf of 1 / (2 * PI * (1 - cos_theta_max))
+pub fn uniform_sample_cone(u : alg::Vector2<f32>, cos_theta_max : f32) -> alg::Vector3<f32> {
+	let cos_theta = 1.0 - u.x * (1.0 - cos_theta_max);
+	let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+	let phi = 2.0 * PI * u.y;
+	alg::Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+//Directions spread evenly over the whole sphere, each with a pdf of 1 / (4 * PI)
+pub fn uniform_sample_sphere(u : alg::Vector2<f32>) -> alg::Vector3<f32> {
+	let z = 1.0 - 2.0 * u.x;
+	let r = (1.0 - z * z).max(0.0).sqrt();
+	let phi = 2.0 * PI * u.y;
+	alg::Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
 
-	pos
+//Points spread evenly over the unit disc in the xy plane, each with a pdf of 1 / PI
+pub fn uniform_sample_disc(u : alg::Vector2<f32>) -> alg::Vector3<f32> {
+	let r = u.x.sqrt();
+	let phi = 2.0 * PI * u.y;
+	alg::Vector3::new(r * phi.cos(), r * phi.sin(), 0.0)
+}
+
+//Weight for combining two sampling strategies, given how many samples and what pdf each one used
+pub fn power_heuristic(f_count : f32, f_pdf : f32, g_count : f32, g_pdf : f32) -> f32 {
+	let f = f_count * f_pdf;
+	let g = g_count * g_pdf;
+	if f == 0.0 && g == 0.0 {
+		return 0.0;
+	}
+	(f * f) / (f * f + g * g)
 }